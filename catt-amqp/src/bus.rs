@@ -0,0 +1,555 @@
+use amqp;
+use amqp::Basic;
+use amqp::Channel;
+use amqp::Session;
+use amqp::Table;
+use amqp::framing::MethodFrame;
+use amqp::protocol;
+
+use config::Config;
+
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::json;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::value::Value;
+use catt_core::util;
+use catt_core::util::CVar;
+
+use errors::*;
+
+const COMMAND_BINDING: &'static str = "*.command";
+
+// messages published at once before waiting for the broker's confirms
+const BATCH_SIZE: usize = 100;
+
+// persistent, so that messages in durable queues survive a broker restart
+const DELIVERY_MODE_PERSISTENT: u8 = 2;
+
+// names are escaped into a single routing key word, '.' separates words and
+// '*' and '#' are wildcards in bindings
+const RESERVED: &'static [char] = &['.', '*', '#'];
+
+fn routing_key(name: &str, sub_type: SubType) -> String {
+    let suffix = match sub_type {
+        SubType::Update => "state",
+        SubType::Command => "command",
+        SubType::Meta => "meta",
+        SubType::All => "*",
+    };
+    format!("{}.{}", util::escape_name(name, RESERVED), suffix)
+}
+
+// the item name of a <name>.command routing key
+fn command_name(routing_key: &str) -> Option<String> {
+    let mut words = routing_key.split('.');
+    match (words.next(), words.next(), words.next()) {
+        (Some(name), Some("command"), None) => util::unescape_name(name),
+        _ => None,
+    }
+}
+
+fn open(cfg: &Config) -> Result<(Session, Channel)> {
+    let mut session = Session::open_url(cfg.url())?;
+    let mut channel = session.open_channel(1)?;
+    channel.exchange_declare(cfg.exchange(),
+                             "topic",
+                             false,
+                             true,
+                             false,
+                             false,
+                             false,
+                             Table::new())?;
+    Ok((session, channel))
+}
+
+struct Publisher {
+    #[allow(dead_code)]
+    session: Session,
+    channel: Channel,
+    // the delivery tag the broker gives the next message it confirms
+    next_tag: u64,
+}
+
+impl Publisher {
+    // in confirm mode the broker acks every message once it has taken
+    // responsibility for it
+    fn connect(cfg: &Config) -> Result<Publisher> {
+        let (session, mut channel) = open(cfg)?;
+        channel.rpc::<_, protocol::confirm::SelectOk>(&protocol::confirm::Select { nowait: false },
+                                                      "confirm.select-ok")?;
+        Ok(Publisher {
+            session: session,
+            channel: channel,
+            next_tag: 1,
+        })
+    }
+
+    // publishes the whole batch before waiting for the confirms, so there is
+    // a single round trip per batch rather than per message
+    fn send(&mut self, exchange: &str, batch: &[Outgoing]) -> Result<()> {
+        let mut pending = BTreeMap::new();
+        for out in batch {
+            let properties = protocol::basic::BasicProperties {
+                content_type: Some(out.content_type.into()),
+                delivery_mode: Some(DELIVERY_MODE_PERSISTENT),
+                ..Default::default()
+            };
+
+            self.channel
+                .basic_publish(exchange,
+                               &out.routing_key,
+                               false,
+                               false,
+                               properties,
+                               out.payload.clone())?;
+            pending.insert(self.next_tag, &out.routing_key);
+            self.next_tag += 1;
+        }
+
+        while !pending.is_empty() {
+            let method = MethodFrame::decode(&self.channel.read()?)?;
+            let (tag, multiple, acked) = match method.method_name() {
+                "basic.ack" => {
+                    let ack: protocol::basic::Ack = protocol::Method::decode(method)?;
+                    (ack.delivery_tag, ack.multiple, true)
+                }
+                "basic.nack" => {
+                    let nack: protocol::basic::Nack = protocol::Method::decode(method)?;
+                    (nack.delivery_tag, nack.multiple, false)
+                }
+                other => {
+                    debug!("ignoring {} while waiting for confirms", other);
+                    continue;
+                }
+            };
+
+            let confirmed: Vec<u64> = if multiple {
+                pending.keys().cloned().take_while(|&t| t <= tag).collect()
+            } else {
+                vec![tag]
+            };
+            for t in confirmed {
+                if let Some(routing_key) = pending.remove(&t) {
+                    if !acked {
+                        return Err(ErrorKind::Nacked(routing_key.clone()).into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Outgoing {
+    routing_key: String,
+    content_type: &'static str,
+    payload: Vec<u8>,
+}
+
+// runs on its own thread so that connecting and waiting for confirms never
+// blocks the bridge. whatever is queued is published in batches of up to
+// BATCH_SIZE. a batch that isn't confirmed in full is sent again after
+// reconnecting until it goes through, so nothing is lost or reordered while
+// the broker is away, although messages may arrive twice. stops once the bus
+// is dropped.
+fn publish(cfg: Config, reconnect: Duration, messages: mpsc::Receiver<Outgoing>) {
+    let mut publisher: Option<Publisher> = None;
+    while let Ok(first) = messages.recv() {
+        let mut batch = vec![first];
+        while batch.len() < BATCH_SIZE {
+            match messages.try_recv() {
+                Ok(out) => batch.push(out),
+                Err(_) => break,
+            }
+        }
+
+        loop {
+            if publisher.is_none() {
+                match Publisher::connect(&cfg) {
+                    Ok(p) => publisher = Some(p),
+                    Err(e) => warn!("error connecting amqp publisher: {}", e),
+                }
+            }
+
+            let res = match publisher {
+                Some(ref mut p) => p.send(cfg.exchange(), &batch),
+                None => Err(ErrorKind::NotConnected.into()),
+            };
+
+            match res {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!("amqp publish of {} messages failed, retrying: {}", batch.len(), e);
+                    publisher = None;
+                    thread::sleep(reconnect);
+                }
+            }
+        }
+    }
+}
+
+// updates go to the exchange as <name>.state and <name>.meta. commands are
+// consumed from a durable queue bound to *.command and only acknowledged
+// once they have been handed to the bridge, so a crash in between leaves
+// them queued. consuming starts with the first command subscription so
+// that commands aren't turned away while the bridge is still starting.
+pub struct Amqp {
+    outgoing: mpsc::SyncSender<Outgoing>,
+    filter: Subscriptions,
+    subscribed: CVar,
+}
+
+impl Amqp {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let reconnect = Duration::from_secs(cfg.reconnect()? as u64);
+        let (tx, rx) = channel(handle)?;
+        let filter = Subscriptions::default();
+        let subscribed = CVar::new();
+
+        let consumer_cfg = cfg.clone();
+        let consumer_filter = filter.clone();
+        let consumer_subscribed = subscribed.clone();
+        thread::spawn(move || {
+            drop(consumer_subscribed.wait());
+            consume(consumer_cfg, reconnect, consumer_filter, tx)
+        });
+
+        let (outgoing, messages) = mpsc::sync_channel(cfg.queue_size());
+        let publisher_cfg = cfg.clone();
+        thread::spawn(move || publish(publisher_cfg, reconnect, messages));
+
+        let amqp = Amqp {
+            outgoing: outgoing,
+            filter: filter,
+            subscribed: subscribed,
+        };
+
+        Ok((amqp, rx))
+    }
+
+    fn send(&self,
+            name: &str,
+            sub_type: SubType,
+            content_type: &'static str,
+            payload: Vec<u8>)
+            -> Result<()> {
+        let out = Outgoing {
+            routing_key: routing_key(name, sub_type),
+            content_type: content_type,
+            payload: payload,
+        };
+
+        match self.outgoing.try_send(out) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(out)) => {
+                Err(ErrorKind::QueueFull(out.routing_key).into())
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn command_subscribed(&self, sub_type: SubType) {
+        if sub_type == SubType::Command || sub_type == SubType::All {
+            self.subscribed.notify_all();
+        }
+    }
+}
+
+struct Commands {
+    filter: Subscriptions,
+    tx: Sender<Message>,
+}
+
+impl amqp::Consumer for Commands {
+    fn handle_delivery(&mut self,
+                       channel: &mut Channel,
+                       deliver: protocol::basic::Deliver,
+                       _: protocol::basic::BasicProperties,
+                       body: Vec<u8>) {
+        // commands that can't be handled are rejected rather than
+        // acknowledged, so that a dead letter exchange can pick them up
+        let tag = deliver.delivery_tag;
+        let name = match command_name(&deliver.routing_key) {
+            Some(n) => n,
+            None => {
+                warn!("unexpected routing key {}, rejecting", deliver.routing_key);
+                let _ = channel.basic_reject(tag, false);
+                return;
+            }
+        };
+
+        if !self.filter.accepts(&name, SubType::Command) {
+            debug!("no subscription for {}, rejecting", name);
+            let _ = channel.basic_reject(tag, false);
+            return;
+        }
+
+        let res = match self.tx.send(Message::Command(name, Value::from_raw(&body))) {
+            Ok(_) => channel.basic_ack(tag, false),
+            Err(e) => {
+                // leave it for the next consumer
+                warn!("channel send error: {}", e);
+                channel.basic_reject(tag, true)
+            }
+        };
+
+        if let Err(e) = res {
+            warn!("error acknowledging command: {:?}", e);
+        }
+    }
+}
+
+// runs on its own thread, reconnecting whenever the consumer connection breaks
+fn consume(cfg: Config, reconnect: Duration, filter: Subscriptions, tx: Sender<Message>) {
+    loop {
+        let res = open(&cfg).and_then(|(session, mut channel)| {
+            channel.queue_declare(cfg.queue(), false, true, false, false, false, Table::new())?;
+            channel.queue_bind(cfg.queue(),
+                               cfg.exchange(),
+                               COMMAND_BINDING,
+                               false,
+                               Table::new())?;
+
+            let commands = Commands {
+                filter: filter.clone(),
+                tx: tx.clone(),
+            };
+            channel.basic_consume(commands,
+                                  cfg.queue(),
+                                  "",
+                                  false,
+                                  false,
+                                  false,
+                                  false,
+                                  Table::new())?;
+            info!("consuming commands from {}", cfg.queue());
+
+            channel.start_consuming();
+            drop(session);
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            warn!("amqp consumer error, reconnecting: {}", e);
+        }
+        thread::sleep(reconnect);
+    }
+}
+
+impl Bus for Amqp {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Amqp::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        match message {
+            Message::Update(name, value, _) => {
+                self.send(&name, SubType::Update, "text/plain", value.as_string()?.into_bytes())
+            }
+            Message::Command(name, value) => {
+                self.send(&name, SubType::Command, "text/plain", value.as_string()?.into_bytes())
+            }
+            Message::Meta(name, meta) => {
+                self.send(&name,
+                          SubType::Meta,
+                          "application/json",
+                          json::encode(&meta)?.into_bytes())
+            }
+        }
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        self.command_subscribed(sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        self.command_subscribed(sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Amqp;
+    use super::command_name;
+    use super::open;
+    use super::routing_key;
+
+    use config::Config;
+
+    use amqp::Basic;
+    use amqp::Table;
+    use amqp::protocol;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::value::Value;
+
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    // the integration tests run against a broker on localhost, e.g.
+    //   rabbitmq-server & cargo test -- --ignored
+    // each uses an exchange and queue of its own
+    fn test_config(name: &str) -> Config {
+        let mut cfg = Config::default();
+        cfg.exchange = Some(format!("catt.test.{}", name));
+        cfg.queue = Some(format!("catt.test.{}.commands", name));
+        cfg.reconnect = Some(1);
+        cfg
+    }
+
+    #[test]
+    fn routing_keys() {
+        assert_eq!(routing_key("kitchen.lamp", SubType::Command), "kitchen%2Elamp.command");
+        assert_eq!(routing_key("a*#", SubType::Update), "a%2A%23.state");
+        assert_eq!(command_name(&routing_key("kitchen.lamp", SubType::Command)),
+                   Some("kitchen.lamp".to_string()));
+    }
+
+    #[test]
+    fn invalid_command_keys() {
+        assert_eq!(command_name("lamp.state"), None);
+        assert_eq!(command_name("kitchen.lamp.command"), None);
+        assert_eq!(command_name("lamp%zz.command"), None);
+        assert_eq!(command_name("command"), None);
+    }
+
+    #[test]
+    fn zero_reconnect_is_rejected() {
+        let mut cfg = Config::default();
+        assert_eq!(cfg.reconnect().unwrap(), 3);
+        cfg.reconnect = Some(0);
+        assert!(cfg.reconnect().is_err());
+        let core = Core::new().unwrap();
+        assert!(Amqp::with_config(&core.handle(), &cfg).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn publish_is_confirmed_and_routed() {
+        let cfg = test_config("publish");
+        // an exclusive queue of the test's own, bound before anything is
+        // published
+        let (_session, mut channel) = open(&cfg).unwrap();
+        let queue = "catt.test.publish.states";
+        channel.queue_declare(queue, false, false, true, false, false, Table::new()).unwrap();
+        channel.queue_bind(queue, cfg.exchange(), "*.state", false, Table::new()).unwrap();
+
+        let core = Core::new().unwrap();
+        let (amqp, _rx) = Amqp::with_config(&core.handle(), &cfg).unwrap();
+        for i in 0..250 {
+            let name = format!("lamp.{}", i);
+            amqp.publish(Message::Update(name, Value::Number(i as f64), SystemTime::now()))
+                .unwrap();
+        }
+
+        // everything arrives, in order
+        let mut received = vec![];
+        for _ in 0..50 {
+            for get in channel.basic_get(queue, true) {
+                received.push((get.reply.routing_key.clone(), get.body.clone()));
+            }
+            if received.len() >= 250 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(received.len(), 250);
+        for (i, &(ref key, ref body)) in received.iter().enumerate() {
+            assert_eq!(*key, format!("lamp%2E{}.state", i));
+            assert_eq!(*body, format!("{}", i).into_bytes());
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn command_round_trip() {
+        let cfg = test_config("command");
+        let mut core = Core::new().unwrap();
+        let (amqp, rx) = Amqp::with_config(&core.handle(), &cfg).unwrap();
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        amqp.subscribe("lamp", SubType::Command).unwrap();
+        // give the consumer time to declare and bind its queue
+        thread::sleep(Duration::from_secs(1));
+
+        let (_session, mut channel) = open(&cfg).unwrap();
+        let properties = protocol::basic::BasicProperties::default();
+        for &(key, body) in &[("other.command", "1"), ("lamp.command", "ON")] {
+            channel.basic_publish(cfg.exchange(),
+                                  key,
+                                  false,
+                                  false,
+                                  properties.clone(),
+                                  body.as_bytes().to_vec())
+                .unwrap();
+        }
+
+        // the command for an item that isn't subscribed is rejected
+        let mut received = None;
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                received = Some(m);
+                break;
+            }
+        }
+        match received {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "lamp");
+                assert_eq!(value, Value::Bool(true));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+    }
+}
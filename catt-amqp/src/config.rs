@@ -0,0 +1,46 @@
+use errors::*;
+
+pub const AMQP_URL_DEFAULT: &'static str = "amqp://127.0.0.1//";
+pub const AMQP_EXCHANGE_DEFAULT: &'static str = "catt";
+pub const AMQP_QUEUE_DEFAULT: &'static str = "catt.commands";
+pub const AMQP_RECONNECT_DEFAULT: u16 = 3;
+pub const AMQP_QUEUE_SIZE_DEFAULT: usize = 1000;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub url: Option<String>,
+    pub exchange: Option<String>,
+    pub queue: Option<String>,
+    pub reconnect: Option<u16>,
+    pub queue_size: Option<usize>,
+}
+
+impl Config {
+    pub fn url(&self) -> &str {
+        self.url.as_ref().map(|u| u.as_str()).unwrap_or(AMQP_URL_DEFAULT)
+    }
+
+    // durable topic exchange that updates, meta and commands are published to
+    pub fn exchange(&self) -> &str {
+        self.exchange.as_ref().map(|e| e.as_str()).unwrap_or(AMQP_EXCHANGE_DEFAULT)
+    }
+
+    // durable queue bound to *.command that the bridge consumes commands from
+    pub fn queue(&self) -> &str {
+        self.queue.as_ref().map(|q| q.as_str()).unwrap_or(AMQP_QUEUE_DEFAULT)
+    }
+
+    // seconds to wait before re-opening a broken connection
+    pub fn reconnect(&self) -> Result<u16> {
+        match self.reconnect.unwrap_or(AMQP_RECONNECT_DEFAULT) {
+            0 => Err(ErrorKind::InvalidConfig("reconnect must be at least 1 second".into()).into()),
+            r => Ok(r),
+        }
+    }
+
+    // messages waiting to be published while the broker can't be reached.
+    // publishing fails once it is full.
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(AMQP_QUEUE_SIZE_DEFAULT)
+    }
+}
@@ -0,0 +1,42 @@
+use amqp;
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+    }
+
+    errors {
+        Amqp(e: amqp::AMQPError) {
+            description("amqp error")
+            display("amqp error: {:?}", e)
+        }
+        NotConnected {
+            description("not connected to the amqp broker")
+            display("not connected to the amqp broker")
+        }
+        QueueFull(routing_key: String) {
+            description("publish queue full")
+            display("publish queue full, dropping message for {}", routing_key)
+        }
+        Nacked(routing_key: String) {
+            description("message rejected by the broker")
+            display("message for {} rejected by the broker", routing_key)
+        }
+        InvalidConfig(reason: String) {
+            description("invalid amqp configuration")
+            display("invalid amqp configuration: {}", reason)
+        }
+    }
+}
+
+impl From<amqp::AMQPError> for Error {
+    fn from(other: amqp::AMQPError) -> Self {
+        ErrorKind::Amqp(other).into()
+    }
+}
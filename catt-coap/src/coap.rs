@@ -0,0 +1,605 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::value::Value;
+use catt_core::util::always_lock;
+
+use config::Config;
+use message;
+use message::Packet;
+use message::Type;
+
+use errors::*;
+
+// largest datagram we expect, see RFC 7252 section 4.6
+const MAX_MESSAGE_SIZE: usize = 1152;
+
+// observe sequence numbers are 24 bits
+const OBSERVE_MASK: u32 = 0xffffff;
+
+// transmission parameters, RFC 7252 section 4.8
+const ACK_TIMEOUT_SECS: u64 = 2;
+const MAX_RETRANSMIT: u32 = 4;
+const EXCHANGE_LIFETIME_SECS: u64 = 247;
+
+// responses kept for answering retransmitted requests
+const MAX_EXCHANGES: usize = 1024;
+
+// notifications go out confirmable at least this often so that observers
+// that have gone away are noticed, RFC 7641 section 4.5
+const CONFIRM_INTERVAL_SECS: u64 = 60;
+
+// how often the server looks for notifications to retransmit
+const MAINTENANCE_INTERVAL_SECS: u64 = 1;
+
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(ACK_TIMEOUT_SECS << attempts)
+}
+
+// a confirmable notification waiting for its acknowledgement
+#[derive(Clone)]
+struct Unacked {
+    message_id: u16,
+    bytes: Vec<u8>,
+    sent: Instant,
+    attempts: u32,
+}
+
+#[derive(Clone)]
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    last_confirmed: Instant,
+    unacked: Option<Unacked>,
+}
+
+impl Observer {
+    fn new(addr: SocketAddr, token: Vec<u8>) -> Observer {
+        Observer {
+            addr: addr,
+            token: token,
+            last_confirmed: Instant::now(),
+            unacked: None,
+        }
+    }
+
+    // gave up on after the last retransmission went unanswered
+    fn is_gone(&self) -> bool {
+        match self.unacked {
+            Some(ref u) => u.attempts >= MAX_RETRANSMIT && u.sent.elapsed() >= backoff(u.attempts),
+            None => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Resources {
+    // latest state of every item published to the bus
+    items: BTreeMap<String, Option<Value>>,
+    observers: BTreeMap<String, Vec<Observer>>,
+    message_id: u16,
+    observe_seq: u32,
+}
+
+impl Resources {
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+
+    fn next_observe_seq(&mut self) -> u32 {
+        self.observe_seq = (self.observe_seq + 1) & OBSERVE_MASK;
+        self.observe_seq
+    }
+
+    // returns false if the resource already has max other observers
+    fn observe(&mut self, name: &str, observer: Observer, max: usize) -> bool {
+        let observers = self.observers.entry(name.into()).or_insert_with(Vec::new);
+        observers.retain(|o| o.addr != observer.addr || o.token != observer.token);
+        if observers.len() >= max {
+            return false;
+        }
+        observers.push(observer);
+        true
+    }
+
+    fn acknowledge(&mut self, addr: SocketAddr, message_id: u16) {
+        for observers in self.observers.values_mut() {
+            for o in observers.iter_mut() {
+                if o.addr == addr && o.unacked.as_ref().map(|u| u.message_id) == Some(message_id) {
+                    o.unacked = None;
+                    o.last_confirmed = Instant::now();
+                }
+            }
+        }
+    }
+
+    // returns the confirmable notifications that are due to be resent and
+    // drops the observers that never acknowledged theirs
+    fn retransmit(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut resend = vec![];
+        for (name, observers) in self.observers.iter_mut() {
+            for o in observers.iter_mut() {
+                if let Some(ref mut u) = o.unacked {
+                    if u.attempts < MAX_RETRANSMIT && u.sent.elapsed() >= backoff(u.attempts) {
+                        u.attempts += 1;
+                        u.sent = Instant::now();
+                        resend.push((o.addr, u.bytes.clone()));
+                    }
+                }
+            }
+
+            let before = observers.len();
+            observers.retain(|o| !o.is_gone());
+            if observers.len() < before {
+                debug!("dropped {} unresponsive observers of {}",
+                       before - observers.len(),
+                       name);
+            }
+        }
+        resend
+    }
+
+    fn forget(&mut self, addr: SocketAddr, token: Option<&[u8]>) {
+        for observers in self.observers.values_mut() {
+            observers.retain(|o| o.addr != addr || token.map(|t| o.token != t).unwrap_or(false));
+        }
+    }
+}
+
+// responses to recent confirmable requests, so that a retransmitted request
+// gets the same response again instead of being handled twice
+#[derive(Default)]
+struct Exchanges {
+    responses: HashMap<(SocketAddr, u16), (Instant, Vec<u8>)>,
+}
+
+impl Exchanges {
+    fn get(&self, addr: SocketAddr, message_id: u16) -> Option<&[u8]> {
+        let lifetime = Duration::from_secs(EXCHANGE_LIFETIME_SECS);
+        match self.responses.get(&(addr, message_id)) {
+            Some(&(ref t, ref r)) if t.elapsed() < lifetime => Some(r.as_slice()),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, addr: SocketAddr, message_id: u16, response: Vec<u8>) {
+        if self.responses.len() >= MAX_EXCHANGES {
+            self.expire();
+        }
+        if self.responses.len() >= MAX_EXCHANGES {
+            debug!("too many recent exchanges, not keeping the response to {}", addr);
+            return;
+        }
+        self.responses.insert((addr, message_id), (Instant::now(), response));
+    }
+
+    fn expire(&mut self) {
+        let lifetime = Duration::from_secs(EXCHANGE_LIFETIME_SECS);
+        let expired = self.responses
+            .iter()
+            .filter(|&(_, &(ref t, _))| t.elapsed() >= lifetime)
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        for k in expired {
+            self.responses.remove(&k);
+        }
+    }
+}
+
+// exposes every item as /items/<name>. GET returns the state (with Observe
+// support), PUT and POST send the payload as a command. notifications are
+// mostly sent non-confirmable, with a confirmable one every so often that
+// drops the observer if it is never acknowledged. clients drop an
+// observation by answering a notification with a reset or by a GET with
+// Observe set to 1.
+pub struct Coap {
+    socket: UdpSocket,
+    resources: Arc<Mutex<Resources>>,
+    filter: Subscriptions,
+}
+
+impl Coap {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let socket = UdpSocket::bind(cfg.listen())?;
+        let resources = Arc::new(Mutex::new(Resources::default()));
+        let filter = Subscriptions::default();
+        info!("coap server listening on {}", cfg.listen());
+
+        let server = Server {
+            socket: socket.try_clone()?,
+            resources: resources.clone(),
+            filter: filter.clone(),
+            tx: tx,
+            max_observers: cfg.max_observers(),
+        };
+        thread::spawn(move || server.run());
+
+        let coap = Coap {
+            socket: socket,
+            resources: resources,
+            filter: filter,
+        };
+
+        Ok((coap, rx))
+    }
+
+    fn notify(&self, name: &str, value: &Value) -> Result<()> {
+        let payload = value.as_string()?.into_bytes();
+        let mut resources = always_lock(self.resources.lock());
+        let mut observers = match resources.observers.remove(name) {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+
+        let seq = resources.next_observe_seq();
+        for observer in observers.iter_mut() {
+            let confirm = observer.unacked.is_none() &&
+                          observer.last_confirmed.elapsed() >=
+                          Duration::from_secs(CONFIRM_INTERVAL_SECS);
+            let message_type = if confirm {
+                Type::Confirmable
+            } else {
+                Type::NonConfirmable
+            };
+
+            let mut packet = Packet::new(message_type,
+                                         message::CONTENT,
+                                         resources.next_message_id(),
+                                         observer.token.clone());
+            packet.add_option(message::OBSERVE, message::encode_uint(seq));
+            packet.add_option(message::CONTENT_FORMAT,
+                              message::encode_uint(message::TEXT_PLAIN));
+            packet.payload = payload.clone();
+
+            let bytes = packet.to_bytes();
+            if confirm {
+                observer.unacked = Some(Unacked {
+                    message_id: packet.message_id,
+                    bytes: bytes.clone(),
+                    sent: Instant::now(),
+                    attempts: 0,
+                });
+            }
+            if let Err(e) = self.socket.send_to(&bytes, observer.addr) {
+                warn!("error notifying {}: {}", observer.addr, e);
+            }
+        }
+
+        resources.observers.insert(name.into(), observers);
+        Ok(())
+    }
+}
+
+struct Server {
+    socket: UdpSocket,
+    resources: Arc<Mutex<Resources>>,
+    filter: Subscriptions,
+    tx: Sender<Message>,
+    max_observers: usize,
+}
+
+impl Server {
+    fn run(self) {
+        let interval = Duration::from_secs(MAINTENANCE_INTERVAL_SECS);
+        if let Err(e) = self.socket.set_read_timeout(Some(interval)) {
+            warn!("error setting coap socket timeout: {}", e);
+        }
+
+        let mut exchanges = Exchanges::default();
+        let mut last_maintenance = Instant::now();
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        loop {
+            if last_maintenance.elapsed() >= interval {
+                self.maintain(&mut exchanges);
+                last_maintenance = Instant::now();
+            }
+
+            let (len, addr) = match self.socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                              e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    warn!("coap receive error: {}", e);
+                    continue;
+                }
+            };
+
+            let request = match Packet::from_bytes(&buf[..len]) {
+                Ok(p) => p,
+                Err(e) => {
+                    debug!("ignoring message from {}: {}", addr, e);
+                    continue;
+                }
+            };
+
+            let confirmable = request.message_type == Type::Confirmable;
+            let response = match request.message_type {
+                Type::Reset => {
+                    always_lock(self.resources.lock()).forget(addr, None);
+                    continue;
+                }
+                Type::Acknowledgement => {
+                    always_lock(self.resources.lock()).acknowledge(addr, request.message_id);
+                    continue;
+                }
+                _ if confirmable && exchanges.get(addr, request.message_id).is_some() => {
+                    debug!("duplicate request {} from {}", request.message_id, addr);
+                    exchanges.get(addr, request.message_id).unwrap_or(&[]).to_vec()
+                }
+                _ => self.handle(addr, &request).to_bytes(),
+            };
+
+            if confirmable {
+                exchanges.insert(addr, request.message_id, response.clone());
+            }
+            if let Err(e) = self.socket.send_to(&response, addr) {
+                warn!("error responding to {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn maintain(&self, exchanges: &mut Exchanges) {
+        exchanges.expire();
+
+        let resend = always_lock(self.resources.lock()).retransmit();
+        for (addr, bytes) in resend {
+            if let Err(e) = self.socket.send_to(&bytes, addr) {
+                warn!("error notifying {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn handle(&self, addr: SocketAddr, request: &Packet) -> Packet {
+        let mut response = {
+            let mut resources = always_lock(self.resources.lock());
+            match request.message_type {
+                // piggybacked response
+                Type::Confirmable => {
+                    Packet::new(Type::Acknowledgement,
+                                message::CONTENT,
+                                request.message_id,
+                                request.token.clone())
+                }
+                _ => {
+                    Packet::new(Type::NonConfirmable,
+                                message::CONTENT,
+                                resources.next_message_id(),
+                                request.token.clone())
+                }
+            }
+        };
+
+        // a confirmable empty message is a ping
+        if request.code == message::EMPTY {
+            response.message_type = Type::Reset;
+            response.code = message::EMPTY;
+            response.token = vec![];
+            return response;
+        }
+
+        let path = request.uri_path();
+        if path.len() == 2 && path[0] == ".well-known" && path[1] == "core" {
+            return self.link_format(response);
+        }
+        if path.len() < 2 || path[0] != "items" {
+            response.code = message::NOT_FOUND;
+            return response;
+        }
+
+        let name = path[1..].join("/");
+        let code = match request.code {
+            message::GET => self.get(&name, addr, request, &mut response),
+            message::PUT | message::POST => self.command(name, request),
+            _ => message::METHOD_NOT_ALLOWED,
+        };
+        response.code = code;
+        response
+    }
+
+    fn get(&self, name: &str, addr: SocketAddr, request: &Packet, response: &mut Packet) -> u8 {
+        let mut resources = always_lock(self.resources.lock());
+        let payload = match resources.items.get(name) {
+            Some(&Some(ref v)) => v.as_string().unwrap_or(String::new()),
+            Some(&None) => String::new(),
+            None => return message::NOT_FOUND,
+        };
+
+        match request.uint_option(message::OBSERVE) {
+            Some(0) => {
+                let observer = Observer::new(addr, request.token.clone());
+                if resources.observe(name, observer, self.max_observers) {
+                    debug!("{} observing {}", addr, name);
+                    let seq = resources.observe_seq;
+                    response.add_option(message::OBSERVE, message::encode_uint(seq));
+                } else {
+                    // answered without the option, the client knows it isn't observing
+                    debug!("{} observers of {} already, not adding {}",
+                           self.max_observers,
+                           name,
+                           addr);
+                }
+            }
+            Some(1) => resources.forget(addr, Some(&request.token)),
+            _ => {}
+        }
+
+        response.add_option(message::CONTENT_FORMAT,
+                            message::encode_uint(message::TEXT_PLAIN));
+        response.payload = payload.into_bytes();
+        message::CONTENT
+    }
+
+    // the payload is the new value, the same as a plain mqtt payload
+    fn command(&self, name: String, request: &Packet) -> u8 {
+        if !always_lock(self.resources.lock()).items.contains_key(&name) ||
+           !self.filter.accepts(&name, SubType::Command) {
+            return message::NOT_FOUND;
+        }
+
+        match self.tx.send(Message::Command(name, Value::from_raw(&request.payload))) {
+            Ok(_) => message::CHANGED,
+            Err(e) => {
+                warn!("channel send error: {}", e);
+                message::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+
+    // resource discovery, RFC 6690
+    fn link_format(&self, mut response: Packet) -> Packet {
+        let links = always_lock(self.resources.lock())
+            .items
+            .keys()
+            .map(|name| format!("</items/{}>;obs", name))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        response.add_option(message::CONTENT_FORMAT,
+                            message::encode_uint(message::LINK_FORMAT));
+        response.payload = links.into_bytes();
+        response
+    }
+}
+
+impl Bus for Coap {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Coap::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        match message {
+            Message::Update(name, value, _) => {
+                always_lock(self.resources.lock()).items.insert(name.clone(), Some(value.clone()));
+                self.notify(&name, &value)
+            }
+            Message::Meta(name, _) => {
+                always_lock(self.resources.lock()).items.entry(name).or_insert(None);
+                Ok(())
+            }
+            // commands only ever come in as requests
+            Message::Command(name, _) => {
+                debug!("ignoring command for {}", name);
+                Ok(())
+            }
+        }
+    }
+
+    // observers of the name are notified but it doesn't become a resource
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        match message {
+            Message::Update(name, value, _) => self.notify(&name, &value),
+            message => self.publish(message),
+        }
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        let mut resources = always_lock(self.resources.lock());
+        resources.items.remove(item_name);
+        resources.observers.remove(item_name);
+        Ok(())
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Exchanges;
+    use super::Observer;
+    use super::Resources;
+    use super::Unacked;
+
+    use std::net::SocketAddr;
+    use std::time::Instant;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn observer_limit() {
+        let mut resources = Resources::default();
+        assert!(resources.observe("a", Observer::new(addr(1), vec![1]), 2));
+        assert!(resources.observe("a", Observer::new(addr(2), vec![1]), 2));
+        assert!(!resources.observe("a", Observer::new(addr(3), vec![1]), 2));
+
+        // re-registering doesn't count against the limit
+        assert!(resources.observe("a", Observer::new(addr(2), vec![1]), 2));
+        assert!(resources.observe("b", Observer::new(addr(3), vec![1]), 2));
+    }
+
+    #[test]
+    fn acknowledged_notification() {
+        let mut resources = Resources::default();
+        let mut observer = Observer::new(addr(1), vec![1]);
+        observer.unacked = Some(Unacked {
+            message_id: 7,
+            bytes: vec![],
+            sent: Instant::now(),
+            attempts: 0,
+        });
+        resources.observe("a", observer, 8);
+
+        resources.acknowledge(addr(1), 6);
+        assert!(resources.observers["a"][0].unacked.is_some());
+        resources.acknowledge(addr(1), 7);
+        assert!(resources.observers["a"][0].unacked.is_none());
+    }
+
+    #[test]
+    fn duplicate_requests() {
+        let mut exchanges = Exchanges::default();
+        exchanges.insert(addr(1), 1, vec![1, 2]);
+        assert_eq!(exchanges.get(addr(1), 1), Some(&[1u8, 2][..]));
+        assert_eq!(exchanges.get(addr(1), 2), None);
+        assert_eq!(exchanges.get(addr(2), 1), None);
+    }
+}
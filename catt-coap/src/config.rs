@@ -0,0 +1,19 @@
+pub const COAP_LISTEN_DEFAULT: &'static str = "0.0.0.0:5683";
+pub const COAP_MAX_OBSERVERS_DEFAULT: usize = 32;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub listen: Option<String>,
+    pub max_observers: Option<usize>,
+}
+
+impl Config {
+    pub fn listen(&self) -> &str {
+        self.listen.as_ref().map(|l| l.as_str()).unwrap_or(COAP_LISTEN_DEFAULT)
+    }
+
+    // per item, further observe requests are answered without observing
+    pub fn max_observers(&self) -> usize {
+        self.max_observers.unwrap_or(COAP_MAX_OBSERVERS_DEFAULT)
+    }
+}
@@ -0,0 +1,18 @@
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+    }
+
+    errors {
+        InvalidMessage(reason: String) {
+            description("invalid coap message")
+            display("invalid coap message: {}", reason)
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// the parts of the RFC 7252 message format the bus needs
+
+use errors::*;
+
+pub const VERSION: u8 = 1;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Type {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl Type {
+    fn from_bits(bits: u8) -> Type {
+        match bits & 0x03 {
+            0 => Type::Confirmable,
+            1 => Type::NonConfirmable,
+            2 => Type::Acknowledgement,
+            _ => Type::Reset,
+        }
+    }
+
+    fn bits(&self) -> u8 {
+        match self {
+            &Type::Confirmable => 0,
+            &Type::NonConfirmable => 1,
+            &Type::Acknowledgement => 2,
+            &Type::Reset => 3,
+        }
+    }
+}
+
+// codes are class.detail packed as (class << 5) | detail
+pub const EMPTY: u8 = 0x00;
+pub const GET: u8 = 0x01;
+pub const POST: u8 = 0x02;
+pub const PUT: u8 = 0x03;
+
+pub const CHANGED: u8 = 0x44;
+pub const CONTENT: u8 = 0x45;
+pub const BAD_REQUEST: u8 = 0x80;
+pub const NOT_FOUND: u8 = 0x84;
+pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+pub const OBSERVE: u16 = 6;
+pub const URI_PATH: u16 = 11;
+pub const CONTENT_FORMAT: u16 = 12;
+
+pub const TEXT_PLAIN: u32 = 0;
+pub const LINK_FORMAT: u32 = 40;
+
+#[derive(Debug,Clone)]
+pub struct Packet {
+    pub message_type: Type,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    // (number, value), kept sorted by number
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(message_type: Type, code: u8, message_id: u16, token: Vec<u8>) -> Packet {
+        Packet {
+            message_type: message_type,
+            code: code,
+            message_id: message_id,
+            token: token,
+            options: vec![],
+            payload: vec![],
+        }
+    }
+
+    pub fn add_option(&mut self, number: u16, value: Vec<u8>) {
+        let pos = self.options.iter().position(|&(n, _)| n > number).unwrap_or(self.options.len());
+        self.options.insert(pos, (number, value));
+    }
+
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options.iter().find(|&&(n, _)| n == number).map(|&(_, ref v)| v.as_slice())
+    }
+
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).map(decode_uint)
+    }
+
+    pub fn uri_path(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|&&(n, _)| n == URI_PATH)
+            .map(|&(_, ref v)| String::from_utf8_lossy(v).into_owned())
+            .collect()
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 4 {
+            return Err(invalid("message too short"));
+        }
+        if buf[0] >> 6 != VERSION {
+            return Err(invalid("unsupported version"));
+        }
+
+        let token_len = (buf[0] & 0x0f) as usize;
+        if token_len > 8 || buf.len() < 4 + token_len {
+            return Err(invalid("invalid token length"));
+        }
+
+        let mut packet = Packet::new(Type::from_bits(buf[0] >> 4),
+                                     buf[1],
+                                     ((buf[2] as u16) << 8) | buf[3] as u16,
+                                     buf[4..4 + token_len].to_vec());
+
+        let mut pos = 4 + token_len;
+        let mut number = 0u16;
+        while pos < buf.len() {
+            if buf[pos] == 0xff {
+                if pos + 1 == buf.len() {
+                    return Err(invalid("payload marker without payload"));
+                }
+                packet.payload = buf[pos + 1..].to_vec();
+                break;
+            }
+
+            let delta_bits = (buf[pos] >> 4) as u16;
+            let len_bits = (buf[pos] & 0x0f) as u16;
+            pos += 1;
+
+            let delta = read_extended(buf, &mut pos, delta_bits)?;
+            let len = read_extended(buf, &mut pos, len_bits)? as usize;
+            if pos + len > buf.len() {
+                return Err(invalid("option overruns message"));
+            }
+
+            number = number.checked_add(delta).ok_or_else(|| invalid("option number overflow"))?;
+            packet.options.push((number, buf[pos..pos + len].to_vec()));
+            pos += len;
+        }
+
+        Ok(packet)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![(VERSION << 6) | (self.message_type.bits() << 4) |
+                           self.token.len() as u8,
+                           self.code,
+                           (self.message_id >> 8) as u8,
+                           self.message_id as u8];
+        buf.extend(&self.token);
+
+        let mut last = 0u16;
+        for &(number, ref value) in &self.options {
+            let (delta_bits, delta_ext) = extended(number - last);
+            let (len_bits, len_ext) = extended(value.len() as u16);
+            buf.push((delta_bits << 4) | len_bits);
+            buf.extend(delta_ext);
+            buf.extend(len_ext);
+            buf.extend(value);
+            last = number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(0xff);
+            buf.extend(&self.payload);
+        }
+        buf
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    ErrorKind::InvalidMessage(reason.into()).into()
+}
+
+// option deltas and lengths of 13 and up spill into one or two extra bytes
+fn read_extended(buf: &[u8], pos: &mut usize, bits: u16) -> Result<u16> {
+    match bits {
+        13 => {
+            if *pos >= buf.len() {
+                return Err(invalid("truncated option"));
+            }
+            *pos += 1;
+            Ok(buf[*pos - 1] as u16 + 13)
+        }
+        14 => {
+            if *pos + 1 >= buf.len() {
+                return Err(invalid("truncated option"));
+            }
+            *pos += 2;
+            let n = ((buf[*pos - 2] as u16) << 8) | buf[*pos - 1] as u16;
+            n.checked_add(269).ok_or_else(|| invalid("option number overflow"))
+        }
+        15 => Err(invalid("reserved option nibble")),
+        n => Ok(n),
+    }
+}
+
+fn extended(n: u16) -> (u8, Vec<u8>) {
+    if n < 13 {
+        (n as u8, vec![])
+    } else if n < 269 {
+        (13, vec![(n - 13) as u8])
+    } else {
+        let n = n - 269;
+        (14, vec![(n >> 8) as u8, n as u8])
+    }
+}
+
+pub fn encode_uint(n: u32) -> Vec<u8> {
+    let bytes = vec![(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8];
+    bytes.into_iter().skip_while(|b| *b == 0).collect()
+}
+
+pub fn decode_uint(buf: &[u8]) -> u32 {
+    buf.iter().fold(0, |n, b| (n << 8) | *b as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Packet;
+    use super::Type;
+    use super::CONTENT;
+    use super::CONTENT_FORMAT;
+    use super::GET;
+    use super::OBSERVE;
+    use super::URI_PATH;
+    use super::decode_uint;
+    use super::encode_uint;
+
+    #[test]
+    fn round_trip() {
+        let mut packet = Packet::new(Type::Confirmable, GET, 0x1234, vec![1, 2, 3]);
+        packet.add_option(URI_PATH, b"items".to_vec());
+        packet.add_option(URI_PATH, b"light".to_vec());
+        packet.add_option(OBSERVE, encode_uint(0));
+        packet.payload = b"ON".to_vec();
+
+        let parsed = Packet::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(parsed.message_type, Type::Confirmable);
+        assert_eq!(parsed.code, GET);
+        assert_eq!(parsed.message_id, 0x1234);
+        assert_eq!(parsed.token, vec![1, 2, 3]);
+        assert_eq!(parsed.uri_path(), vec!["items".to_string(), "light".to_string()]);
+        assert_eq!(parsed.uint_option(OBSERVE), Some(0));
+        assert_eq!(parsed.payload, b"ON".to_vec());
+    }
+
+    #[test]
+    fn extended_options() {
+        let long = vec![b'x'; 300];
+        let mut packet = Packet::new(Type::NonConfirmable, CONTENT, 1, vec![]);
+        packet.add_option(CONTENT_FORMAT, encode_uint(40));
+        packet.add_option(300, vec![b'y'; 20]);
+        packet.add_option(2000, long.clone());
+
+        let parsed = Packet::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(parsed.uint_option(CONTENT_FORMAT), Some(40));
+        assert_eq!(parsed.option(300), Some(&[b'y'; 20][..]));
+        assert_eq!(parsed.option(2000), Some(long.as_slice()));
+    }
+
+    #[test]
+    fn uints() {
+        assert!(encode_uint(0).is_empty());
+        assert_eq!(encode_uint(0x0102), vec![1, 2]);
+        assert_eq!(decode_uint(&encode_uint(0xffffff)), 0xffffff);
+    }
+
+    #[test]
+    fn malformed() {
+        // too short, wrong version, token longer than the message
+        assert!(Packet::from_bytes(&[0x40, 0x01, 0x00]).is_err());
+        assert!(Packet::from_bytes(&[0x80, 0x01, 0x00, 0x01]).is_err());
+        assert!(Packet::from_bytes(&[0x48, 0x01, 0x00, 0x01, 0x01]).is_err());
+        // payload marker with nothing after it
+        assert!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xff]).is_err());
+        // option value past the end, truncated extended delta, reserved nibble
+        assert!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xb5, b'a']).is_err());
+        assert!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xe0, 0x01]).is_err());
+        assert!(Packet::from_bytes(&[0x40, 0x01, 0x00, 0x01, 0xf0]).is_err());
+        // option numbers past 65535
+        let overflow = [0x40, 0x01, 0x00, 0x01, 0xe0, 0xff, 0x00, 0xe0, 0xff, 0x00];
+        assert!(Packet::from_bytes(&overflow).is_err());
+    }
+}
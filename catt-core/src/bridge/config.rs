@@ -26,6 +26,8 @@ error_chain! {
 pub struct Config<B, C> {
     pub bus: Option<B>,
     pub binding: Option<C>,
+    // log incoming commands instead of applying them
+    pub dry_run: Option<bool>,
 }
 
 impl<B, C> Config<B, C> {
@@ -18,6 +18,8 @@ use item::Item;
 use item::Meta;
 
 use std::error::Error as SError;
+use std::rc::Rc;
+use std::time::SystemTime;
 
 error_chain! {
     links {
@@ -47,6 +49,11 @@ pub fn new<B, C>(handle: &Handle, cfg: Config<B::Config, C::Config>) -> Result<(
           C::Config: Default
 {
 
+    let dry_run = cfg.dry_run.unwrap_or(false);
+    if dry_run {
+        info!("dry run, commands will be logged but not applied");
+    }
+
     let (bus, messages) = match B::new(handle, &cfg.bus.unwrap_or_default()) {
         Ok(b) => b,
         Err(e) => return Err(ErrorKind::Bus(Box::new(e)).into()),
@@ -56,12 +63,22 @@ pub fn new<B, C>(handle: &Handle, cfg: Config<B::Config, C::Config>) -> Result<(
         Err(e) => return Err(ErrorKind::Binding(Box::new(e)).into()),
     };
 
+    // a single pattern subscription replaces the per-item ones if the bus
+    // supports it
+    let wildcard = match bus.subscribe_pattern("*", SubType::Command) {
+        Ok(w) => w,
+        Err(e) => return Err(ErrorKind::Bus(Box::new(e)).into()),
+    };
+
+    // command results are reported back to the bus, so both directions need it
+    let bus = Rc::new(bus);
+
     let msg_fut = messages
         .map_err(Error::from)
-        .for_each(bus_to_binding(binding));
+        .for_each(bus_to_binding(bus.clone(), binding, dry_run));
     let not_fut = notifications
         .map_err(Error::from)
-        .for_each(binding_to_bus(bus));
+        .for_each(binding_to_bus(bus, wildcard));
 
     Ok((msg_fut, not_fut))
 }
@@ -78,8 +95,12 @@ pub fn from_file<B, C>(handle: &Handle, config_file: &str) -> Result<(impl Futur
     Ok(new::<B, C>(handle, cfg)?)
 }
 
-fn bus_to_binding<C>(binding: C) -> impl FnMut(Message) -> Result<()>
-    where C: Binding
+fn bus_to_binding<B, C>(bus: Rc<B>,
+                        binding: C,
+                        dry_run: bool)
+                        -> impl FnMut(Message) -> Result<()>
+    where B: Bus,
+          C: Binding
 {
     move |msg| {
         debug!("got message: {:?}", msg);
@@ -97,20 +118,43 @@ fn bus_to_binding<C>(binding: C) -> impl FnMut(Message) -> Result<()>
             Some(v) => v.clone(),
             None => {
                 debug!("could not find item for command");
+                if let Err(e) = bus.command_result(name, Err(format!("unknown item: {}", name))) {
+                    warn!("bus command result error: {:?}", e);
+                }
                 return Ok(())
             }
         };
 
-        match val.set_value(value.clone()) {
-            Ok(_) => {}
-            Err(e) => warn!("error setting value from {:?}: {:?}", msg, e),
+        let result = if dry_run {
+            match val.describe_set(value.clone()) {
+                Ok(setter) => {
+                    info!("dry run: command for {} with {:?} would call {}", name, value, setter);
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!("dry run: invalid command {:?}: {:?}", msg, e);
+                    Err(format!("{}", e))
+                }
+            }
+        } else {
+            match val.set_value(value.clone()) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    warn!("error setting value from {:?}: {:?}", msg, e);
+                    Err(format!("{}", e))
+                }
+            }
         };
 
+        if let Err(e) = bus.command_result(name, result) {
+            warn!("bus command result error: {:?}", e);
+        }
+
         Ok(())
     }
 }
 
-fn binding_to_bus<B, V>(bus: B) -> impl FnMut(Notification<V>) -> Result<()>
+fn binding_to_bus<B, V>(bus: Rc<B>, wildcard: bool) -> impl FnMut(Notification<V>) -> Result<()>
     where V: Item + Sized,
           B: Bus
 {
@@ -141,15 +185,20 @@ fn binding_to_bus<B, V>(bus: B) -> impl FnMut(Notification<V>) -> Result<()>
             }
         }
 
-        if new_sub {
+        if new_sub && !wildcard {
             if let Err(e) = bus.subscribe(&val.get_name(), SubType::Command) {
                 warn!("bus subscribe error: {:?}", e);
             }
         }
 
         if remove_sub {
-            if let Err(e) = bus.unsubscribe(&val.get_name(), SubType::Command) {
-                warn!("bus unsubscribe error: {:?}", e);
+            if !wildcard {
+                if let Err(e) = bus.unsubscribe(&val.get_name(), SubType::Command) {
+                    warn!("bus unsubscribe error: {:?}", e);
+                }
+            }
+            if let Err(e) = bus.clear(&val.get_name()) {
+                warn!("bus clear error: {:?}", e);
             }
         }
 
@@ -165,7 +214,8 @@ fn binding_to_bus<B, V>(bus: B) -> impl FnMut(Notification<V>) -> Result<()>
             }
         };
 
-        if let Err(e) = bus.publish(Message::Update(val.get_name(), value)) {
+        let ts = val.get_timestamp().unwrap_or_else(SystemTime::now);
+        if let Err(e) = bus.publish(Message::Update(val.get_name(), value, ts)) {
             warn!("bus publish error: {:?}", e);
         }
         Ok(())
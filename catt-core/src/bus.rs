@@ -1,17 +1,23 @@
 use tokio_core::reactor::Handle;
 use tokio_core::channel::Receiver;
 
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
 use value::Value;
 use item::Meta;
+use util::always_lock;
 
-#[derive(Debug)]
+#[derive(Debug,Clone)]
 pub enum Message {
-    Update(String, Value),
+    // the timestamp is when the binding observed the new value
+    Update(String, Value, SystemTime),
     Command(String, Value),
     Meta(String, Meta),
 }
 
-#[derive(Debug)]
+#[derive(Debug,Clone,Copy,PartialEq)]
 pub enum SubType {
     Update,
     Command,
@@ -27,6 +33,151 @@ pub trait Bus {
         where Self: ::std::marker::Sized;
 
     fn publish(&self, Message) -> Result<(), Self::Error>;
+
+    // publishes a message that only matters to whoever is listening right
+    // now, e.g. the answer to a query. buses that keep the latest message of
+    // an item for later subscribers (retained messages, state hashes) must
+    // not keep these.
+    fn publish_transient(&self, message: Message) -> Result<(), Self::Error> {
+        self.publish(message)
+    }
     fn subscribe(&self, item_name: &str, SubType) -> Result<(), Self::Error>;
     fn unsubscribe(&self, item_name: &str, SubType) -> Result<(), Self::Error>;
+
+    // called when an item goes away so that any state the bus keeps for it
+    // (e.g. retained messages) can be dropped.
+    #[allow(unused_variables)]
+    fn clear(&self, item_name: &str) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    // subscribes to every item whose name matches the pattern (see
+    // `pattern_matches`). returns false if the bus doesn't support pattern
+    // subscriptions, in which case items have to be subscribed one by one.
+    #[allow(unused_variables)]
+    fn subscribe_pattern(&self, pattern: &str, SubType) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    #[allow(unused_variables)]
+    fn unsubscribe_pattern(&self, pattern: &str, SubType) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    // reports the outcome of a command received from this bus once the
+    // binding has handled it. commands are handled in the order they arrive,
+    // so buses that answer commands can match results up by item name.
+    #[allow(unused_variables)]
+    fn command_result(&self,
+                      item_name: &str,
+                      result: ::std::result::Result<(), String>)
+                      -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// matches an item name against a pattern where '*' matches any sequence of
+// characters, including none.
+pub fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+
+    let first = parts[0];
+    let last = parts[parts.len() - 1];
+    if name.len() < first.len() + last.len() || !name.starts_with(first) ||
+       !name.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// item and pattern subscriptions for buses that receive everything under a
+// common prefix and have to filter incoming messages themselves
+#[derive(Clone,Default)]
+pub struct Subscriptions {
+    subs: Arc<Mutex<SubscriptionList>>,
+}
+
+#[derive(Default)]
+struct SubscriptionList {
+    items: Vec<(String, SubType)>,
+    patterns: Vec<(String, SubType)>,
+}
+
+impl Subscriptions {
+    pub fn add_item(&self, name: &str, sub_type: SubType) {
+        always_lock(self.subs.lock()).items.push((name.into(), sub_type));
+    }
+
+    pub fn remove_item(&self, name: &str, sub_type: SubType) {
+        always_lock(self.subs.lock()).items.retain(|&(ref n, s)| !(n == name && s == sub_type));
+    }
+
+    pub fn add_pattern(&self, pattern: &str, sub_type: SubType) {
+        always_lock(self.subs.lock()).patterns.push((pattern.into(), sub_type));
+    }
+
+    pub fn remove_pattern(&self, pattern: &str, sub_type: SubType) {
+        always_lock(self.subs.lock())
+            .patterns
+            .retain(|&(ref p, s)| !(p == pattern && s == sub_type));
+    }
+
+    // sub_type is the type of the incoming message, never `All`
+    pub fn accepts(&self, name: &str, sub_type: SubType) -> bool {
+        let subs = always_lock(self.subs.lock());
+        let covers = |s: SubType| s == sub_type || s == SubType::All;
+        subs.items.iter().any(|&(ref n, s)| n == name && covers(s)) ||
+        subs.patterns.iter().any(|&(ref p, s)| pattern_matches(p, name) && covers(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubType;
+    use super::Subscriptions;
+    use super::pattern_matches;
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("light", "light"));
+        assert!(!pattern_matches("light", "lights"));
+        assert!(pattern_matches("*", ""));
+        assert!(pattern_matches("*", "a/b"));
+        assert!(pattern_matches("kitchen_*", "kitchen_light"));
+        assert!(pattern_matches("*_temp", "bath_temp"));
+        assert!(pattern_matches("a*b*c", "aXbYc"));
+        assert!(pattern_matches("a*b*c", "abc"));
+        assert!(!pattern_matches("a*b*c", "aXc"));
+        // the prefix and suffix may not overlap
+        assert!(!pattern_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn subscriptions() {
+        let subs = Subscriptions::default();
+        assert!(!subs.accepts("light", SubType::Command));
+
+        subs.add_item("light", SubType::Command);
+        subs.add_pattern("temp_*", SubType::All);
+        assert!(subs.accepts("light", SubType::Command));
+        assert!(!subs.accepts("light", SubType::Update));
+        assert!(subs.accepts("temp_bath", SubType::Update));
+        assert!(subs.accepts("temp_bath", SubType::Meta));
+
+        subs.remove_item("light", SubType::Command);
+        subs.remove_pattern("temp_*", SubType::All);
+        assert!(!subs.accepts("light", SubType::Command));
+        assert!(!subs.accepts("temp_bath", SubType::Update));
+    }
 }
@@ -0,0 +1,413 @@
+use tokio_core::channel::Sender;
+
+use bus;
+use bus::Message;
+use bus::SubType;
+use bus::Subscriptions;
+
+use util::always_lock;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+
+pub mod protocol;
+pub use self::protocol::Request;
+
+error_chain! {
+    foreign_links {
+        ::rustc_serialize::json::ParserError, JsonParseError;
+    }
+
+    errors {
+        InvalidRequest(reason: String) {
+            description("invalid request")
+            display("invalid request: {}", reason)
+        }
+    }
+}
+
+// frames buffered for a connection. one that falls further behind, or
+// subscribes to more items than fit at once, is dropped rather than letting
+// its buffer grow without limit.
+pub const QUEUE_SIZE: usize = 1024;
+
+struct Connection<T> {
+    id: usize,
+    patterns: Vec<String>,
+    out: mpsc::SyncSender<T>,
+}
+
+impl<T> Connection<T> {
+    // false if the connection has to be dropped
+    fn deliver(&self, frame: T) -> bool {
+        match self.out.try_send(frame) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                warn!("client {} is lagging behind, dropping it", self.id);
+                false
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// the clients of a bus that speaks `protocol` to many connections, each
+// subscribed to its own set of item patterns. every connection has a writer
+// that takes frames from the receiver it got when registering, and closes
+// the connection once the receiver runs dry.
+pub struct Registry<T> {
+    connections: Arc<Mutex<Vec<Connection<T>>>>,
+    // latest update and meta frames for each item so that new subscribers
+    // don't have to wait for the next change
+    last: Arc<Mutex<BTreeMap<String, (Option<String>, Option<String>)>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for Registry<T> {
+    fn clone(&self) -> Self {
+        Registry {
+            connections: self.connections.clone(),
+            last: self.last.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Registry {
+            connections: Default::default(),
+            last: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+}
+
+impl<T: From<String>> Registry<T> {
+    pub fn register(&self) -> (usize, mpsc::Receiver<T>) {
+        let (out, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        always_lock(self.connections.lock()).push(Connection {
+            id: id,
+            patterns: vec![],
+            out: out,
+        });
+        (id, rx)
+    }
+
+    pub fn unregister(&self, id: usize) {
+        always_lock(self.connections.lock()).retain(|c| c.id != id);
+    }
+
+    // sends a frame to a single connection, e.g. the reply to a request
+    pub fn send(&self, id: usize, frame: T) {
+        let mut connections = always_lock(self.connections.lock());
+        let delivered = match connections.iter().find(|c| c.id == id) {
+            Some(c) => c.deliver(frame),
+            None => return,
+        };
+        if !delivered {
+            connections.retain(|c| c.id != id);
+        }
+    }
+
+    fn subscribe(&self, id: usize, patterns: Vec<String>) {
+        let mut connections = always_lock(self.connections.lock());
+        let i = match connections.iter().position(|c| c.id == id) {
+            Some(i) => i,
+            None => return,
+        };
+
+        // replay the current state of everything the new patterns cover
+        let mut delivered = true;
+        {
+            let conn = &connections[i];
+            let last = always_lock(self.last.lock());
+            for (name, &(ref update, ref meta)) in last.iter() {
+                if !patterns.iter().any(|p| bus::pattern_matches(p, name)) ||
+                   conn.patterns.iter().any(|p| bus::pattern_matches(p, name)) {
+                    continue;
+                }
+                for frame in meta.iter().chain(update.iter()) {
+                    delivered = delivered && conn.deliver(frame.clone().into());
+                }
+            }
+        }
+        if !delivered {
+            connections.remove(i);
+            return;
+        }
+
+        let conn = &mut connections[i];
+        for p in patterns {
+            if !conn.patterns.contains(&p) {
+                conn.patterns.push(p);
+            }
+        }
+    }
+
+    fn unsubscribe(&self, id: usize, patterns: Vec<String>) {
+        let mut connections = always_lock(self.connections.lock());
+        if let Some(conn) = connections.iter_mut().find(|c| c.id == id) {
+            conn.patterns.retain(|p| !patterns.contains(p));
+        }
+    }
+
+    // sends a frame to every connection subscribed to the item, dropping
+    // connections that have gone away or can't keep up
+    fn broadcast(&self, name: &str, frame: String) {
+        let mut connections = always_lock(self.connections.lock());
+        connections.retain(|c| {
+            if !c.patterns.iter().any(|p| bus::pattern_matches(p, name)) {
+                return true;
+            }
+            c.deliver(frame.clone().into())
+        });
+    }
+
+    // handles a request frame from connection id. commands go to tx if the
+    // bridge is subscribed to the item.
+    pub fn handle_request(&self,
+                          id: usize,
+                          text: &str,
+                          filter: &Subscriptions,
+                          tx: &Mutex<Sender<Message>>)
+                          -> Result<()> {
+        match protocol::parse_request(text)? {
+            Request::Subscribe(patterns) => self.subscribe(id, patterns),
+            Request::Unsubscribe(patterns) => self.unsubscribe(id, patterns),
+            Request::Command(name, value) => {
+                if !filter.accepts(&name, SubType::Command) {
+                    return Err(ErrorKind::InvalidRequest(format!("unknown item {}", name)).into());
+                }
+                if let Err(e) = always_lock(tx.lock()).send(Message::Command(name, value)) {
+                    warn!("channel send error: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // sends updates and meta to the subscribed connections and remembers them
+    // for later subscribers
+    pub fn publish(&self, message: Message) {
+        let (name, frame) = match message {
+            Message::Update(name, value, ts) => {
+                let frame = protocol::update(&name, &value, ts);
+                always_lock(self.last.lock())
+                    .entry(name.clone())
+                    .or_insert((None, None))
+                    .0 = Some(frame.clone());
+                (name, frame)
+            }
+            Message::Meta(name, meta) => {
+                let frame = protocol::meta(&name, &meta);
+                always_lock(self.last.lock())
+                    .entry(name.clone())
+                    .or_insert((None, None))
+                    .1 = Some(frame.clone());
+                (name, frame)
+            }
+            // clients only ever send commands
+            Message::Command(name, _) => {
+                debug!("ignoring command for {}", name);
+                return;
+            }
+        };
+
+        self.broadcast(&name, frame);
+    }
+
+    // like publish, but updates aren't replayed to later subscribers
+    pub fn publish_transient(&self, message: Message) {
+        match message {
+            Message::Update(name, value, ts) => {
+                self.broadcast(&name, protocol::update(&name, &value, ts))
+            }
+            message => self.publish(message),
+        }
+    }
+
+    pub fn clear(&self, name: &str) {
+        always_lock(self.last.lock()).remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QUEUE_SIZE;
+    use super::Registry;
+
+    use bus::Message;
+    use bus::SubType;
+    use bus::Subscriptions;
+    use item::Meta;
+    use value::Value;
+
+    use rustc_serialize::json::Json;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::channel::channel;
+    use tokio_core::channel::Sender;
+    use tokio_core::reactor::Core;
+
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    fn update(name: &str, n: f64) -> Message {
+        Message::Update(name.into(), Value::Number(n), SystemTime::now())
+    }
+
+    // the (type, name) of every frame waiting for a connection
+    fn frames(rx: &mpsc::Receiver<String>) -> Vec<(String, String)> {
+        let mut frames = vec![];
+        while let Ok(frame) = rx.try_recv() {
+            let frame = Json::from_str(&frame).unwrap();
+            let field = |f: &str| frame.find(f).and_then(|v| v.as_string()).unwrap().to_string();
+            frames.push((field("type"), field("name")));
+        }
+        frames
+    }
+
+    fn frame(frame_type: &str, name: &str) -> (String, String) {
+        (frame_type.to_string(), name.to_string())
+    }
+
+    // a sender for commands along with whatever arrives through it
+    fn commands(core: &Core) -> (Mutex<Sender<Message>>, mpsc::Receiver<Message>) {
+        let (tx, rx) = channel(&core.handle()).unwrap();
+        let (fwd, received) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = fwd.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+        (Mutex::new(tx), received)
+    }
+
+    #[test]
+    fn per_connection_patterns() {
+        let core = Core::new().unwrap();
+        let (tx, _) = commands(&core);
+        let filter = Subscriptions::default();
+        let registry = Registry::<String>::default();
+
+        let (a, rx_a) = registry.register();
+        let (b, rx_b) = registry.register();
+        registry.handle_request(a, r#"{"type":"subscribe","items":["kitchen/*"]}"#, &filter, &tx)
+            .unwrap();
+        registry.handle_request(b, r#"{"type":"subscribe","items":["hall"]}"#, &filter, &tx)
+            .unwrap();
+
+        for name in &["kitchen/light", "hall", "other"] {
+            registry.publish(update(name, 1.0));
+        }
+        assert_eq!(frames(&rx_a), vec![frame("update", "kitchen/light")]);
+        assert_eq!(frames(&rx_b), vec![frame("update", "hall")]);
+
+        registry.handle_request(a, r#"{"type":"unsubscribe","items":["kitchen/*"]}"#, &filter, &tx)
+            .unwrap();
+        registry.publish(update("kitchen/light", 2.0));
+        assert!(frames(&rx_a).is_empty());
+
+        // a connection that went away is dropped on the next broadcast
+        drop(rx_b);
+        registry.publish(update("hall", 2.0));
+        registry.unregister(a);
+        assert!(registry.connections.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn subscribe_replays_state() {
+        let core = Core::new().unwrap();
+        let (tx, _) = commands(&core);
+        let filter = Subscriptions::default();
+        let registry = Registry::<String>::default();
+        registry.publish(update("lamp", 1.0));
+        registry.publish(Message::Meta("lamp".into(), Meta::default()));
+        registry.publish(update("other", 1.0));
+
+        let (id, rx) = registry.register();
+        let subscribe = r#"{"type":"subscribe","items":["lamp"]}"#;
+        registry.handle_request(id, subscribe, &filter, &tx).unwrap();
+        assert_eq!(frames(&rx), vec![frame("meta", "lamp"), frame("update", "lamp")]);
+
+        // items that were already covered aren't replayed again
+        registry.handle_request(id, r#"{"type":"subscribe","items":["l*"]}"#, &filter, &tx)
+            .unwrap();
+        assert!(frames(&rx).is_empty());
+
+        // neither transient updates nor cleared items are replayed
+        registry.publish_transient(update("lamp/history", 1.0));
+        registry.clear("lamp");
+        let (late, late_rx) = registry.register();
+        registry.handle_request(late, r#"{"type":"subscribe","items":["*"]}"#, &filter, &tx)
+            .unwrap();
+        assert_eq!(frames(&late_rx), vec![frame("update", "other")]);
+    }
+
+    #[test]
+    fn commands_need_a_subscription() {
+        let mut core = Core::new().unwrap();
+        let (tx, received) = commands(&core);
+        let filter = Subscriptions::default();
+        let registry = Registry::<String>::default();
+        let (id, _rx) = registry.register();
+
+        let command = r#"{"type":"command","name":"lamp","value":true}"#;
+        assert!(registry.handle_request(id, command, &filter, &tx).is_err());
+        filter.add_item("lamp", SubType::Command);
+        registry.handle_request(id, command, &filter, &tx).unwrap();
+
+        let mut command = received.try_recv();
+        for _ in 0..50 {
+            if command.is_ok() {
+                break;
+            }
+            core.turn(Some(Duration::from_millis(100)));
+            command = received.try_recv();
+        }
+        match command {
+            Ok(Message::Command(name, value)) => {
+                assert_eq!(name, "lamp");
+                assert_eq!(value, Value::Bool(true));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+        assert!(received.try_recv().is_err());
+
+        for invalid in &["{", "[]", r#"{"type":"other"}"#, r#"{"type":"subscribe"}"#] {
+            assert!(registry.handle_request(id, invalid, &filter, &tx).is_err());
+        }
+    }
+
+    #[test]
+    fn lagging_connection_is_dropped() {
+        let core = Core::new().unwrap();
+        let (tx, _) = commands(&core);
+        let filter = Subscriptions::default();
+        let registry = Registry::<String>::default();
+        let (id, rx) = registry.register();
+        registry.handle_request(id, r#"{"type":"subscribe","items":["*"]}"#, &filter, &tx)
+            .unwrap();
+
+        for i in 0..QUEUE_SIZE + 1 {
+            registry.publish(update("lamp", i as f64));
+        }
+
+        // what was queued is still delivered, then the receiver runs dry
+        for _ in 0..QUEUE_SIZE {
+            assert!(rx.try_recv().is_ok());
+        }
+        assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+        registry.send(id, "ignored".into());
+    }
+}
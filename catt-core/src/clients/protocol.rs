@@ -0,0 +1,208 @@
+// json messages exchanged with clients of the websocket and ipc buses, one
+// per text frame or line.
+//
+// server -> client:
+//   {"type":"update","name":...,"value":...,"value_type":...,"ts":...}
+//   {"type":"meta","name":...,"meta":{"backend":...,"value_type":...,"ext":{...}}}
+//   {"type":"error","error":...}
+//
+// client -> server:
+//   {"type":"subscribe","items":["<pattern>", ...]}
+//   {"type":"unsubscribe","items":["<pattern>", ...]}
+//   {"type":"command","name":...,"value":...}
+//
+// patterns use the same '*' globbing as bus pattern subscriptions.
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use rustc_serialize::json::Json;
+
+use item::Meta;
+use value::Value;
+use util;
+
+use super::Error;
+use super::ErrorKind;
+use super::Result;
+
+#[derive(Debug)]
+pub enum Request {
+    Subscribe(Vec<String>),
+    Unsubscribe(Vec<String>),
+    Command(String, Value),
+}
+
+pub fn update(name: &str, value: &Value, ts: SystemTime) -> String {
+    let mut obj = message("update", name);
+    obj.insert("value".to_string(), value.to_json());
+    obj.insert("value_type".to_string(), Json::String(value.type_string().into()));
+    obj.insert("ts".to_string(), Json::String(util::format_ts(ts)));
+    Json::Object(obj).to_string()
+}
+
+pub fn meta(name: &str, meta: &Meta) -> String {
+    let mut obj = message("meta", name);
+    obj.insert("meta".to_string(), meta.to_json());
+    Json::Object(obj).to_string()
+}
+
+pub fn error(msg: &str) -> String {
+    let mut obj = BTreeMap::new();
+    obj.insert("type".to_string(), Json::String("error".into()));
+    obj.insert("error".to_string(), Json::String(msg.into()));
+    Json::Object(obj).to_string()
+}
+
+pub fn parse_request(text: &str) -> Result<Request> {
+    let mut obj = match Json::from_str(text)? {
+        Json::Object(obj) => obj,
+        _ => return Err(invalid("expected an object")),
+    };
+
+    let request_type = match obj.remove("type") {
+        Some(Json::String(t)) => t,
+        _ => return Err(invalid("missing type")),
+    };
+
+    match request_type.as_str() {
+        "subscribe" => Ok(Request::Subscribe(patterns(obj.remove("items"))?)),
+        "unsubscribe" => Ok(Request::Unsubscribe(patterns(obj.remove("items"))?)),
+        "command" => {
+            let name = match obj.remove("name") {
+                Some(Json::String(n)) => n,
+                _ => return Err(invalid("missing item name")),
+            };
+            let value = match obj.remove("value") {
+                Some(Json::Boolean(b)) => Value::Bool(b),
+                Some(Json::I64(n)) => Value::Number(n as f64),
+                Some(Json::U64(n)) => Value::Number(n as f64),
+                Some(Json::F64(n)) => Value::Number(n),
+                Some(Json::String(s)) => Value::String(s),
+                _ => return Err(invalid("missing or unsupported value")),
+            };
+            Ok(Request::Command(name, value))
+        }
+        t => Err(invalid(&format!("unknown request type {}", t))),
+    }
+}
+
+fn patterns(items: Option<Json>) -> Result<Vec<String>> {
+    let items = match items {
+        Some(Json::Array(items)) => items,
+        _ => return Err(invalid("items must be a list")),
+    };
+
+    items.into_iter()
+        .map(|i| match i {
+            Json::String(s) => Ok(s),
+            _ => Err(invalid("items must be strings")),
+        })
+        .collect()
+}
+
+fn invalid(reason: &str) -> Error {
+    ErrorKind::InvalidRequest(reason.into()).into()
+}
+
+fn message(message_type: &str, name: &str) -> BTreeMap<String, Json> {
+    let mut obj = BTreeMap::new();
+    obj.insert("type".to_string(), Json::String(message_type.into()));
+    obj.insert("name".to_string(), Json::String(name.into()));
+    obj
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Request;
+    use super::error;
+    use super::meta;
+    use super::parse_request;
+    use super::update;
+
+    use item::Meta;
+    use value::Value;
+
+    use rustc_serialize::json::Json;
+
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn frames() {
+        let frame = Json::from_str(&update("lamp", &Value::Bool(true), UNIX_EPOCH)).unwrap();
+        assert_eq!(frame.find("type").and_then(|t| t.as_string()), Some("update"));
+        assert_eq!(frame.find("name").and_then(|n| n.as_string()), Some("lamp"));
+        assert_eq!(frame.find("value"), Some(&Json::Boolean(true)));
+        assert_eq!(frame.find("value_type").and_then(|t| t.as_string()), Some("bool"));
+        assert!(frame.find("ts").and_then(|t| t.as_string()).is_some());
+
+        let mut ext = HashMap::new();
+        ext.insert("unit".to_string(), "C".to_string());
+        let item_meta = Meta {
+            backend: Some("zwave".into()),
+            value_type: None,
+            ext: Some(ext),
+        };
+        let frame = Json::from_str(&meta("temp", &item_meta)).unwrap();
+        assert_eq!(frame.find("type").and_then(|t| t.as_string()), Some("meta"));
+        assert_eq!(frame.find_path(&["meta", "backend"]).and_then(|b| b.as_string()),
+                   Some("zwave"));
+        assert_eq!(frame.find_path(&["meta", "value_type"]), Some(&Json::Null));
+        assert_eq!(frame.find_path(&["meta", "ext", "unit"]).and_then(|u| u.as_string()),
+                   Some("C"));
+
+        let frame = Json::from_str(&error("nope")).unwrap();
+        assert_eq!(frame.find("type").and_then(|t| t.as_string()), Some("error"));
+        assert_eq!(frame.find("error").and_then(|e| e.as_string()), Some("nope"));
+        // frames are sent one per line
+        assert!(!update("a\nb", &Value::String("c\nd".into()), SystemTime::now()).contains('\n'));
+    }
+
+    #[test]
+    fn requests() {
+        match parse_request(r#"{"type":"subscribe","items":["a","b/*"]}"#).unwrap() {
+            Request::Subscribe(items) => assert_eq!(items, vec!["a", "b/*"]),
+            r => panic!("unexpected request {:?}", r),
+        }
+        match parse_request(r#"{"type":"unsubscribe","items":[]}"#).unwrap() {
+            Request::Unsubscribe(items) => assert!(items.is_empty()),
+            r => panic!("unexpected request {:?}", r),
+        }
+
+        let values = vec![("true", Value::Bool(true)),
+                          ("-2", Value::Number(-2.0)),
+                          ("3", Value::Number(3.0)),
+                          ("1.5", Value::Number(1.5)),
+                          (r#""ON""#, Value::String("ON".into()))];
+        for (json, value) in values {
+            let text = format!(r#"{{"type":"command","name":"lamp","value":{}}}"#, json);
+            match parse_request(&text).unwrap() {
+                Request::Command(name, v) => {
+                    assert_eq!(name, "lamp");
+                    assert_eq!(v, value);
+                }
+                r => panic!("unexpected request {:?}", r),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let invalid = ["",
+                       "[]",
+                       "{}",
+                       r#"{"type":"other"}"#,
+                       r#"{"type":"subscribe"}"#,
+                       r#"{"type":"subscribe","items":"a"}"#,
+                       r#"{"type":"subscribe","items":[1]}"#,
+                       r#"{"type":"command","value":1}"#,
+                       r#"{"type":"command","name":"lamp"}"#,
+                       r#"{"type":"command","name":"lamp","value":null}"#,
+                       r#"{"type":"command","name":"lamp","value":[1]}"#];
+        for text in invalid.iter() {
+            assert!(parse_request(text).is_err(), "{} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use tokio_core::reactor::Handle;
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use futures::Future;
+use futures::stream::Stream;
+
+use bus::Bus;
+use bus::Message;
+use bus::SubType;
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::error::Error as SError;
+use std::rc::Rc;
+
+error_chain! {
+    foreign_links {
+        ::std::io::Error, IoError;
+    }
+
+    errors {
+        Primary(e: Box<SError + Send + 'static>) {
+            display("primary bus error: {}", e)
+            description("primary bus error")
+        }
+        Secondary(e: Box<SError + Send + 'static>) {
+            display("secondary bus error: {}", e)
+            description("secondary bus error")
+        }
+    }
+}
+
+#[derive(Default, RustcDecodable)]
+pub struct Config<P, S> {
+    pub primary: Option<P>,
+    pub secondary: Option<S>,
+}
+
+#[derive(Debug,Clone,Copy)]
+enum Origin {
+    Primary,
+    Secondary,
+}
+
+// which bus each outstanding command came from, oldest first
+type Pending = Rc<RefCell<BTreeMap<String, VecDeque<Origin>>>>;
+
+// publishes to both buses and accepts commands from either of them. more than
+// two buses can be combined by nesting, e.g. Composite<Mqtt, Composite<Http,
+// WebSocket>>.
+pub struct Composite<P, S> {
+    primary: P,
+    secondary: S,
+    pending: Pending,
+}
+
+fn primary_error<E: SError + Send + 'static>(e: E) -> Error {
+    ErrorKind::Primary(Box::new(e)).into()
+}
+
+fn secondary_error<E: SError + Send + 'static>(e: E) -> Error {
+    ErrorKind::Secondary(Box::new(e)).into()
+}
+
+// feeds the messages from one of the buses into the combined receiver
+fn forward(handle: &Handle,
+           messages: Receiver<Message>,
+           tx: Sender<Message>,
+           origin: Origin,
+           pending: Pending) {
+    let fut = messages.for_each(move |msg| {
+            let name = match msg {
+                Message::Command(ref name, _) => Some(name.clone()),
+                _ => None,
+            };
+            tx.send(msg)?;
+            if let Some(name) = name {
+                pending.borrow_mut().entry(name).or_insert_with(VecDeque::new).push_back(origin);
+            }
+            Ok(())
+        })
+        .map_err(move |e| warn!("{:?} bus receive error: {}", origin, e));
+    handle.spawn(fut);
+}
+
+impl<P, S> Bus for Composite<P, S>
+    where P: Bus,
+          S: Bus,
+          P::Config: Default,
+          S::Config: Default
+{
+    type Config = Config<P::Config, S::Config>;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        let (primary, primary_rx) = match cfg.primary {
+                Some(ref c) => P::new(handle, c),
+                None => P::new(handle, &Default::default()),
+            }
+            .map_err(primary_error)?;
+        let (secondary, secondary_rx) = match cfg.secondary {
+                Some(ref c) => S::new(handle, c),
+                None => S::new(handle, &Default::default()),
+            }
+            .map_err(secondary_error)?;
+
+        let (tx, rx) = channel(handle)?;
+        let pending = Pending::default();
+        forward(handle, primary_rx, tx.clone(), Origin::Primary, pending.clone());
+        forward(handle, secondary_rx, tx, Origin::Secondary, pending.clone());
+
+        let composite = Composite {
+            primary: primary,
+            secondary: secondary,
+            pending: pending,
+        };
+
+        Ok((composite, rx))
+    }
+
+    // a failing bus shouldn't keep the message from the other one
+    fn publish(&self, message: Message) -> Result<()> {
+        let primary = self.primary.publish(message.clone()).map_err(primary_error);
+        let secondary = self.secondary.publish(message).map_err(secondary_error);
+        primary.and(secondary)
+    }
+
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        let primary = self.primary.publish_transient(message.clone()).map_err(primary_error);
+        let secondary = self.secondary.publish_transient(message).map_err(secondary_error);
+        primary.and(secondary)
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        let primary = self.primary.subscribe(item_name, sub_type).map_err(primary_error);
+        let secondary = self.secondary.subscribe(item_name, sub_type).map_err(secondary_error);
+        primary.and(secondary)
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        let primary = self.primary.unsubscribe(item_name, sub_type).map_err(primary_error);
+        let secondary = self.secondary.unsubscribe(item_name, sub_type).map_err(secondary_error);
+        primary.and(secondary)
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        let primary = self.primary.clear(item_name).map_err(primary_error);
+        let secondary = self.secondary.clear(item_name).map_err(secondary_error);
+        primary.and(secondary)
+    }
+
+    // only reports pattern support if both buses have it. otherwise the
+    // pattern is dropped again so that both get the per-item subscriptions
+    // and stay in step. a failure leaves neither bus with the pattern.
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        let primary = self.primary.subscribe_pattern(pattern, sub_type).map_err(primary_error)?;
+        let secondary = match self.secondary.subscribe_pattern(pattern, sub_type) {
+            Ok(s) => s,
+            Err(e) => {
+                if primary {
+                    if let Err(e) = self.primary.unsubscribe_pattern(pattern, sub_type) {
+                        warn!("error dropping pattern {} from the primary bus: {}", pattern, e);
+                    }
+                }
+                return Err(secondary_error(e));
+            }
+        };
+
+        if primary && !secondary {
+            self.primary.unsubscribe_pattern(pattern, sub_type).map_err(primary_error)?;
+        }
+        if secondary && !primary {
+            self.secondary.unsubscribe_pattern(pattern, sub_type).map_err(secondary_error)?;
+        }
+        Ok(primary && secondary)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        let primary = self.primary.unsubscribe_pattern(pattern, sub_type).map_err(primary_error);
+        let secondary = self.secondary
+            .unsubscribe_pattern(pattern, sub_type)
+            .map_err(secondary_error);
+        Ok(primary? && secondary?)
+    }
+
+    // commands are handled in the order they arrive, so the oldest pending
+    // command for the item is the one this result belongs to
+    fn command_result(&self,
+                      item_name: &str,
+                      result: ::std::result::Result<(), String>)
+                      -> Result<()> {
+        let origin = {
+            let mut pending = self.pending.borrow_mut();
+            let origin = pending.get_mut(item_name).and_then(|q| q.pop_front());
+            if pending.get(item_name).map(|q| q.is_empty()).unwrap_or(false) {
+                pending.remove(item_name);
+            }
+            origin
+        };
+
+        match origin {
+            Some(Origin::Primary) => {
+                self.primary.command_result(item_name, result).map_err(primary_error)
+            }
+            Some(Origin::Secondary) => {
+                self.secondary.command_result(item_name, result).map_err(secondary_error)
+            }
+            None => {
+                debug!("no pending command for {}", item_name);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Composite;
+
+    use bus::Bus;
+    use bus::Message;
+    use bus::SubType;
+    use value::Value;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::channel::channel;
+    use tokio_core::channel::Receiver;
+    use tokio_core::channel::Sender;
+    use tokio_core::reactor::Core;
+    use tokio_core::reactor::Handle;
+
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::io;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    // records what goes through it. commands are injected through tx.
+    struct Stub {
+        tx: Sender<Message>,
+        sent: RefCell<Vec<Message>>,
+        patterns: RefCell<Vec<String>>,
+        results: RefCell<Vec<(String, Result<(), String>)>>,
+        fail: Cell<bool>,
+        supports_patterns: Cell<bool>,
+    }
+
+    impl Stub {
+        fn check(&self) -> io::Result<()> {
+            if self.fail.get() {
+                Err(io::Error::new(io::ErrorKind::Other, "failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl Bus for Stub {
+        type Config = ();
+        type Error = io::Error;
+
+        fn new(handle: &Handle, _: &()) -> io::Result<(Self, Receiver<Message>)> {
+            let (tx, rx) = channel(handle)?;
+            let stub = Stub {
+                tx: tx,
+                sent: Default::default(),
+                patterns: Default::default(),
+                results: Default::default(),
+                fail: Cell::new(false),
+                supports_patterns: Cell::new(true),
+            };
+            Ok((stub, rx))
+        }
+
+        fn publish(&self, message: Message) -> io::Result<()> {
+            self.check()?;
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn subscribe(&self, _: &str, _: SubType) -> io::Result<()> {
+            self.check()
+        }
+
+        fn unsubscribe(&self, _: &str, _: SubType) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn subscribe_pattern(&self, pattern: &str, _: SubType) -> io::Result<bool> {
+            self.check()?;
+            if self.supports_patterns.get() {
+                self.patterns.borrow_mut().push(pattern.into());
+            }
+            Ok(self.supports_patterns.get())
+        }
+
+        fn unsubscribe_pattern(&self, pattern: &str, _: SubType) -> io::Result<bool> {
+            self.patterns.borrow_mut().retain(|p| p != pattern);
+            Ok(self.supports_patterns.get())
+        }
+
+        fn command_result(&self,
+                          item_name: &str,
+                          result: Result<(), String>)
+                          -> io::Result<()> {
+            self.results.borrow_mut().push((item_name.into(), result));
+            Ok(())
+        }
+    }
+
+    type Both = Composite<Stub, Stub>;
+
+    fn composite(core: &Core) -> (Both, mpsc::Receiver<Message>) {
+        let (composite, rx) = Both::new(&core.handle(), &Default::default()).unwrap();
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+        (composite, messages)
+    }
+
+    // sends a command through one of the buses and waits for it to come out
+    // of the composite
+    fn command(core: &mut Core, messages: &mpsc::Receiver<Message>, from: &Stub, name: &str) {
+        from.tx.send(Message::Command(name.into(), Value::Bool(true))).unwrap();
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            match messages.try_recv() {
+                Ok(Message::Command(ref n, _)) if n == name => return,
+                Ok(m) => panic!("unexpected message {:?}", m),
+                Err(_) => {}
+            }
+        }
+        panic!("command for {} never arrived", name);
+    }
+
+    #[test]
+    fn fan_out() {
+        let core = Core::new().unwrap();
+        let (composite, _) = composite(&core);
+        let update = Message::Update("lamp".into(), Value::Bool(true), SystemTime::now());
+        composite.publish(update.clone()).unwrap();
+        composite.publish_transient(update.clone()).unwrap();
+        assert_eq!(composite.primary.sent.borrow().len(), 2);
+        assert_eq!(composite.secondary.sent.borrow().len(), 2);
+
+        // a failing bus doesn't keep the message from the other one
+        composite.primary.fail.set(true);
+        assert!(composite.publish(update.clone()).is_err());
+        assert_eq!(composite.secondary.sent.borrow().len(), 3);
+        assert!(composite.subscribe("lamp", SubType::Command).is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let core = Core::new().unwrap();
+        let (composite, _) = composite(&core);
+        assert!(composite.subscribe_pattern("a*", SubType::Command).unwrap());
+        assert_eq!(*composite.primary.patterns.borrow(), vec!["a*".to_string()]);
+        assert_eq!(*composite.secondary.patterns.borrow(), vec!["a*".to_string()]);
+
+        // without support on both sides neither keeps it
+        composite.secondary.supports_patterns.set(false);
+        assert!(!composite.subscribe_pattern("b*", SubType::Command).unwrap());
+        assert_eq!(*composite.primary.patterns.borrow(), vec!["a*".to_string()]);
+
+        // nor when the secondary fails
+        composite.secondary.supports_patterns.set(true);
+        composite.secondary.fail.set(true);
+        assert!(composite.subscribe_pattern("c*", SubType::Command).is_err());
+        assert_eq!(*composite.primary.patterns.borrow(), vec!["a*".to_string()]);
+        assert_eq!(*composite.secondary.patterns.borrow(), vec!["a*".to_string()]);
+    }
+
+    #[test]
+    fn command_results_go_back_to_their_bus() {
+        let mut core = Core::new().unwrap();
+        let (composite, messages) = composite(&core);
+        command(&mut core, &messages, &composite.secondary, "lamp");
+        command(&mut core, &messages, &composite.primary, "lamp");
+        command(&mut core, &messages, &composite.primary, "hall");
+
+        composite.command_result("lamp", Ok(())).unwrap();
+        composite.command_result("hall", Err("failed".into())).unwrap();
+        composite.command_result("lamp", Err("busy".into())).unwrap();
+        // nothing is pending for it any more
+        composite.command_result("lamp", Ok(())).unwrap();
+
+        assert_eq!(*composite.secondary.results.borrow(),
+                   vec![("lamp".to_string(), Ok(()))]);
+        assert_eq!(*composite.primary.results.borrow(),
+                   vec![("hall".to_string(), Err("failed".to_string())),
+                        ("lamp".to_string(), Err("busy".to_string()))]);
+        assert!(composite.pending.borrow().is_empty());
+    }
+}
@@ -1,14 +1,41 @@
 use value::Value;
 
+use rustc_serialize::json::Json;
+
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
-#[derive(RustcEncodable,Debug,RustcDecodable,Default)]
+#[derive(RustcEncodable,Debug,Clone,RustcDecodable,Default)]
 pub struct Meta {
     pub backend: Option<String>,
     pub value_type: Option<String>,
     pub ext: Option<HashMap<String, String>>,
 }
 
+impl Meta {
+    // unset fields become nulls rather than being left out
+    pub fn to_json(&self) -> Json {
+        let string_or_null = |s: &Option<String>| {
+            s.as_ref().map(|s| Json::String(s.clone())).unwrap_or(Json::Null)
+        };
+
+        let mut obj = BTreeMap::new();
+        obj.insert("backend".to_string(), string_or_null(&self.backend));
+        obj.insert("value_type".to_string(), string_or_null(&self.value_type));
+        obj.insert("ext".to_string(),
+                   self.ext
+                       .as_ref()
+                       .map(|ext| {
+                           Json::Object(ext.iter()
+                               .map(|(k, v)| (k.clone(), Json::String(v.clone())))
+                               .collect())
+                       })
+                       .unwrap_or(Json::Null));
+        Json::Object(obj)
+    }
+}
+
 pub trait Item {
     type Error: ::std::error::Error;
 
@@ -18,6 +45,18 @@ pub trait Item {
         None
     }
 
+    // when the current value was observed, if the binding knows
+    fn get_timestamp(&self) -> Option<SystemTime> {
+        None
+    }
+
     fn get_value(&self) -> Result<Value, Self::Error>;
     fn set_value(&self, Value) -> Result<(), Self::Error>;
+
+    // what set_value would do with the value, without doing it. used by the
+    // bridge's dry-run mode, so a value that set_value would reject should be
+    // an error here too.
+    fn describe_set(&self, value: Value) -> Result<String, Self::Error> {
+        Ok(format!("set {} to {:?}", self.get_name(), value))
+    }
 }
@@ -12,6 +12,8 @@ extern crate rustc_serialize;
 
 extern crate byteorder;
 
+extern crate chrono;
+
 extern crate toml;
 
 extern crate futures;
@@ -19,10 +21,13 @@ extern crate tokio_core;
 
 pub mod util;
 pub mod bus;
+pub mod composite;
 pub mod value;
 pub mod item;
 pub mod binding;
 pub mod bridge;
+pub mod clients;
+pub mod record;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,317 @@
+use rustc_serialize::json;
+
+use bus::Message;
+use item::Meta;
+use value::Value;
+use util::millis;
+
+use std::error::Error as SError;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+pub mod recorder;
+pub mod replay;
+
+pub use self::recorder::Recorder;
+pub use self::replay::Replay;
+pub use self::replay::ReplayBus;
+
+error_chain! {
+    foreign_links {
+        ::std::io::Error, IoError;
+        json::EncoderError, JsonEncodeError;
+        json::DecoderError, JsonDecodeError;
+    }
+
+    errors {
+        Bus(e: Box<SError + Send + 'static>) {
+            display("bus error: {}", e)
+            description("bus error")
+        }
+        NoValue(name: String) {
+            display("no value recorded for {}", name)
+            description("no value recorded")
+        }
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,RustcEncodable,RustcDecodable)]
+pub enum Direction {
+    // received from the bus, i.e. commands
+    FromBus,
+    // published by the bridge on behalf of the binding
+    ToBus,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,RustcEncodable,RustcDecodable)]
+pub enum Kind {
+    Update,
+    Command,
+    Meta,
+}
+
+// a single line of a recording
+#[derive(Debug,RustcEncodable,RustcDecodable)]
+pub struct Entry {
+    // milliseconds since the epoch when the message went through the bridge
+    pub ts: u64,
+    // milliseconds since the epoch when the binding observed an update
+    pub observed: Option<u64>,
+    pub direction: Direction,
+    pub kind: Kind,
+    pub name: String,
+    pub value: Option<Value>,
+    pub meta: Option<Meta>,
+}
+
+impl Entry {
+    pub fn new(direction: Direction, message: Message) -> Entry {
+        let (kind, name, value, meta, observed) = match message {
+            Message::Update(name, value, ts) => {
+                (Kind::Update, name, Some(value), None, Some(millis(ts)))
+            }
+            Message::Command(name, value) => (Kind::Command, name, Some(value), None, None),
+            Message::Meta(name, meta) => (Kind::Meta, name, None, Some(meta), None),
+        };
+
+        Entry {
+            ts: millis(SystemTime::now()),
+            observed: observed,
+            direction: direction,
+            kind: kind,
+            name: name,
+            value: value,
+            meta: meta,
+        }
+    }
+
+    // when a recorded update was observed, moved by the offset between the
+    // recording and its replay. meant to be called as the entry is replayed.
+    pub fn observed_at(&self) -> Option<SystemTime> {
+        self.observed.map(|observed| {
+            let lag = self.ts.saturating_sub(observed);
+            SystemTime::now() - Duration::from_millis(lag)
+        })
+    }
+
+    // recordings without observation times replay updates as observed now
+    pub fn into_message(self) -> Result<Message> {
+        let observed = self.observed_at().unwrap_or_else(SystemTime::now);
+        let name = self.name;
+        Ok(match self.kind {
+            Kind::Update => {
+                let value = self.value.ok_or_else(|| ErrorKind::NoValue(name.clone()))?;
+                Message::Update(name, value, observed)
+            }
+            Kind::Command => {
+                let value = self.value.ok_or_else(|| ErrorKind::NoValue(name.clone()))?;
+                Message::Command(name, value)
+            }
+            Kind::Meta => Message::Meta(name, self.meta.unwrap_or_default()),
+        })
+    }
+}
+
+// the time of the first entry of a recording, whichever direction it went.
+// both directions of a replay are timed from it so that commands keep their
+// place among the updates and meta.
+pub fn recording_start(file_name: &str) -> Result<Option<u64>> {
+    let file = File::open(file_name)?;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = json::decode(&line)?;
+        return Ok(Some(entry.ts));
+    }
+    Ok(None)
+}
+
+// reads a recording and hands every entry going in the given direction to f
+// at its recorded offset from start, divided by speed. a speed of 0 plays
+// everything back without any delay. stops early if f returns false.
+pub fn play<F>(file_name: &str,
+               start: u64,
+               speed: f64,
+               direction: Direction,
+               mut f: F)
+               -> Result<()>
+    where F: FnMut(Entry) -> bool
+{
+    let file = File::open(file_name)?;
+    let started = Instant::now();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry: Entry = json::decode(&line)?;
+        if entry.direction != direction {
+            continue;
+        }
+
+        if speed > 0.0 && entry.ts > start {
+            let due = Duration::from_millis(((entry.ts - start) as f64 / speed) as u64);
+            let elapsed = started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        if !f(entry) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// plays a recording from its own start, see `play`
+pub fn play_from_start<F>(file_name: &str, speed: f64, direction: Direction, f: F) -> Result<()>
+    where F: FnMut(Entry) -> bool
+{
+    match recording_start(file_name)? {
+        Some(start) => play(file_name, start, speed, direction, f),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Direction;
+    use super::Entry;
+    use super::Kind;
+    use super::play;
+    use super::play_from_start;
+    use super::recording_start;
+
+    use bus::Message;
+    use value::Value;
+
+    use rustc_serialize::json;
+
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+
+    // writes (ts, direction, kind, name) entries to a recording in the temp
+    // directory and returns its path
+    fn recording(file_name: &str, entries: &[(u64, Direction, Kind, &str)]) -> String {
+        let path = env::temp_dir().join(file_name);
+        let mut file = File::create(&path).unwrap();
+        for &(ts, direction, kind, name) in entries {
+            let entry = Entry {
+                ts: ts,
+                observed: None,
+                direction: direction,
+                kind: kind,
+                name: name.into(),
+                value: Some(Value::Bool(true)),
+                meta: None,
+            };
+            writeln!(file, "{}", json::encode(&entry).unwrap()).unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    fn names(path: &str, speed: f64, direction: Direction) -> Vec<String> {
+        let mut names = vec![];
+        play_from_start(path, speed, direction, |entry| {
+                names.push(entry.name);
+                true
+            })
+            .unwrap();
+        names
+    }
+
+    #[test]
+    fn play_filters_direction() {
+        let path = recording("catt-record-direction.json",
+                             &[(1000, Direction::ToBus, Kind::Meta, "a"),
+                               (1000, Direction::ToBus, Kind::Update, "a"),
+                               (1001, Direction::FromBus, Kind::Command, "b"),
+                               (1002, Direction::ToBus, Kind::Update, "c")]);
+        assert_eq!(names(&path, 0.0, Direction::ToBus), vec!["a", "a", "c"]);
+        assert_eq!(names(&path, 0.0, Direction::FromBus), vec!["b"]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn play_times_from_recording_start() {
+        // the only command comes 400ms into the recording
+        let path = recording("catt-record-timing.json",
+                             &[(1000, Direction::ToBus, Kind::Meta, "a"),
+                               (1400, Direction::FromBus, Kind::Command, "a"),
+                               (1600, Direction::ToBus, Kind::Update, "a")]);
+        assert_eq!(recording_start(&path).unwrap(), Some(1000));
+
+        let started = Instant::now();
+        let mut at = vec![];
+        play_from_start(&path, 2.0, Direction::FromBus, |_| {
+                at.push(started.elapsed());
+                true
+            })
+            .unwrap();
+        assert_eq!(at.len(), 1);
+        assert!(at[0] >= Duration::from_millis(200) && at[0] < Duration::from_millis(400));
+
+        // later entries are due relative to the same start
+        let started = Instant::now();
+        let mut at = vec![];
+        play(&path, 1000, 2.0, Direction::ToBus, |_| {
+                at.push(started.elapsed());
+                true
+            })
+            .unwrap();
+        assert!(at[0] < Duration::from_millis(100));
+        assert!(at[1] >= Duration::from_millis(300) && at[1] < Duration::from_millis(500));
+
+        // returning false stops the replay
+        let mut count = 0;
+        play(&path, 1000, 0.0, Direction::ToBus, |_| {
+                count += 1;
+                false
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn observed_time_keeps_its_lag() {
+        let observed = SystemTime::now() - Duration::from_secs(5);
+        let message = Message::Update("a".into(), Value::Bool(true), observed);
+        let mut entry = Entry::new(Direction::ToBus, message);
+        // recorded an hour ago
+        entry.ts -= 3600 * 1000;
+        entry.observed = entry.observed.map(|o| o - 3600 * 1000);
+
+        match entry.into_message().unwrap() {
+            Message::Update(_, _, ts) => {
+                let lag = SystemTime::now().duration_since(ts).unwrap();
+                assert!(lag >= Duration::from_secs(5) && lag < Duration::from_secs(10));
+            }
+            m => panic!("unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn old_recordings_have_no_observed_time() {
+        let line = r#"{"ts":1000,"direction":"ToBus","kind":"Update","name":"a",
+                       "value":{"variant":"Bool","fields":[true]},"meta":null}"#;
+        let entry: Entry = json::decode(line).unwrap();
+        assert!(entry.observed_at().is_none());
+    }
+}
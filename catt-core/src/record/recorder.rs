@@ -0,0 +1,147 @@
+use rustc_serialize::json;
+
+use tokio_core::reactor::Handle;
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+
+use futures::Future;
+use futures::stream::Stream;
+
+use bus::Bus;
+use bus::Message;
+use bus::SubType;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::LineWriter;
+use std::io::Write;
+use std::rc::Rc;
+
+use super::Direction;
+use super::Entry;
+use super::Error;
+use super::ErrorKind;
+use super::Result;
+
+pub const FILE_DEFAULT: &'static str = "recording.jsonl";
+
+#[derive(Default, RustcDecodable)]
+pub struct Config<B> {
+    pub file: Option<String>,
+    pub bus: Option<B>,
+}
+
+impl<B> Config<B> {
+    pub fn file(&self) -> &str {
+        self.file.as_ref().map(|s| s.as_str()).unwrap_or(FILE_DEFAULT)
+    }
+}
+
+// one json entry per line, flushed as it is written so that a recording
+// survives the bridge going down
+#[derive(Clone)]
+struct Log(Rc<RefCell<LineWriter<File>>>);
+
+impl Log {
+    fn open(file_name: &str) -> Result<Log> {
+        let file = OpenOptions::new().create(true).append(true).open(file_name)?;
+        Ok(Log(Rc::new(RefCell::new(LineWriter::new(file)))))
+    }
+
+    fn write(&self, direction: Direction, message: Message) -> Result<()> {
+        let line = json::encode(&Entry::new(direction, message))?;
+        let mut writer = self.0.borrow_mut();
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+// wraps a bus and appends every message going through it, in either
+// direction, to a file that `Replay` and `ReplayBus` can play back
+pub struct Recorder<B> {
+    bus: B,
+    log: Log,
+}
+
+fn bus_error<E: ::std::error::Error + Send + 'static>(e: E) -> Error {
+    ErrorKind::Bus(Box::new(e)).into()
+}
+
+impl<B> Bus for Recorder<B>
+    where B: Bus,
+          B::Config: Default
+{
+    type Config = Config<B::Config>;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        let (bus, messages) = match cfg.bus {
+                Some(ref c) => B::new(handle, c),
+                None => B::new(handle, &Default::default()),
+            }
+            .map_err(bus_error)?;
+        let log = Log::open(cfg.file())?;
+        info!("recording bus traffic to {}", cfg.file());
+
+        let (tx, rx) = channel(handle)?;
+        let incoming = log.clone();
+        let fut = messages.for_each(move |msg| {
+                if let Err(e) = incoming.write(Direction::FromBus, msg.clone()) {
+                    warn!("error recording message: {}", e);
+                }
+                tx.send(msg)
+            })
+            .map_err(|e| warn!("bus receive error: {}", e));
+        handle.spawn(fut);
+
+        let recorder = Recorder {
+            bus: bus,
+            log: log,
+        };
+
+        Ok((recorder, rx))
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        if let Err(e) = self.log.write(Direction::ToBus, message.clone()) {
+            warn!("error recording message: {}", e);
+        }
+        self.bus.publish(message).map_err(bus_error)
+    }
+
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        if let Err(e) = self.log.write(Direction::ToBus, message.clone()) {
+            warn!("error recording message: {}", e);
+        }
+        self.bus.publish_transient(message).map_err(bus_error)
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        self.bus.subscribe(item_name, sub_type).map_err(bus_error)
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        self.bus.unsubscribe(item_name, sub_type).map_err(bus_error)
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        self.bus.clear(item_name).map_err(bus_error)
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        self.bus.subscribe_pattern(pattern, sub_type).map_err(bus_error)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        self.bus.unsubscribe_pattern(pattern, sub_type).map_err(bus_error)
+    }
+
+    fn command_result(&self,
+                      item_name: &str,
+                      result: ::std::result::Result<(), String>)
+                      -> Result<()> {
+        self.bus.command_result(item_name, result).map_err(bus_error)
+    }
+}
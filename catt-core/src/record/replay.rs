@@ -0,0 +1,204 @@
+use tokio_core::reactor::Handle;
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+
+use binding::Binding;
+use binding::Notification;
+
+use bus::Bus;
+use bus::Message;
+use bus::SubType;
+
+use item::Item;
+use item::Meta;
+
+use value::Value;
+
+use util::always_lock;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+
+use super::play_from_start;
+use super::Direction;
+use super::Error;
+use super::ErrorKind;
+use super::Kind;
+use super::Result;
+use super::recorder::FILE_DEFAULT;
+
+pub const SPEED_DEFAULT: f64 = 1.0;
+
+#[derive(Default, RustcDecodable, Clone)]
+pub struct Config {
+    pub file: Option<String>,
+    // 2.0 plays back twice as fast as recorded, 0 as fast as possible
+    pub speed: Option<f64>,
+}
+
+impl Config {
+    pub fn file(&self) -> &str {
+        self.file.as_ref().map(|s| s.as_str()).unwrap_or(FILE_DEFAULT)
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed.unwrap_or(SPEED_DEFAULT)
+    }
+}
+
+#[derive(Clone)]
+pub struct ReplayItem {
+    name: String,
+    meta: Option<Meta>,
+    value: Arc<Mutex<Option<Value>>>,
+    observed: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Item for ReplayItem {
+    type Error = Error;
+
+    fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_meta(&self) -> Option<Meta> {
+        self.meta.clone()
+    }
+
+    fn get_timestamp(&self) -> Option<SystemTime> {
+        *always_lock(self.observed.lock())
+    }
+
+    fn get_value(&self) -> Result<Value> {
+        match *always_lock(self.value.lock()) {
+            Some(ref v) => Ok(v.clone()),
+            None => Err(ErrorKind::NoValue(self.name.clone()).into()),
+        }
+    }
+
+    // there's nothing to drive, so commands just become the new value
+    fn set_value(&self, value: Value) -> Result<()> {
+        info!("replay: setting {} to {:?}", self.name, value);
+        *always_lock(self.value.lock()) = Some(value);
+        *always_lock(self.observed.lock()) = None;
+        Ok(())
+    }
+}
+
+// a binding that plays back the updates and meta from a recording as if they
+// came from real devices
+pub struct Replay {
+    items: Arc<Mutex<BTreeMap<String, ReplayItem>>>,
+}
+
+impl Binding for Replay {
+    type Config = Config;
+    type Error = Error;
+    type Item = ReplayItem;
+
+    fn new(handle: &Handle,
+           cfg: &Self::Config)
+           -> Result<(Self, Receiver<Notification<Self::Item>>)> {
+        let (tx, rx) = channel(handle)?;
+        let items: Arc<Mutex<BTreeMap<String, ReplayItem>>> = Default::default();
+
+        let cfg = cfg.clone();
+        let thread_items = items.clone();
+        thread::spawn(move || {
+            let res = play_from_start(cfg.file(), cfg.speed(), Direction::ToBus, |entry| {
+                let mut items = always_lock(thread_items.lock());
+                let notification = match entry.kind {
+                    Kind::Meta => {
+                        let item = ReplayItem {
+                            name: entry.name.clone(),
+                            meta: entry.meta,
+                            value: Default::default(),
+                            observed: Default::default(),
+                        };
+                        items.insert(entry.name, item.clone());
+                        Notification::Added(item)
+                    }
+                    Kind::Update => {
+                        let observed = entry.observed_at();
+                        let name = entry.name;
+                        let item = items.entry(name.clone())
+                            .or_insert_with(|| {
+                                ReplayItem {
+                                    name: name,
+                                    meta: None,
+                                    value: Default::default(),
+                                    observed: Default::default(),
+                                }
+                            })
+                            .clone();
+                        *always_lock(item.observed.lock()) = observed;
+                        *always_lock(item.value.lock()) = entry.value;
+                        Notification::Changed(item)
+                    }
+                    Kind::Command => return true,
+                };
+                tx.send(notification).is_ok()
+            });
+
+            match res {
+                Ok(_) => info!("replay of {} finished", cfg.file()),
+                Err(e) => warn!("error replaying {}: {}", cfg.file(), e),
+            }
+        });
+
+        Ok((Replay { items: items }, rx))
+    }
+
+    fn get_value(&self, name: &str) -> Option<ReplayItem> {
+        always_lock(self.items.lock()).get(name).cloned()
+    }
+}
+
+// a bus that plays back the commands from a recording, meant to be combined
+// with a real bus through `Composite` and run alongside `Replay`
+pub struct ReplayBus;
+
+impl Bus for ReplayBus {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+
+        let cfg = cfg.clone();
+        thread::spawn(move || {
+            let res = play_from_start(cfg.file(), cfg.speed(), Direction::FromBus, |entry| {
+                match entry.into_message() {
+                    Ok(msg) => tx.send(msg).is_ok(),
+                    Err(e) => {
+                        warn!("skipping recorded message: {}", e);
+                        true
+                    }
+                }
+            });
+
+            match res {
+                Ok(_) => info!("replay of {} finished", cfg.file()),
+                Err(e) => warn!("error replaying {}: {}", cfg.file(), e),
+            }
+        });
+
+        Ok((ReplayBus, rx))
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("replay: {:?}", message);
+        Ok(())
+    }
+
+    fn subscribe(&self, _: &str, _: SubType) -> Result<()> {
+        Ok(())
+    }
+
+    fn unsubscribe(&self, _: &str, _: SubType) -> Result<()> {
+        Ok(())
+    }
+}
@@ -1,5 +1,13 @@
 use std::sync::{Mutex, Condvar, MutexGuard};
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Timelike;
+use chrono::UTC;
 
 pub fn always_lock<G>(res: ::std::sync::LockResult<G>) -> G {
     match res {
@@ -11,6 +19,70 @@ pub fn always_lock<G>(res: ::std::sync::LockResult<G>) -> G {
     }
 }
 
+// timestamps on the wire are RFC 3339
+pub fn format_ts(ts: SystemTime) -> String {
+    let since_epoch = ts.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    UTC.timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos()).to_rfc3339()
+}
+
+pub fn parse_ts(ts: &str) -> Option<SystemTime> {
+    let parsed = match DateTime::parse_from_rfc3339(ts) {
+        Ok(dt) => dt,
+        Err(e) => {
+            debug!("invalid timestamp {}: {}", ts, e);
+            return None;
+        }
+    };
+
+    if parsed.timestamp() < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(parsed.timestamp() as u64, parsed.nanosecond()))
+}
+
+// milliseconds since the epoch, for storage and protocols that count in them
+pub fn millis(ts: SystemTime) -> u64 {
+    let d = ts.duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0));
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1000000) as u64
+}
+
+// percent-encodes '%' and the reserved characters so that item names can be
+// used in topics, subjects and keys where those characters mean something.
+// reserved characters have to be ascii.
+pub fn escape_name(name: &str, reserved: &[char]) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c == '%' || reserved.contains(&c) {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// reverses escape_name, whatever characters were reserved
+pub fn unescape_name(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let code = chars.by_ref().take(2).collect::<String>();
+        match u8::from_str_radix(&code, 16) {
+            Ok(b) if code.len() == 2 && b < 0x80 => unescaped.push(b as char),
+            _ => {
+                debug!("invalid escape sequence in item name: {}", name);
+                return None;
+            }
+        }
+    }
+    Some(unescaped)
+}
+
 #[derive(Clone)]
 pub struct CVar(Arc<(Mutex<bool>, Condvar)>);
 
@@ -1,4 +1,5 @@
 use rustc_serialize::base64::{self, STANDARD, FromBase64, ToBase64};
+use rustc_serialize::json::Json;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use std::io;
@@ -108,6 +109,16 @@ impl Value {
     pub fn as_raw_value(&self) -> Result<Value> {
         Ok(Value::Raw(self.as_raw()?))
     }
+
+    pub fn to_json(&self) -> Json {
+        match self {
+            &Value::Number(n) => Json::F64(n),
+            &Value::String(ref s) => Json::String(s.clone()),
+            &Value::Bool(b) => Json::Boolean(b),
+            // raw values are sent base64 encoded
+            &Value::Raw(ref v) => Json::String(v.to_base64(STANDARD)),
+        }
+    }
 }
 
 fn un_stringify(val: Value) -> Value {
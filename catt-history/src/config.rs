@@ -0,0 +1,50 @@
+pub const HISTORY_PATH_DEFAULT: &'static str = "history.db";
+pub const HISTORY_RETENTION_DEFAULT: u64 = 30;
+pub const HISTORY_DOWNSAMPLE_AFTER_DEFAULT: u64 = 24;
+pub const HISTORY_DOWNSAMPLE_INTERVAL_DEFAULT: u64 = 300;
+pub const HISTORY_MAINTENANCE_DEFAULT: u64 = 3600;
+pub const HISTORY_QUERY_LIMIT_DEFAULT: u64 = 1000;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config<B> {
+    pub path: Option<String>,
+    pub retention_days: Option<u64>,
+    pub downsample_after_hours: Option<u64>,
+    pub downsample_interval: Option<u64>,
+    pub maintenance_interval: Option<u64>,
+    pub query_limit: Option<u64>,
+    // the bus the history sits in front of
+    pub bus: Option<B>,
+}
+
+impl<B> Config<B> {
+    pub fn path(&self) -> &str {
+        self.path.as_ref().map(|p| p.as_str()).unwrap_or(HISTORY_PATH_DEFAULT)
+    }
+
+    // values older than this are dropped, 0 keeps them forever
+    pub fn retention_days(&self) -> u64 {
+        self.retention_days.unwrap_or(HISTORY_RETENTION_DEFAULT)
+    }
+
+    // values older than this are thinned out to one per downsample interval,
+    // 0 turns downsampling off
+    pub fn downsample_after_hours(&self) -> u64 {
+        self.downsample_after_hours.unwrap_or(HISTORY_DOWNSAMPLE_AFTER_DEFAULT)
+    }
+
+    // seconds
+    pub fn downsample_interval(&self) -> u64 {
+        self.downsample_interval.unwrap_or(HISTORY_DOWNSAMPLE_INTERVAL_DEFAULT)
+    }
+
+    // seconds between retention and downsampling runs
+    pub fn maintenance_interval(&self) -> u64 {
+        self.maintenance_interval.unwrap_or(HISTORY_MAINTENANCE_DEFAULT)
+    }
+
+    // most values returned by a single query
+    pub fn query_limit(&self) -> u64 {
+        self.query_limit.unwrap_or(HISTORY_QUERY_LIMIT_DEFAULT)
+    }
+}
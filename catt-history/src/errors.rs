@@ -0,0 +1,23 @@
+use std::error::Error as SError;
+
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::rusqlite::Error, SqliteError;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+        ::rustc_serialize::json::DecoderError, JsonDecodeError;
+    }
+
+    errors {
+        Bus(e: Box<SError + Send + 'static>) {
+            description("bus error")
+            display("bus error: {}", e)
+        }
+    }
+}
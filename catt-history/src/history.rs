@@ -0,0 +1,379 @@
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Interval;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+
+use futures::Future;
+use futures::stream::Stream;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+
+use catt_core::value::Value;
+use catt_core::util;
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use config::Config;
+use store::Store;
+
+use errors::*;
+
+// commands to <item>/history are queries, answered with a transient update on
+// the same name
+pub const HISTORY_SUFFIX: &'static str = "/history";
+
+const MS_PER_HOUR: i64 = 60 * 60 * 1000;
+
+// the payload of a history query, all fields optional. times are
+// milliseconds since the epoch.
+#[derive(RustcDecodable,Debug,Default)]
+struct Query {
+    from: Option<i64>,
+    to: Option<i64>,
+    limit: Option<u64>,
+}
+
+fn bus_error<E: ::std::error::Error + Send + 'static>(e: E) -> Error {
+    ErrorKind::Bus(Box::new(e)).into()
+}
+
+// wraps a bus, storing every update published to it and answering history
+// queries that come in over it
+pub struct History<B> {
+    bus: Rc<B>,
+    store: Rc<Store>,
+}
+
+impl<B> History<B>
+    where B: Bus,
+          B::Config: Default
+{
+    pub fn with_config(handle: &Handle,
+                       cfg: &Config<B::Config>)
+                       -> Result<(Self, Receiver<Message>)> {
+        let (bus, messages) = match cfg.bus {
+                Some(ref c) => B::new(handle, c),
+                None => B::new(handle, &Default::default()),
+            }
+            .map_err(bus_error)?;
+        let bus = Rc::new(bus);
+        let store = Rc::new(Store::open(cfg.path())?);
+        info!("storing item history in {}", cfg.path());
+
+        let (tx, rx) = channel(handle)?;
+        let query_bus = bus.clone();
+        let query_store = store.clone();
+        let limit = cfg.query_limit();
+        let queries = messages.for_each(move |msg| {
+                if let Message::Command(ref name, ref value) = msg {
+                    if name.ends_with(HISTORY_SUFFIX) {
+                        let item = &name[..name.len() - HISTORY_SUFFIX.len()];
+                        let result = answer(&*query_bus, &query_store, item, value, limit)
+                            .map_err(|e| {
+                                warn!("error answering history query for {}: {}", item, e);
+                                format!("{}", e)
+                            });
+                        if let Err(e) = query_bus.command_result(name, result) {
+                            warn!("error reporting history query result for {}: {}", item, e);
+                        }
+                        return Ok(());
+                    }
+                }
+                tx.send(msg)
+            })
+            .map_err(|e| warn!("bus receive error: {}", e));
+        handle.spawn(queries);
+
+        let retention = cfg.retention_days() as i64 * 24 * MS_PER_HOUR;
+        let downsample_after = cfg.downsample_after_hours() as i64 * MS_PER_HOUR;
+        let bucket = cfg.downsample_interval() as i64 * 1000;
+        let maintenance_store = store.clone();
+        let maintenance = Interval::new(Duration::from_secs(cfg.maintenance_interval()), handle)?
+            .for_each(move |_| {
+                let now = util::millis(SystemTime::now()) as i64;
+                if retention > 0 {
+                    match maintenance_store.expire(now - retention) {
+                        Ok(n) => debug!("expired {} history values", n),
+                        Err(e) => warn!("error expiring history: {}", e),
+                    }
+                }
+                if downsample_after > 0 && bucket > 0 {
+                    match maintenance_store.downsample(now - downsample_after, bucket) {
+                        Ok(n) => debug!("downsampling dropped {} history values", n),
+                        Err(e) => warn!("error downsampling history: {}", e),
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| warn!("history maintenance timer error: {}", e));
+        handle.spawn(maintenance);
+
+        let history = History {
+            bus: bus,
+            store: store,
+        };
+
+        Ok((history, rx))
+    }
+}
+
+fn answer<B: Bus>(bus: &B, store: &Store, item: &str, value: &Value, limit: u64) -> Result<()> {
+    let text = value.as_string()?;
+    let query: Query = if text.trim().is_empty() {
+        Query::default()
+    } else {
+        json::decode(&text)?
+    };
+
+    let to = query.to.unwrap_or_else(|| util::millis(SystemTime::now()) as i64);
+    let limit = cmp::min(query.limit.unwrap_or(limit), limit) as i64;
+
+    // without a start the newest values are the interesting ones
+    let values = match query.from {
+            Some(from) => store.query(item, from, to, limit)?,
+            None => store.latest(item, to, limit)?,
+        }
+        .into_iter()
+        .map(|(ts, value)| {
+            let mut obj = BTreeMap::new();
+            obj.insert("ts".to_string(), Json::I64(ts));
+            obj.insert("value".to_string(), value.to_json());
+            Json::Object(obj)
+        })
+        .collect();
+
+    let mut reply = BTreeMap::new();
+    reply.insert("item".to_string(), Json::String(item.into()));
+    reply.insert("values".to_string(), Json::Array(values));
+
+    // the answer is for whoever asked, not the latest state of anything
+    bus.publish_transient(Message::Update(format!("{}{}", item, HISTORY_SUFFIX),
+                                          Value::String(Json::Object(reply).to_string()),
+                                          SystemTime::now()))
+        .map_err(bus_error)
+}
+
+// the pattern covering the history names of the items matched by a command
+// pattern. a trailing '*' already matches them, and subscribing twice would
+// have some buses deliver each query twice.
+fn history_pattern(pattern: &str, sub_type: SubType) -> Option<String> {
+    if (sub_type == SubType::Command || sub_type == SubType::All) && !pattern.ends_with('*') {
+        Some(format!("{}{}", pattern, HISTORY_SUFFIX))
+    } else {
+        None
+    }
+}
+
+impl<B> Bus for History<B>
+    where B: Bus,
+          B::Config: Default
+{
+    type Config = Config<B::Config>;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        History::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        if let Message::Update(ref name, ref value, ts) = message {
+            if let Err(e) = self.store.insert(name, util::millis(ts) as i64, value) {
+                warn!("error storing history for {}: {}", name, e);
+            }
+        }
+        self.bus.publish(message).map_err(bus_error)
+    }
+
+    // not part of any item's history
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        self.bus.publish_transient(message).map_err(bus_error)
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        self.bus.subscribe(item_name, sub_type).map_err(bus_error)?;
+        if sub_type == SubType::Command || sub_type == SubType::All {
+            let history = format!("{}{}", item_name, HISTORY_SUFFIX);
+            self.bus.subscribe(&history, SubType::Command).map_err(bus_error)?;
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        self.bus.unsubscribe(item_name, sub_type).map_err(bus_error)?;
+        if sub_type == SubType::Command || sub_type == SubType::All {
+            let history = format!("{}{}", item_name, HISTORY_SUFFIX);
+            self.bus.unsubscribe(&history, SubType::Command).map_err(bus_error)?;
+        }
+        Ok(())
+    }
+
+    // the history of an item outlives it, retention takes care of it
+    fn clear(&self, item_name: &str) -> Result<()> {
+        self.bus.clear(item_name).map_err(bus_error)
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        if !self.bus.subscribe_pattern(pattern, sub_type).map_err(bus_error)? {
+            return Ok(false);
+        }
+        match history_pattern(pattern, sub_type) {
+            Some(history) => {
+                self.bus.subscribe_pattern(&history, SubType::Command).map_err(bus_error)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        if !self.bus.unsubscribe_pattern(pattern, sub_type).map_err(bus_error)? {
+            return Ok(false);
+        }
+        match history_pattern(pattern, sub_type) {
+            Some(history) => {
+                self.bus.unsubscribe_pattern(&history, SubType::Command).map_err(bus_error)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn command_result(&self,
+                      item_name: &str,
+                      result: ::std::result::Result<(), String>)
+                      -> Result<()> {
+        self.bus.command_result(item_name, result).map_err(bus_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::History;
+    use super::answer;
+
+    use rustc_serialize::json::Json;
+
+    use tokio_core::reactor::Handle;
+    use tokio_core::channel::channel;
+    use tokio_core::channel::Receiver;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::value::Value;
+
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use store::Store;
+
+    // records what goes through it, accepting any pattern
+    #[derive(Default)]
+    struct Stub {
+        sent: RefCell<Vec<Message>>,
+        patterns: RefCell<Vec<(String, SubType)>>,
+    }
+
+    impl Bus for Stub {
+        type Config = ();
+        type Error = io::Error;
+
+        fn new(handle: &Handle, _: &()) -> io::Result<(Self, Receiver<Message>)> {
+            let (_, rx) = channel(handle)?;
+            Ok((Stub::default(), rx))
+        }
+
+        fn publish(&self, message: Message) -> io::Result<()> {
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn subscribe(&self, _: &str, _: SubType) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn unsubscribe(&self, _: &str, _: SubType) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> io::Result<bool> {
+            self.patterns.borrow_mut().push((pattern.into(), sub_type));
+            Ok(true)
+        }
+    }
+
+    fn store() -> Store {
+        let store = Store::open(":memory:").unwrap();
+        for ts in 0..10 {
+            store.insert("a", ts * 100, &Value::Number(ts as f64)).unwrap();
+        }
+        store
+    }
+
+    // the timestamps in the reply to a query
+    fn ask(query: &str, limit: u64) -> Vec<i64> {
+        let bus = Stub::default();
+        answer(&bus, &store(), "a", &Value::String(query.into()), limit).unwrap();
+
+        let sent = bus.sent.borrow();
+        assert_eq!(sent.len(), 1);
+        let reply = match sent[0] {
+            Message::Update(ref name, Value::String(ref reply), _) => {
+                assert_eq!(name, "a/history");
+                Json::from_str(reply).unwrap()
+            }
+            ref m => panic!("unexpected reply {:?}", m),
+        };
+        assert_eq!(reply.find("item").and_then(|i| i.as_string()), Some("a"));
+        reply.find("values")
+            .and_then(|v| v.as_array())
+            .unwrap()
+            .iter()
+            .map(|v| v.find("ts").and_then(|ts| ts.as_i64()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn answer_query() {
+        // a bare query gets the newest values
+        assert_eq!(ask("", 3), vec![700, 800, 900]);
+        assert_eq!(ask(r#"{"limit":2}"#, 3), vec![800, 900]);
+        // the configured limit wins over the query's
+        assert_eq!(ask(r#"{"limit":20}"#, 3), vec![700, 800, 900]);
+        assert_eq!(ask(r#"{"to":450,"limit":2}"#, 10), vec![300, 400]);
+        assert_eq!(ask(r#"{"from":200,"to":400}"#, 10), vec![200, 300, 400]);
+        assert_eq!(ask(r#"{"from":200}"#, 2), vec![200, 300]);
+    }
+
+    #[test]
+    fn answer_invalid_query() {
+        let bus = Stub::default();
+        assert!(answer(&bus, &store(), "a", &Value::String("{".into()), 10).is_err());
+        assert!(bus.sent.borrow().is_empty());
+    }
+
+    #[test]
+    fn pattern_covers_history() {
+        let history = History {
+            bus: Rc::new(Stub::default()),
+            store: Rc::new(store()),
+        };
+        assert!(history.subscribe_pattern("kitchen/light", SubType::Command).unwrap());
+        assert!(history.subscribe_pattern("kitchen/*", SubType::All).unwrap());
+        assert!(history.subscribe_pattern("hall", SubType::Update).unwrap());
+        assert_eq!(*history.bus.patterns.borrow(),
+                   vec![("kitchen/light".to_string(), SubType::Command),
+                        ("kitchen/light/history".to_string(), SubType::Command),
+                        ("kitchen/*".to_string(), SubType::All),
+                        ("hall".to_string(), SubType::Update)]);
+    }
+}
@@ -0,0 +1,21 @@
+#![feature(question_mark)]
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate log;
+
+extern crate rusqlite;
+
+extern crate rustc_serialize;
+
+extern crate catt_core;
+
+extern crate futures;
+extern crate tokio_core;
+
+pub mod errors;
+pub mod config;
+pub mod store;
+pub mod history;
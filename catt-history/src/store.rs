@@ -0,0 +1,154 @@
+use rusqlite::Connection;
+
+use rustc_serialize::json;
+
+use catt_core::value::Value;
+
+use errors::*;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        ts INTEGER NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_name_ts ON history (name, ts);
+";
+
+// item values keyed by name and milliseconds since the epoch. values are
+// stored as their json encoding so that they come back with the same type.
+pub struct Store {
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Store { conn: conn })
+    }
+
+    pub fn insert(&self, name: &str, ts: i64, value: &Value) -> Result<()> {
+        let value = json::encode(value)?;
+        self.conn
+            .execute("INSERT INTO history (name, ts, value) VALUES (?, ?, ?)",
+                     &[&name, &ts, &value])?;
+        Ok(())
+    }
+
+    // oldest first
+    pub fn query(&self, name: &str, from: i64, to: i64, limit: i64) -> Result<Vec<(i64, Value)>> {
+        let mut stmt = self.conn
+            .prepare("SELECT ts, value FROM history WHERE name = ? AND ts >= ? AND ts <= ? \
+                      ORDER BY ts LIMIT ?")?;
+        let rows = stmt.query_map(&[&name, &from, &to, &limit], |row| {
+                let ts: i64 = row.get(0);
+                let value: String = row.get(1);
+                (ts, value)
+            })?;
+
+        let mut values = vec![];
+        for row in rows {
+            let (ts, value) = row?;
+            values.push((ts, json::decode(&value)?));
+        }
+        Ok(values)
+    }
+
+    // the newest values up to the given time, still oldest first
+    pub fn latest(&self, name: &str, to: i64, limit: i64) -> Result<Vec<(i64, Value)>> {
+        let mut stmt = self.conn
+            .prepare("SELECT ts, value FROM history WHERE name = ? AND ts <= ? \
+                      ORDER BY ts DESC LIMIT ?")?;
+        let rows = stmt.query_map(&[&name, &to, &limit], |row| {
+                let ts: i64 = row.get(0);
+                let value: String = row.get(1);
+                (ts, value)
+            })?;
+
+        let mut values = vec![];
+        for row in rows {
+            let (ts, value) = row?;
+            values.push((ts, json::decode(&value)?));
+        }
+        values.reverse();
+        Ok(values)
+    }
+
+    // drops everything older than the cutoff
+    pub fn expire(&self, before: i64) -> Result<i32> {
+        Ok(self.conn.execute("DELETE FROM history WHERE ts < ?", &[&before])?)
+    }
+
+    // keeps only the latest value of each item in every bucket of the given
+    // length for everything older than the cutoff
+    pub fn downsample(&self, before: i64, bucket: i64) -> Result<i32> {
+        Ok(self.conn
+            .execute("DELETE FROM history WHERE ts < ?1 AND id NOT IN (SELECT MAX(id) FROM \
+                      history WHERE ts < ?1 GROUP BY name, ts / ?2)",
+                     &[&before, &bucket])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Store;
+
+    use catt_core::value::Value;
+
+    fn store() -> Store {
+        let store = Store::open(":memory:").unwrap();
+        for ts in 0..10 {
+            store.insert("a", ts * 100, &Value::Number(ts as f64)).unwrap();
+            store.insert("b", ts * 100, &Value::Bool(ts % 2 == 0)).unwrap();
+        }
+        store
+    }
+
+    fn timestamps(store: &Store, name: &str) -> Vec<i64> {
+        store.query(name, 0, 10000, 100).unwrap().into_iter().map(|(ts, _)| ts).collect()
+    }
+
+    #[test]
+    fn query_range() {
+        let store = store();
+        let values = store.query("a", 200, 400, 100).unwrap();
+        assert_eq!(values,
+                   vec![(200, Value::Number(2.0)),
+                        (300, Value::Number(3.0)),
+                        (400, Value::Number(4.0))]);
+        assert_eq!(store.query("a", 0, 10000, 2).unwrap().len(), 2);
+        assert!(store.query("c", 0, 10000, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn latest() {
+        let store = store();
+        assert_eq!(store.latest("a", 10000, 2).unwrap(),
+                   vec![(800, Value::Number(8.0)), (900, Value::Number(9.0))]);
+        assert_eq!(store.latest("a", 350, 2).unwrap(),
+                   vec![(200, Value::Number(2.0)), (300, Value::Number(3.0))]);
+        assert!(store.latest("c", 10000, 100).unwrap().is_empty());
+    }
+
+    #[test]
+    fn expire() {
+        let store = store();
+        assert_eq!(store.expire(500).unwrap(), 10);
+        assert_eq!(timestamps(&store, "a"), vec![500, 600, 700, 800, 900]);
+        assert_eq!(timestamps(&store, "b"), vec![500, 600, 700, 800, 900]);
+    }
+
+    #[test]
+    fn downsample() {
+        let store = store();
+        // buckets of 300ms below 600 are [0, 300) and [300, 600)
+        assert_eq!(store.downsample(600, 300).unwrap(), 8);
+        assert_eq!(timestamps(&store, "a"), vec![200, 500, 600, 700, 800, 900]);
+        assert_eq!(timestamps(&store, "b"), vec![200, 500, 600, 700, 800, 900]);
+
+        // the latest value of each bucket is the one kept
+        assert_eq!(store.query("a", 200, 200, 1).unwrap(), vec![(200, Value::Number(2.0))]);
+    }
+}
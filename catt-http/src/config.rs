@@ -0,0 +1,27 @@
+pub const HTTP_LISTEN_DEFAULT: &'static str = "127.0.0.1:8080";
+pub const HTTP_THREADS_DEFAULT: usize = 4;
+pub const HTTP_MAX_EVENT_STREAMS_DEFAULT: usize = 16;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub listen: Option<String>,
+    pub threads: Option<usize>,
+    pub max_event_streams: Option<usize>,
+}
+
+impl Config {
+    pub fn listen(&self) -> &str {
+        self.listen.as_ref().map(|l| l.as_str()).unwrap_or(HTTP_LISTEN_DEFAULT)
+    }
+
+    // number of api requests that can be handled at once
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(HTTP_THREADS_DEFAULT)
+    }
+
+    // every open /events stream has a thread of its own, further clients are
+    // turned away until one closes
+    pub fn max_event_streams(&self) -> usize {
+        self.max_event_streams.unwrap_or(HTTP_MAX_EVENT_STREAMS_DEFAULT)
+    }
+}
@@ -0,0 +1,27 @@
+use hyper;
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::std::string::FromUtf8Error, Utf8Error;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+    }
+
+    errors {
+        Http(e: hyper::Error) {
+            description("http error")
+            display("http error: {}", e)
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(other: hyper::Error) -> Self {
+        ErrorKind::Http(other).into()
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::TrySendError;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use url::percent_encoding::percent_decode;
+
+use rustc_serialize::json::Json;
+
+use catt_core::bus;
+use catt_core::value::Value;
+use catt_core::util;
+use catt_core::util::always_lock;
+
+// comment lines sent on idle event streams so that closed connections are
+// noticed. also the longest a write to a stream may take.
+pub const KEEP_ALIVE_SECS: u64 = 15;
+
+// updates buffered for a stream. a client that falls further behind is
+// dropped rather than letting the buffer grow.
+const QUEUE_SIZE: usize = 256;
+
+struct Listener {
+    id: usize,
+    // no patterns means every item
+    patterns: Vec<String>,
+    tx: mpsc::SyncSender<String>,
+}
+
+// fans item updates out to the open /events streams
+#[derive(Clone,Default)]
+pub struct Events {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+// an open stream's updates, it stops receiving them once dropped
+pub struct Stream {
+    id: usize,
+    events: Events,
+    rx: mpsc::Receiver<String>,
+}
+
+impl Stream {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout)
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        always_lock(self.events.listeners.lock()).retain(|l| l.id != self.id);
+    }
+}
+
+impl Events {
+    // returns None if there are already max streams open
+    pub fn listen(&self, patterns: Vec<String>, max: usize) -> Option<Stream> {
+        let mut listeners = always_lock(self.listeners.lock());
+        if listeners.len() >= max {
+            return None;
+        }
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        listeners.push(Listener {
+            id: id,
+            patterns: patterns,
+            tx: tx,
+        });
+
+        Some(Stream {
+            id: id,
+            events: self.clone(),
+            rx: rx,
+        })
+    }
+
+    // {"name":..., "value":..., "type":..., "ts":...}
+    pub fn update(&self, name: &str, value: &Value, ts: SystemTime) {
+        let mut listeners = always_lock(self.listeners.lock());
+        if listeners.is_empty() {
+            return;
+        }
+
+        let mut obj = BTreeMap::new();
+        obj.insert("name".to_string(), Json::String(name.into()));
+        obj.insert("value".to_string(), value.to_json());
+        obj.insert("type".to_string(), Json::String(value.type_string().into()));
+        obj.insert("ts".to_string(), Json::String(util::format_ts(ts)));
+        let data = Json::Object(obj).to_string();
+
+        // streams that have gone away or can't keep up are dropped here
+        listeners.retain(|l| {
+            if !l.patterns.is_empty() && !l.patterns.iter().any(|p| bus::pattern_matches(p, name)) {
+                return true;
+            }
+            match l.tx.try_send(data.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("event stream {} is lagging behind, closing it", l.id);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+// the items parameter is a comma separated list of item name patterns
+pub fn query_patterns(query: &str) -> Vec<String> {
+    query.split('&')
+        .filter(|pair| pair.starts_with("items="))
+        .filter_map(|pair| percent_decode(pair["items=".len()..].as_bytes()).decode_utf8().ok())
+        .flat_map(|items| {
+            items.split(',')
+                .filter(|i| !i.is_empty())
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// writes updates to an event stream as they come in, with a comment line
+// whenever it has been idle for a while. returns once the stream has been
+// dropped for lagging behind or the client has gone away.
+pub fn stream<W: Write>(updates: &Stream, out: &mut W) -> io::Result<()> {
+    loop {
+        match updates.recv_timeout(Duration::from_secs(KEEP_ALIVE_SECS)) {
+            Ok(data) => write!(out, "event: update\ndata: {}\n\n", data)?,
+            Err(RecvTimeoutError::Timeout) => write!(out, ": keepalive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        out.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Events;
+    use super::QUEUE_SIZE;
+    use super::query_patterns;
+    use super::stream;
+
+    use catt_core::util::always_lock;
+    use catt_core::value::Value;
+
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    #[test]
+    fn patterns() {
+        assert_eq!(query_patterns("items=a,b%2Fc&x=1&items=d"),
+                   vec!["a".to_string(), "b/c".to_string(), "d".to_string()]);
+        assert!(query_patterns("").is_empty());
+    }
+
+    #[test]
+    fn stream_limit() {
+        let events = Events::default();
+        let first = events.listen(vec![], 2).unwrap();
+        let _second = events.listen(vec![], 2).unwrap();
+        assert!(events.listen(vec![], 2).is_none());
+
+        drop(first);
+        assert!(events.listen(vec![], 2).is_some());
+    }
+
+    #[test]
+    fn lagging_stream_is_dropped() {
+        let events = Events::default();
+        let lagging = events.listen(vec![], 2).unwrap();
+        for _ in 0..QUEUE_SIZE + 1 {
+            events.update("a", &Value::Bool(true), SystemTime::now());
+        }
+
+        // what was buffered is still delivered, then the stream ends
+        for _ in 0..QUEUE_SIZE {
+            assert!(lagging.recv_timeout(Duration::from_millis(10)).is_ok());
+        }
+        assert!(lagging.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn stream_writes_matching_updates() {
+        let events = Events::default();
+        let updates = events.listen(vec!["a*".into()], 1).unwrap();
+        events.update("b", &Value::Bool(true), SystemTime::now());
+        events.update("ab", &Value::Number(1.5), SystemTime::now());
+        // ends the stream as if it had been dropped for lagging
+        always_lock(events.listeners.lock()).clear();
+
+        let mut out = vec![];
+        stream(&updates, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("event: update\ndata: {"));
+        assert!(out.ends_with("}\n\n"));
+        assert!(out.contains(r#""name":"ab""#));
+        assert!(out.contains(r#""value":1.5"#));
+        assert_eq!(out.matches("event: update").count(), 1);
+    }
+}
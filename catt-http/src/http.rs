@@ -0,0 +1,460 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hyper::header::ContentType;
+use hyper::method::Method;
+use hyper::server::Handler;
+use hyper::server::Listening;
+use hyper::server::Request;
+use hyper::server::Response;
+use hyper::server::Server;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+
+use url::percent_encoding::percent_decode;
+
+use rustc_serialize::json::Json;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::value::Value;
+use catt_core::util::always_lock;
+
+use config::Config;
+use events;
+use events::Events;
+use store::Store;
+
+use errors::*;
+
+#[derive(Debug,PartialEq)]
+enum Route {
+    // GET /items
+    List,
+    // GET /items/{name}
+    State(String),
+    // PUT /items/{name}/command
+    Command(String),
+    // GET /items/{name}/meta
+    Meta(String),
+    // GET /events?items=<pattern>,<pattern>
+    Events(String),
+    NotFound,
+}
+
+// item names are percent-decoded and may contain '/'. the /command and /meta
+// suffixes only count for the method that goes with them, so the state of an
+// item whose name ends in either can still be read. its meta is reached by
+// escaping the '/' as %2F.
+fn route(method: &Method, uri: &str) -> Route {
+    let mut parts = uri.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next().unwrap_or("");
+
+    if path == "/items" || path == "/items/" {
+        return Route::List;
+    }
+    if path == "/events" {
+        return Route::Events(query.into());
+    }
+    if !path.starts_with("/items/") {
+        return Route::NotFound;
+    }
+
+    let rest = &path["/items/".len()..];
+    let (name, make): (&str, fn(String) -> Route) = match *method {
+        Method::Put if rest.ends_with("/command") => {
+            (&rest[..rest.len() - "/command".len()], Route::Command)
+        }
+        Method::Get if rest.ends_with("/meta") => {
+            (&rest[..rest.len() - "/meta".len()], Route::Meta)
+        }
+        _ => (rest, Route::State),
+    };
+
+    match percent_decode(name.as_bytes()).decode_utf8() {
+        Ok(ref n) if !n.is_empty() => make(n.to_string()),
+        _ => Route::NotFound,
+    }
+}
+
+fn error_json(msg: &str) -> Option<Json> {
+    let mut obj = BTreeMap::new();
+    obj.insert("error".to_string(), Json::String(msg.into()));
+    Some(Json::Object(obj))
+}
+
+// the api is meant to be usable from simple web pages served from elsewhere
+fn allow_any_origin(res: &mut Response) {
+    res.headers_mut().set_raw("Access-Control-Allow-Origin", vec![b"*".to_vec()]);
+}
+
+fn respond(mut res: Response, status: StatusCode, body: Option<Json>) {
+    *res.status_mut() = status;
+    allow_any_origin(&mut res);
+    let body = match body {
+        Some(j) => {
+            res.headers_mut().set(ContentType::json());
+            j.to_string()
+        }
+        None => String::new(),
+    };
+
+    if let Err(e) = res.send(body.as_bytes()) {
+        warn!("error sending http response: {}", e);
+    }
+}
+
+struct Api {
+    store: Store,
+    events: Events,
+    max_event_streams: usize,
+    filter: Subscriptions,
+    tx: Mutex<Sender<Message>>,
+}
+
+impl Api {
+    // streams item updates as server-sent events until the client goes away.
+    // every open stream holds one of the server's threads.
+    fn stream_events(&self, query: &str, mut res: Response) {
+        let patterns = events::query_patterns(query);
+        let updates = match self.events.listen(patterns, self.max_event_streams) {
+            Some(u) => u,
+            None => {
+                warn!("{} event streams already open, refusing another",
+                      self.max_event_streams);
+                return respond(res,
+                               StatusCode::ServiceUnavailable,
+                               error_json("too many event streams"));
+            }
+        };
+
+        allow_any_origin(&mut res);
+        res.headers_mut().set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+        res.headers_mut().set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+        res.headers_mut().set_raw("Connection", vec![b"close".to_vec()]);
+
+        let mut res = match res.start() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("error starting event stream: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = events::stream(&updates, &mut res) {
+            debug!("event stream closed: {}", e);
+        }
+        let _ = res.end();
+    }
+
+    // the request body is the new value, the same as a plain mqtt payload
+    fn command(&self, name: String, req: &mut Request) -> (StatusCode, Option<Json>) {
+        if !self.store.contains(&name) || !self.filter.accepts(&name, SubType::Command) {
+            return (StatusCode::NotFound, error_json("unknown item"));
+        }
+
+        let mut body = vec![];
+        if let Err(e) = req.read_to_end(&mut body) {
+            return (StatusCode::BadRequest, error_json(&format!("{}", e)));
+        }
+
+        let message = Message::Command(name, Value::from_raw(&body));
+        match always_lock(self.tx.lock()).send(message) {
+            Ok(_) => (StatusCode::Accepted, None),
+            Err(e) => {
+                warn!("channel send error: {}", e);
+                (StatusCode::ServiceUnavailable, error_json("bridge not running"))
+            }
+        }
+    }
+}
+
+impl Handler for Api {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, res: Response<'a>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref p) => p.clone(),
+            _ => return respond(res, StatusCode::BadRequest, None),
+        };
+        debug!("{} {}", req.method, path);
+
+        let (status, body) = match (req.method.clone(), route(&req.method, &path)) {
+            (_, Route::NotFound) => (StatusCode::NotFound, None),
+            // read-only, commands always go through PUT
+            (Method::Get, Route::Events(query)) => return self.stream_events(&query, res),
+            (Method::Get, Route::List) => (StatusCode::Ok, Some(self.store.list_json())),
+            (Method::Get, Route::State(name)) => {
+                match self.store.state_json(&name) {
+                    Some(j) => (StatusCode::Ok, Some(j)),
+                    None => (StatusCode::NotFound, error_json("unknown item")),
+                }
+            }
+            (Method::Get, Route::Meta(name)) => {
+                match self.store.meta_json(&name) {
+                    Some(j) => (StatusCode::Ok, Some(j)),
+                    None => (StatusCode::NotFound, error_json("unknown item")),
+                }
+            }
+            (Method::Put, Route::Command(name)) => self.command(name, &mut req),
+            _ => (StatusCode::MethodNotAllowed, None),
+        };
+
+        respond(res, status, body)
+    }
+}
+
+// serves the items published by the bridge over a small json api and turns
+// PUT requests into commands
+pub struct Http {
+    #[allow(dead_code)]
+    listening: Listening,
+    store: Store,
+    events: Events,
+    filter: Subscriptions,
+}
+
+impl Http {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let store = Store::default();
+        let events = Events::default();
+        let filter = Subscriptions::default();
+
+        let api = Api {
+            store: store.clone(),
+            events: events.clone(),
+            max_event_streams: cfg.max_event_streams(),
+            filter: filter.clone(),
+            tx: Mutex::new(tx),
+        };
+        // event streams hold on to their thread, so they get threads of their
+        // own on top of the ones for requests. writes to a client that stops
+        // reading time out and close its stream.
+        let mut server = Server::http(cfg.listen())?;
+        server.set_write_timeout(Some(Duration::from_secs(events::KEEP_ALIVE_SECS)));
+        let listening = server.handle_threads(api, cfg.threads() + cfg.max_event_streams())?;
+        info!("http api listening on {}", listening.socket);
+
+        let http = Http {
+            listening: listening,
+            store: store,
+            events: events,
+            filter: filter,
+        };
+
+        Ok((http, rx))
+    }
+}
+
+impl Bus for Http {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Http::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        match message {
+            Message::Update(name, value, ts) => {
+                self.events.update(&name, &value, ts);
+                self.store.update(&name, value, ts);
+            }
+            Message::Meta(name, meta) => self.store.set_meta(&name, meta),
+            // commands only ever come in through the api
+            Message::Command(name, _) => debug!("ignoring command for {}", name),
+        }
+        Ok(())
+    }
+
+    // only streamed, the store answers requests for the item itself
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        match message {
+            Message::Update(name, value, ts) => self.events.update(&name, &value, ts),
+            message => return self.publish(message),
+        }
+        Ok(())
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        self.store.remove(item_name);
+        Ok(())
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Http;
+    use super::Route;
+    use super::route;
+
+    use config::Config;
+
+    use hyper::method::Method;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::item::Meta;
+    use catt_core::value::Value;
+
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::net::TcpStream;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    #[test]
+    fn routes() {
+        let get = Method::Get;
+        let put = Method::Put;
+        assert_eq!(route(&get, "/items"), Route::List);
+        assert_eq!(route(&get, "/items/"), Route::List);
+        assert_eq!(route(&get, "/items/lamp"), Route::State("lamp".into()));
+        assert_eq!(route(&get, "/items/hall/lamp?x=1"), Route::State("hall/lamp".into()));
+        assert_eq!(route(&get, "/items/hall%2Flamp"), Route::State("hall/lamp".into()));
+        assert_eq!(route(&get, "/items/lamp/meta"), Route::Meta("lamp".into()));
+        assert_eq!(route(&put, "/items/lamp/command"), Route::Command("lamp".into()));
+        assert_eq!(route(&put, "/items/hall/lamp/command"),
+                   Route::Command("hall/lamp".into()));
+
+        // the suffixes only mean something for their own method
+        assert_eq!(route(&get, "/items/lamp/command"), Route::State("lamp/command".into()));
+        assert_eq!(route(&put, "/items/lamp/meta"), Route::State("lamp/meta".into()));
+        assert_eq!(route(&get, "/items/lamp%2Fmeta/meta"), Route::Meta("lamp/meta".into()));
+
+        assert_eq!(route(&get, "/other"), Route::NotFound);
+        assert_eq!(route(&get, "/items/%FF"), Route::NotFound);
+        assert_eq!(route(&put, "/items//command"), Route::NotFound);
+    }
+
+    // a single request on a connection of its own, returning the status and
+    // the body of the response
+    fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream,
+               "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\
+                Connection: close\r\n\r\n{}",
+               method,
+               path,
+               body.len(),
+               body)
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body = match response.find("\r\n\r\n") {
+            Some(i) => response[i + 4..].to_string(),
+            None => String::new(),
+        };
+        (status, body)
+    }
+
+    #[test]
+    fn api() {
+        let mut core = Core::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.listen = Some("127.0.0.1:0".into());
+        let (http, rx) = Http::with_config(&core.handle(), &cfg).unwrap();
+        let addr = http.listening.socket;
+
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        http.publish(Message::Meta("lamp".into(), Meta::default())).unwrap();
+        http.publish(Message::Update("lamp".into(), Value::Bool(true), SystemTime::now()))
+            .unwrap();
+        http.publish(Message::Update("a/command".into(), Value::Number(1.5), SystemTime::now()))
+            .unwrap();
+
+        let (status, body) = request(addr, "GET", "/items", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""name":"lamp""#) && body.contains(r#""name":"a/command""#));
+
+        let (status, body) = request(addr, "GET", "/items/lamp", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""value":true"#));
+        assert!(body.contains(r#""type":"bool""#));
+
+        let (status, body) = request(addr, "GET", "/items/a/command", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""value":1.5"#));
+
+        let (status, body) = request(addr, "GET", "/items/lamp/meta", "");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""backend":null"#));
+
+        assert_eq!(request(addr, "GET", "/items/missing", "").0, 404);
+        assert_eq!(request(addr, "POST", "/items/lamp", "").0, 405);
+
+        // commands are only accepted for subscribed items
+        assert_eq!(request(addr, "PUT", "/items/lamp/command", "OFF").0, 404);
+        http.subscribe("lamp", SubType::Command).unwrap();
+        assert_eq!(request(addr, "PUT", "/items/lamp/command", "OFF").0, 202);
+
+        let mut received = None;
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                received = Some(m);
+                break;
+            }
+        }
+        match received {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "lamp");
+                assert_eq!(value, Value::Bool(false));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+#![feature(question_mark)]
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate log;
+
+extern crate hyper;
+extern crate url;
+
+extern crate rustc_serialize;
+
+extern crate catt_core;
+
+extern crate tokio_core;
+
+#[cfg(test)]
+extern crate futures;
+
+pub mod errors;
+pub mod config;
+pub mod store;
+pub mod events;
+pub mod http;
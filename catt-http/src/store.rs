@@ -0,0 +1,85 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use rustc_serialize::json::Json;
+
+use catt_core::item::Meta;
+use catt_core::value::Value;
+use catt_core::util;
+use catt_core::util::always_lock;
+
+#[derive(Default)]
+struct ItemState {
+    value: Option<Value>,
+    ts: Option<SystemTime>,
+    meta: Option<Meta>,
+}
+
+// the latest state and meta of every item published to the bus, so that
+// requests can be answered without asking the binding
+#[derive(Clone,Default)]
+pub struct Store {
+    items: Arc<Mutex<BTreeMap<String, ItemState>>>,
+}
+
+impl Store {
+    pub fn update(&self, name: &str, value: Value, ts: SystemTime) {
+        let mut items = always_lock(self.items.lock());
+        let item = items.entry(name.into()).or_insert_with(Default::default);
+        item.value = Some(value);
+        item.ts = Some(ts);
+    }
+
+    pub fn set_meta(&self, name: &str, meta: Meta) {
+        let mut items = always_lock(self.items.lock());
+        items.entry(name.into()).or_insert_with(Default::default).meta = Some(meta);
+    }
+
+    pub fn remove(&self, name: &str) {
+        always_lock(self.items.lock()).remove(name);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        always_lock(self.items.lock()).contains_key(name)
+    }
+
+    // [{"name":..., "value":..., "type":..., "ts":..., "meta":{...}}, ...]
+    pub fn list_json(&self) -> Json {
+        let items = always_lock(self.items.lock());
+        Json::Array(items.iter()
+            .map(|(name, item)| {
+                let mut obj = state_object(name, item);
+                obj.insert("meta".to_string(),
+                           item.meta.as_ref().map(Meta::to_json).unwrap_or(Json::Null));
+                Json::Object(obj)
+            })
+            .collect())
+    }
+
+    pub fn state_json(&self, name: &str) -> Option<Json> {
+        let items = always_lock(self.items.lock());
+        items.get(name).map(|item| Json::Object(state_object(name, item)))
+    }
+
+    pub fn meta_json(&self, name: &str) -> Option<Json> {
+        let items = always_lock(self.items.lock());
+        items.get(name).map(|item| item.meta.as_ref().map(Meta::to_json).unwrap_or(Json::Null))
+    }
+}
+
+fn state_object(name: &str, item: &ItemState) -> BTreeMap<String, Json> {
+    let mut obj = BTreeMap::new();
+    obj.insert("name".to_string(), Json::String(name.into()));
+    obj.insert("value".to_string(),
+               item.value.as_ref().map(Value::to_json).unwrap_or(Json::Null));
+    obj.insert("type".to_string(),
+               item.value
+                   .as_ref()
+                   .map(|v| Json::String(v.type_string().into()))
+                   .unwrap_or(Json::Null));
+    obj.insert("ts".to_string(),
+               item.ts.map(|ts| Json::String(util::format_ts(ts))).unwrap_or(Json::Null));
+    obj
+}
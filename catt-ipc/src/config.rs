@@ -0,0 +1,29 @@
+use errors::*;
+
+pub const IPC_PATH_DEFAULT: &'static str = "/run/catt/catt.sock";
+pub const IPC_MODE_DEFAULT: u32 = 0o660;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub path: Option<String>,
+    // octal permission bits for the socket file, e.g. "0660"
+    pub mode: Option<String>,
+}
+
+impl Config {
+    pub fn path(&self) -> &str {
+        self.path.as_ref().map(|p| p.as_str()).unwrap_or(IPC_PATH_DEFAULT)
+    }
+
+    pub fn mode(&self) -> Result<u32> {
+        let mode = match self.mode {
+            Some(ref m) => m,
+            None => return Ok(IPC_MODE_DEFAULT),
+        };
+
+        match u32::from_str_radix(mode, 8) {
+            Ok(m) if m <= 0o777 => Ok(m),
+            _ => Err(ErrorKind::InvalidConfig(format!("invalid socket mode: {}", mode)).into()),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::fs;
+use std::fs::DirBuilder;
+use std::fs::Permissions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::clients::Registry;
+use catt_core::clients::protocol;
+
+use config::Config;
+
+use errors::*;
+
+// access control is left to the filesystem: anyone who can open the socket
+// can subscribe to items and send commands
+pub struct Ipc {
+    clients: Registry<String>,
+    filter: Subscriptions,
+}
+
+impl Ipc {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let clients = Registry::default();
+        let filter = Subscriptions::default();
+
+        let listener = bind(cfg)?;
+        info!("listening on {}", cfg.path());
+
+        let server_clients = clients.clone();
+        let server_filter = filter.clone();
+        let tx = Arc::new(Mutex::new(tx));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("ipc accept error: {}", e);
+                        continue;
+                    }
+                };
+
+                let clients = server_clients.clone();
+                let filter = server_filter.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &clients, &filter, &tx) {
+                        debug!("ipc connection error: {}", e);
+                    }
+                });
+            }
+        });
+
+        let ipc = Ipc {
+            clients: clients,
+            filter: filter,
+        };
+
+        Ok((ipc, rx))
+    }
+}
+
+// a socket file left over from a previous run would make the bind fail, so
+// it's removed first. anything at the path that isn't a socket is left alone.
+fn bind(cfg: &Config) -> Result<UnixListener> {
+    let path = Path::new(cfg.path());
+    let mode = cfg.mode()?;
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(ErrorKind::InvalidConfig(format!("{} exists and is not a socket",
+                                                        cfg.path()))
+                .into());
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(ErrorKind::InvalidConfig(format!("{} is already in use", cfg.path()))
+                .into());
+        }
+        debug!("removing stale socket {}", cfg.path());
+        fs::remove_file(path)?;
+    }
+
+    // the socket is created inside a directory only we can enter and gets its
+    // final mode there, so it's never reachable with the umask's permissions
+    let file_name = match path.file_name() {
+        Some(f) => f.to_string_lossy().into_owned(),
+        None => return Err(ErrorKind::InvalidConfig(format!("invalid path {}", cfg.path())).into()),
+    };
+    let private = path.with_file_name(format!(".{}.bind", file_name));
+    let _ = fs::remove_file(private.join(&file_name));
+    let _ = fs::remove_dir(&private);
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let tmp_path = private.join(&file_name);
+    let res = UnixListener::bind(&tmp_path)
+        .and_then(|listener| {
+            fs::set_permissions(&tmp_path, Permissions::from_mode(mode))?;
+            fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&tmp_path);
+    fs::remove_dir(&private)?;
+    Ok(res?)
+}
+
+fn serve(stream: UnixStream,
+         clients: &Registry<String>,
+         filter: &Subscriptions,
+         tx: &Mutex<Sender<Message>>)
+         -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let (id, out_rx) = clients.register();
+    debug!("ipc client {} connected", id);
+
+    // the receiver runs dry once the client is unregistered, or dropped for
+    // lagging behind
+    thread::spawn(move || {
+        for line in out_rx {
+            let written = writer.write_all(line.as_bytes()).and_then(|_| writer.write_all(b"\n"));
+            if let Err(e) = written {
+                debug!("ipc write error: {}", e);
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let res = read_requests(id, stream, clients, filter, tx);
+    debug!("ipc client {} disconnected", id);
+    clients.unregister(id);
+    res
+}
+
+fn read_requests(id: usize,
+                 stream: UnixStream,
+                 clients: &Registry<String>,
+                 filter: &Subscriptions,
+                 tx: &Mutex<Sender<Message>>)
+                 -> Result<()> {
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Err(e) = clients.handle_request(id, &line, filter, tx) {
+            clients.send(id, protocol::error(&format!("{}", e)));
+        }
+    }
+    Ok(())
+}
+
+impl Bus for Ipc {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Ipc::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        self.clients.publish(message);
+        Ok(())
+    }
+
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        self.clients.publish_transient(message);
+        Ok(())
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        self.clients.clear(item_name);
+        Ok(())
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ipc;
+    use super::bind;
+
+    use config::Config;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::value::Value;
+
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::Write;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    // a fresh, empty directory for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("catt-ipc-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn test_config(dir: &PathBuf, mode: &str) -> Config {
+        let mut cfg = Config::default();
+        cfg.path = Some(dir.join("catt.sock").to_string_lossy().into_owned());
+        cfg.mode = Some(mode.into());
+        cfg
+    }
+
+    #[test]
+    fn socket_mode() {
+        let dir = test_dir("mode");
+        let cfg = test_config(&dir, "0600");
+        let _listener = bind(&cfg).unwrap();
+
+        let metadata = fs::symlink_metadata(cfg.path()).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // the private directory it was created in is gone again
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_socket() {
+        let dir = test_dir("stale");
+        let cfg = test_config(&dir, "0660");
+        drop(UnixListener::bind(cfg.path()).unwrap());
+        assert!(bind(&cfg).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn socket_in_use() {
+        let dir = test_dir("in-use");
+        let cfg = test_config(&dir, "0660");
+        let _listener = bind(&cfg).unwrap();
+        assert!(bind(&cfg).is_err());
+        // the socket in use is left alone
+        assert!(UnixStream::connect(cfg.path()).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn not_a_socket() {
+        let dir = test_dir("file");
+        let cfg = test_config(&dir, "0660");
+        File::create(cfg.path()).unwrap().write_all(b"keep").unwrap();
+        assert!(bind(&cfg).is_err());
+        assert_eq!(fs::metadata(cfg.path()).unwrap().len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_mode() {
+        let dir = test_dir("invalid-mode");
+        assert!(bind(&test_config(&dir, "0999")).is_err());
+        assert!(bind(&test_config(&dir, "01777")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn line_protocol() {
+        let dir = test_dir("lines");
+        let cfg = test_config(&dir, "0600");
+        let mut core = Core::new().unwrap();
+        let (ipc, rx) = Ipc::with_config(&core.handle(), &cfg).unwrap();
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        let mut stream = UnixStream::connect(cfg.path()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+
+        // the reply to a request comes back as an error line
+        writeln!(stream, r#"{{"type":"command","name":"lamp","value":true}}"#).unwrap();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.contains(r#""type":"error""#) && line.contains("unknown item lamp"));
+
+        writeln!(stream, r#"{{"type":"subscribe","items":["lamp"]}}"#).unwrap();
+        // requests are handled in order, so once the second error is back
+        // the subscription is in place
+        writeln!(stream, r#"{{"type":"command","name":"other","value":true}}"#).unwrap();
+        assert!(lines.next().unwrap().unwrap().contains("unknown item other"));
+        ipc.publish(Message::Update("lamp".into(), Value::Bool(true), SystemTime::now()))
+            .unwrap();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.contains(r#""type":"update""#) && line.contains(r#""value":true"#));
+
+        ipc.subscribe("lamp", SubType::Command).unwrap();
+        writeln!(stream, r#"{{"type":"command","name":"lamp","value":false}}"#).unwrap();
+        let mut received = None;
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                received = Some(m);
+                break;
+            }
+        }
+        match received {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "lamp");
+                assert_eq!(value, Value::Bool(false));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,652 @@
+// a minimal in-process MQTT 3.1.1 broker.
+//
+// supports CONNECT (clean sessions only), PUBLISH with QoS 0 and 1, retained
+// messages, SUBSCRIBE/UNSUBSCRIBE with wildcards, PINGREQ and will messages.
+// it is meant for single-box installs and tests, not as a mosquitto
+// replacement: there is no persistence, authentication or QoS 1 redelivery.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use catt_core::util::always_lock;
+
+use errors::*;
+
+pub const BROKER_LISTEN_DEFAULT: &'static str = "127.0.0.1:1883";
+pub const BROKER_MAX_PACKET_SIZE_DEFAULT: usize = 1024 * 1024;
+
+// clients that can't take a packet within this long are disconnected
+const WRITE_TIMEOUT_SECS: u64 = 10;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(Debug,Clone)]
+struct Publish {
+    topic: String,
+    payload: Vec<u8>,
+    qos: u8,
+    retain: bool,
+}
+
+// packets for a session are queued and written by its own thread so that a
+// slow client only holds up itself
+struct Session {
+    client_id: String,
+    stream: TcpStream,
+    out: mpsc::Sender<Vec<u8>>,
+    subscriptions: Vec<(String, u8)>,
+    next_packet_id: u16,
+}
+
+impl Session {
+    fn send(&self, header: u8, body: &[u8]) -> io::Result<()> {
+        self.out
+            .send(encode_packet(header, body))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "session writer is gone"))
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        self.next_packet_id = self.next_packet_id.wrapping_add(1);
+        if self.next_packet_id == 0 {
+            self.next_packet_id = 1;
+        }
+        self.next_packet_id
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    sessions: HashMap<usize, Session>,
+    retained: BTreeMap<String, Publish>,
+}
+
+#[derive(Clone)]
+pub struct Broker {
+    addr: SocketAddr,
+    max_packet_size: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    pub fn start(listen: &str, max_packet_size: usize) -> Result<Broker> {
+        let listener = TcpListener::bind(listen)?;
+        let broker = Broker {
+            addr: listener.local_addr()?,
+            max_packet_size: max_packet_size,
+            state: Arc::new(Mutex::new(Default::default())),
+        };
+
+        info!("embedded mqtt broker listening on {}", broker.addr);
+
+        let acceptor = broker.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("broker accept error: {}", e);
+                        continue;
+                    }
+                };
+
+                let conn = acceptor.clone();
+                thread::spawn(move || conn.handle(stream));
+            }
+        });
+
+        Ok(broker)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // drops a client's connection as if the network had gone away, so its
+    // will is published
+    #[cfg(test)]
+    pub fn disconnect(&self, client_id: &str) {
+        let state = always_lock(self.state.lock());
+        for session in state.sessions.values().filter(|s| s.client_id == client_id) {
+            let _ = session.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn handle(&self, mut stream: TcpStream) {
+        let peer = stream.peer_addr().map(|a| format!("{}", a)).unwrap_or("unknown".into());
+        debug!("broker connection from {}", peer);
+
+        let (id, will) = match self.connect(&mut stream) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("broker connect from {} failed: {}", peer, e);
+                return;
+            }
+        };
+
+        let clean = match self.serve(id, &mut stream) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("broker connection from {} closed: {}", peer, e);
+                false
+            }
+        };
+
+        always_lock(self.state.lock()).sessions.remove(&id);
+
+        if !clean {
+            if let Some(will) = will {
+                self.route(will);
+            }
+        }
+    }
+
+    fn connect(&self, stream: &mut TcpStream) -> io::Result<(usize, Option<Publish>)> {
+        let (header, body) = read_packet(stream, self.max_packet_size)?;
+        if header >> 4 != CONNECT {
+            return Err(invalid("expected CONNECT"));
+        }
+
+        let mut pos = 0;
+        let protocol = read_string(&body, &mut pos)?;
+        let level = read_u8(&body, &mut pos)?;
+        if protocol != "MQTT" && protocol != "MQIsdp" {
+            return Err(invalid("unknown protocol"));
+        }
+        if level != 3 && level != 4 {
+            // unacceptable protocol version
+            write_packet(stream, CONNACK << 4, &[0, 1])?;
+            return Err(invalid("unsupported protocol level"));
+        }
+
+        let flags = read_u8(&body, &mut pos)?;
+        let keep_alive = read_u16(&body, &mut pos)?;
+        let client_id = read_string(&body, &mut pos)?;
+
+        let will = if flags & 0x04 != 0 {
+            let topic = read_string(&body, &mut pos)?;
+            let payload = read_bytes(&body, &mut pos)?;
+            Some(Publish {
+                topic: topic,
+                payload: payload,
+                qos: ::std::cmp::min((flags >> 3) & 0x03, 1),
+                retain: flags & 0x20 != 0,
+            })
+        } else {
+            None
+        };
+
+        if keep_alive > 0 {
+            let timeout = Duration::from_millis(keep_alive as u64 * 1500);
+            stream.set_read_timeout(Some(timeout))?;
+        }
+
+        let out = writer(stream.try_clone()?)?;
+        let mut state = always_lock(self.state.lock());
+
+        // a new connection with the same client id takes over the session
+        let existing = state.sessions
+            .iter()
+            .filter(|&(_, s)| !client_id.is_empty() && s.client_id == client_id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in existing {
+            if let Some(old) = state.sessions.remove(&id) {
+                let _ = old.stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        let session = Session {
+            client_id: client_id,
+            stream: stream.try_clone()?,
+            out: out,
+            subscriptions: vec![],
+            next_packet_id: 0,
+        };
+        session.send(CONNACK << 4, &[0, 0])?;
+
+        state.next_id += 1;
+        let id = state.next_id;
+        state.sessions.insert(id, session);
+        Ok((id, will))
+    }
+
+    // returns Ok once the client disconnects cleanly
+    fn serve(&self, id: usize, stream: &mut TcpStream) -> io::Result<()> {
+        loop {
+            let (header, body) = read_packet(stream, self.max_packet_size)?;
+            let mut pos = 0;
+
+            match header >> 4 {
+                PUBLISH => {
+                    let qos = (header >> 1) & 0x03;
+                    let topic = read_string(&body, &mut pos)?;
+                    if qos > 1 {
+                        return Err(invalid("QoS 2 is not supported"));
+                    }
+                    if qos == 1 {
+                        let packet_id = read_u16(&body, &mut pos)?;
+                        self.send(id, PUBACK << 4, &u16_bytes(packet_id))?;
+                    }
+
+                    self.route(Publish {
+                        topic: topic,
+                        payload: body[pos..].to_vec(),
+                        qos: qos,
+                        retain: header & 0x01 != 0,
+                    });
+                }
+                PUBACK => {}
+                SUBSCRIBE => {
+                    let packet_id = read_u16(&body, &mut pos)?;
+                    let mut filters = vec![];
+                    while pos < body.len() {
+                        let filter = read_string(&body, &mut pos)?;
+                        let qos = ::std::cmp::min(read_u8(&body, &mut pos)?, 1);
+                        filters.push((filter, qos));
+                    }
+
+                    let mut ack = u16_bytes(packet_id).to_vec();
+                    ack.extend(filters.iter().map(|&(_, qos)| qos));
+                    self.send(id, SUBACK << 4, &ack)?;
+
+                    self.subscribe(id, filters);
+                }
+                UNSUBSCRIBE => {
+                    let packet_id = read_u16(&body, &mut pos)?;
+                    let mut filters = vec![];
+                    while pos < body.len() {
+                        filters.push(read_string(&body, &mut pos)?);
+                    }
+
+                    if let Some(session) = always_lock(self.state.lock()).sessions.get_mut(&id) {
+                        session.subscriptions.retain(|&(ref f, _)| !filters.contains(f));
+                    }
+                    self.send(id, UNSUBACK << 4, &u16_bytes(packet_id))?;
+                }
+                PINGREQ => self.send(id, PINGRESP << 4, &[])?,
+                DISCONNECT => return Ok(()),
+                _ => return Err(invalid("unexpected packet type")),
+            }
+        }
+    }
+
+    // queues a packet for the session, fails once it has been taken over
+    fn send(&self, id: usize, header: u8, body: &[u8]) -> io::Result<()> {
+        match always_lock(self.state.lock()).sessions.get(&id) {
+            Some(session) => session.send(header, body),
+            None => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "session taken over")),
+        }
+    }
+
+    fn subscribe(&self, id: usize, filters: Vec<(String, u8)>) {
+        let mut state = always_lock(self.state.lock());
+        let retained = state.retained
+            .values()
+            .filter(|p| filters.iter().any(|&(ref f, _)| topic_matches(f, &p.topic)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let session = match state.sessions.get_mut(&id) {
+            Some(s) => s,
+            None => return,
+        };
+
+        for (filter, qos) in filters {
+            session.subscriptions.retain(|&(ref f, _)| *f != filter);
+            session.subscriptions.push((filter, qos));
+        }
+
+        for publish in retained {
+            if let Err(e) = deliver(session, &publish, true) {
+                debug!("broker retained delivery to {} failed: {}", session.client_id, e);
+            }
+        }
+    }
+
+    fn route(&self, publish: Publish) {
+        let mut state = always_lock(self.state.lock());
+
+        if publish.retain {
+            if publish.payload.is_empty() {
+                state.retained.remove(&publish.topic);
+            } else {
+                state.retained.insert(publish.topic.clone(), publish.clone());
+            }
+        }
+
+        for session in state.sessions.values_mut() {
+            if let Err(e) = deliver(session, &publish, false) {
+                debug!("broker delivery to {} failed: {}", session.client_id, e);
+            }
+        }
+    }
+}
+
+fn deliver(session: &mut Session, publish: &Publish, retained: bool) -> io::Result<()> {
+    let granted = session.subscriptions
+        .iter()
+        .filter(|&&(ref f, _)| topic_matches(f, &publish.topic))
+        .map(|&(_, qos)| qos)
+        .max();
+
+    let qos = match granted {
+        Some(q) => ::std::cmp::min(q, publish.qos),
+        None => return Ok(()),
+    };
+
+    let mut body = vec![];
+    write_string(&mut body, &publish.topic);
+    if qos > 0 {
+        let packet_id = session.packet_id();
+        body.extend(&u16_bytes(packet_id));
+    }
+    body.extend(&publish.payload);
+
+    let header = (PUBLISH << 4) | (qos << 1) | if retained { 1 } else { 0 };
+    session.send(header, &body)
+}
+
+// starts the thread writing a session's packets. a write that fails or times
+// out closes the connection, which ends the session.
+fn writer(mut stream: TcpStream) -> io::Result<mpsc::Sender<Vec<u8>>> {
+    stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)))?;
+    let (tx, rx) = mpsc::channel::<Vec<u8>>();
+    thread::spawn(move || {
+        for packet in rx {
+            if let Err(e) = stream.write_all(&packet) {
+                debug!("broker write error: {}", e);
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            }
+        }
+    });
+    Ok(tx)
+}
+
+// matches a topic against a subscription filter with + and # wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // wildcards at the first level don't match $SYS style topics
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// the remaining length is checked against max_len before anything is
+// allocated for the body
+fn read_packet<R: Read>(stream: &mut R, max_len: usize) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte)?;
+        len += ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err(invalid("malformed remaining length"));
+        }
+    }
+
+    if len > max_len {
+        return Err(invalid("packet too large"));
+    }
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> io::Result<()> {
+    stream.write_all(&encode_packet(header, body))
+}
+
+fn encode_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn read_u8(buf: &[u8], pos: &mut usize) -> io::Result<u8> {
+    if *pos >= buf.len() {
+        return Err(invalid("packet too short"));
+    }
+    *pos += 1;
+    Ok(buf[*pos - 1])
+}
+
+fn read_u16(buf: &[u8], pos: &mut usize) -> io::Result<u16> {
+    let hi = read_u8(buf, pos)? as u16;
+    let lo = read_u8(buf, pos)? as u16;
+    Ok((hi << 8) | lo)
+}
+
+fn read_bytes(buf: &[u8], pos: &mut usize) -> io::Result<Vec<u8>> {
+    let len = read_u16(buf, pos)? as usize;
+    if *pos + len > buf.len() {
+        return Err(invalid("packet too short"));
+    }
+    *pos += len;
+    Ok(buf[*pos - len..*pos].to_vec())
+}
+
+fn read_string(buf: &[u8], pos: &mut usize) -> io::Result<String> {
+    String::from_utf8(read_bytes(buf, pos)?).map_err(|_| invalid("invalid utf8 in string"))
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(&u16_bytes(s.len() as u16));
+    buf.extend(s.as_bytes());
+}
+
+fn u16_bytes(n: u16) -> [u8; 2] {
+    [(n >> 8) as u8, (n & 0xff) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Broker;
+    use super::BROKER_MAX_PACKET_SIZE_DEFAULT;
+    use super::encode_packet;
+    use super::read_packet;
+    use super::topic_matches;
+    use super::PUBLISH;
+
+    use config::Config;
+    use mqtt::Mqtt;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::util::always_lock;
+    use catt_core::value::Value;
+
+    use rumqtt;
+
+    use futures::Future;
+    use futures::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    // a plain client standing in for whoever is on the other side of the bus
+    fn peer(broker: &Broker,
+            client_id: &str,
+            filter: &str)
+            -> (rumqtt::MqRequest, mpsc::Receiver<(String, Vec<u8>)>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let options = rumqtt::MqttOptions::new()
+            .set_client_id(client_id)
+            .broker(&format!("{}", broker.local_addr()));
+        let request = rumqtt::MqttClient::new(options)
+            .message_callback(move |message: rumqtt::Message| {
+                let received = (message.topic.as_str().to_string(), (*message.payload).clone());
+                let _ = always_lock(tx.lock()).send(received);
+            })
+            .start()
+            .unwrap();
+
+        request.subscribe(vec![(filter, rumqtt::QoS::Level0)]).unwrap();
+        // give the subscription time to reach the broker
+        thread::sleep(Duration::from_millis(200));
+        (request, rx)
+    }
+
+    fn recv(core: &mut Core, messages: &mpsc::Receiver<Message>) -> Option<Message> {
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                return Some(m);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        for len in vec![0, 1, 127, 128, 16383, 16384, 100000] {
+            let body = vec![0x5a; len];
+            let packet = encode_packet(PUBLISH << 4, &body);
+            let (header, read) = read_packet(&mut Cursor::new(packet), 1024 * 1024).unwrap();
+            assert_eq!(header, PUBLISH << 4);
+            assert_eq!(read, body);
+        }
+    }
+
+    #[test]
+    fn packet_too_large() {
+        let packet = encode_packet(PUBLISH << 4, &[0; 129]);
+        assert!(read_packet(&mut Cursor::new(packet.clone()), 128).is_err());
+        assert!(read_packet(&mut Cursor::new(packet), 129).is_ok());
+
+        // 256MB announced, nothing sent
+        let packet = vec![PUBLISH << 4, 0xff, 0xff, 0xff, 0x7f];
+        assert!(read_packet(&mut Cursor::new(packet), 1024).is_err());
+    }
+
+    #[test]
+    fn malformed_packets() {
+        // remaining length with too many continuation bytes
+        let packet = vec![PUBLISH << 4, 0x80, 0x80, 0x80, 0x80, 0x01];
+        assert!(read_packet(&mut Cursor::new(packet), usize::max_value()).is_err());
+        // body shorter than announced
+        let packet = vec![PUBLISH << 4, 0x05, 0x00];
+        assert!(read_packet(&mut Cursor::new(packet), 1024).is_err());
+        // no remaining length at all
+        assert!(read_packet(&mut Cursor::new(vec![PUBLISH << 4]), 1024).is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("+/uptime", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn mqtt_bus() {
+        let broker = Broker::start("127.0.0.1:0", BROKER_MAX_PACKET_SIZE_DEFAULT).unwrap();
+        let (watcher, published) = peer(&broker, "watcher", "catt/items/+/state");
+
+        let mut core = Core::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.broker = Some(format!("{}", broker.local_addr()));
+        cfg.client_id = Some("bridge".into());
+        let (mqtt, rx) = Mqtt::with_config(&core.handle(), &cfg).unwrap();
+
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        mqtt.publish(Message::Update("light".into(), Value::Number(21.5), SystemTime::now()))
+            .unwrap();
+        let state = ("catt/items/light/state".to_string(), b"21.5".to_vec());
+        assert_eq!(published.recv_timeout(Duration::from_secs(5)).unwrap(), state);
+
+        // a client showing up later still gets the state
+        let (_late, retained) = peer(&broker, "late", "catt/items/+/state");
+        assert_eq!(retained.recv_timeout(Duration::from_secs(5)).unwrap(), state);
+
+        mqtt.subscribe("light", SubType::Command).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        watcher.publish("catt/items/light/command", rumqtt::QoS::Level0, b"42".to_vec())
+            .unwrap();
+        match recv(&mut core, &messages) {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "light");
+                assert_eq!(value, Value::Number(42.0));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use toml;
+
+use catt_core::item::Meta;
+use catt_core::util;
+use catt_core::value::Value;
+
+use errors::*;
+
+// a decoded value along with the metadata the payload carried
+#[derive(Debug,Clone)]
+pub struct Envelope {
+    pub value: Value,
+    pub ts: Option<SystemTime>,
+    pub origin: Option<String>,
+}
+
+impl Envelope {
+    fn plain(value: Value) -> Self {
+        Envelope {
+            value: value,
+            ts: None,
+            origin: None,
+        }
+    }
+}
+
+// recognizes commands that echo our own publishes by the origin they carry,
+// counting every one it finds
+#[derive(Debug,Clone)]
+pub struct LoopDetector {
+    origin: String,
+    count: Arc<AtomicUsize>,
+}
+
+impl LoopDetector {
+    pub fn new(origin: &str) -> Self {
+        LoopDetector {
+            origin: origin.into(),
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn is_echo(&self, name: &str, env: &Envelope) -> bool {
+        if env.origin.as_ref() != Some(&self.origin) {
+            return false;
+        }
+        let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!("dropping command for {} that echoes our own publish ({} loops detected so far)",
+              name,
+              count);
+        true
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+}
+
+// payload format used on the state, command and meta topics
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Codec {
+    // plain value strings and toml encoded meta
+    Text,
+    // {"value":..., "type":..., "ts":..., "origin":...} and json encoded meta
+    Json,
+}
+
+impl Codec {
+    pub fn from_str(s: &str) -> Option<Codec> {
+        match s.to_lowercase().as_str() {
+            "text" => Some(Codec::Text),
+            "json" => Some(Codec::Json),
+            _ => None,
+        }
+    }
+
+    // ts is when the value was observed and origin identifies the publisher
+    // so that echoed messages can be detected. only the json format has room
+    // for them, which is why loop protection requires it.
+    pub fn encode_value(&self, value: &Value, ts: SystemTime, origin: &str) -> Result<String> {
+        match self {
+            &Codec::Text => Ok(value.as_string()?),
+            &Codec::Json => {
+                let mut obj = BTreeMap::new();
+                obj.insert("value".to_string(), value.to_json());
+                obj.insert("type".to_string(), Json::String(value.type_string().into()));
+                obj.insert("ts".to_string(), Json::String(util::format_ts(ts)));
+                obj.insert("origin".to_string(), Json::String(origin.into()));
+                Ok(Json::Object(obj).to_string())
+            }
+        }
+    }
+
+    pub fn decode_value(&self, payload: &[u8]) -> Result<Value> {
+        self.decode_envelope(payload).map(|env| env.value)
+    }
+
+    pub fn decode_envelope(&self, payload: &[u8]) -> Result<Envelope> {
+        match self {
+            &Codec::Text => Ok(Envelope::plain(Value::from_raw(payload))),
+            &Codec::Json => {
+                let parsed = ::std::str::from_utf8(payload)
+                    .ok()
+                    .and_then(|s| Json::from_str(s).ok());
+                match parsed {
+                    Some(j) => json_to_value(j),
+                    None => {
+                        // still accept plain payloads so that simple clients keep working
+                        debug!("payload is not json, falling back to text");
+                        Ok(Envelope::plain(Value::from_raw(payload)))
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn encode_meta(&self, meta: &Meta) -> Result<String> {
+        match self {
+            &Codec::Text => Ok(toml::encode_str(meta)),
+            &Codec::Json => Ok(json::encode(meta)?),
+        }
+    }
+
+    pub fn decode_meta(&self, payload: &[u8]) -> Result<Meta> {
+        let payload_str = String::from_utf8(payload.into())?;
+        match self {
+            &Codec::Text => {
+                match toml::decode_str(payload_str.as_str()) {
+                    Some(meta) => Ok(meta),
+                    None => Err(ErrorKind::InvalidPayload(payload_str).into()),
+                }
+            }
+            &Codec::Json => Ok(json::decode(payload_str.as_str())?),
+        }
+    }
+}
+
+fn json_to_value(j: Json) -> Result<Envelope> {
+    let (value, value_type, ts, origin) = match j {
+        Json::Object(mut obj) => {
+            let value = match obj.remove("value") {
+                Some(v) => v,
+                None => return Err(ErrorKind::InvalidPayload(Json::Object(obj).to_string()).into()),
+            };
+            let value_type = match obj.remove("type") {
+                Some(Json::String(t)) => Some(t),
+                _ => None,
+            };
+            let ts = match obj.remove("ts") {
+                Some(Json::String(ts)) => util::parse_ts(&ts),
+                _ => None,
+            };
+            let origin = match obj.remove("origin") {
+                Some(Json::String(o)) => Some(o),
+                _ => None,
+            };
+            (value, value_type, ts, origin)
+        }
+        j => (j, None, None, None),
+    };
+
+    let value = match value {
+        Json::Boolean(b) => Value::Bool(b),
+        Json::I64(n) => Value::Number(n as f64),
+        Json::U64(n) => Value::Number(n as f64),
+        Json::F64(n) => Value::Number(n),
+        Json::String(s) => Value::String(s),
+        j => return Err(ErrorKind::InvalidPayload(j.to_string()).into()),
+    };
+
+    let value = match value_type.as_ref().map(|t| t.as_str()) {
+        Some("number") => value.as_number_value()?,
+        Some("string") => value.as_string_value()?,
+        Some("bool") => value.as_bool_value()?,
+        Some("raw") => value.as_raw_value()?,
+        _ => value,
+    };
+
+    Ok(Envelope {
+        value: value,
+        ts: ts,
+        origin: origin,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Codec;
+    use super::LoopDetector;
+
+    use catt_core::value::Value;
+
+    use std::time::SystemTime;
+
+    #[test]
+    fn json_round_trip() {
+        let payload = Codec::Json.encode_value(&Value::Number(21.5), SystemTime::now(), "a")
+            .unwrap();
+        let env = Codec::Json.decode_envelope(payload.as_bytes()).unwrap();
+        assert_eq!(env.value, Value::Number(21.5));
+        assert_eq!(env.origin, Some("a".to_string()));
+        assert!(env.ts.is_some());
+    }
+
+    #[test]
+    fn text_has_no_origin() {
+        let payload = Codec::Text.encode_value(&Value::Bool(true), SystemTime::now(), "a")
+            .unwrap();
+        let env = Codec::Text.decode_envelope(payload.as_bytes()).unwrap();
+        assert_eq!(env.origin, None);
+    }
+
+    #[test]
+    fn loop_counter() {
+        let loops = LoopDetector::new("a");
+        let own = Codec::Json.encode_value(&Value::Bool(true), SystemTime::now(), "a").unwrap();
+        let other = Codec::Json.encode_value(&Value::Bool(true), SystemTime::now(), "b").unwrap();
+
+        let decode = |p: &str| Codec::Json.decode_envelope(p.as_bytes()).unwrap();
+        assert!(!loops.is_echo("lamp", &decode(&other)));
+        assert!(!loops.is_echo("lamp", &decode("true")));
+        assert_eq!(loops.count(), 0);
+
+        assert!(loops.is_echo("lamp", &decode(&own)));
+        assert!(loops.is_echo("lamp", &decode(&own)));
+        assert_eq!(loops.count(), 2);
+
+        // clones share the counter
+        assert!(loops.clone().is_echo("lamp", &decode(&own)));
+        assert_eq!(loops.count(), 3);
+    }
+}
@@ -1,5 +1,23 @@
+use errors::*;
+
+use codec::Codec;
+use queue::Policy;
+use broker::BROKER_LISTEN_DEFAULT;
+use broker::BROKER_MAX_PACKET_SIZE_DEFAULT;
+use homie::HOMIE_BASE_DEFAULT;
+use homie::HOMIE_DEVICE_DEFAULT;
+use sparkplug::GROUP_DEFAULT;
+use sparkplug::EDGE_NODE_DEFAULT;
+
+pub const MQTT_BROKER_DEFAULT: &'static str = "127.0.0.1:1883";
 pub const MQTT_BASE_DEFAULT: &'static str = "catt/items";
 pub const MQTT_QOS_DEFAULT: u8 = 0;
+pub const MQTT_RETAIN_STATE_DEFAULT: bool = true;
+pub const MQTT_RETAIN_META_DEFAULT: bool = true;
+pub const MQTT_KEEP_ALIVE_DEFAULT: u16 = 5;
+pub const MQTT_RECONNECT_DEFAULT: u16 = 3;
+pub const MQTT_QUEUE_SIZE_DEFAULT: usize = 1000;
+pub const MQTT_DISCOVERY_PREFIX_DEFAULT: &'static str = "homeassistant";
 
 #[derive(RustcDecodable,Debug,Clone,Default)]
 pub struct Config {
@@ -8,4 +26,191 @@ pub struct Config {
     pub client_id: Option<String>,
     pub qos: Option<u8>,
     pub tls: Option<bool>,
+    pub keep_alive: Option<u16>,
+    pub reconnect: Option<u16>,
+    pub queue_size: Option<usize>,
+    pub queue_policy: Option<String>,
+    pub retain_state: Option<bool>,
+    pub retain_meta: Option<bool>,
+    pub discovery: Option<bool>,
+    pub discovery_prefix: Option<String>,
+    pub mode: Option<String>,
+    pub homie_base: Option<String>,
+    pub homie_device: Option<String>,
+    pub sparkplug_group: Option<String>,
+    pub sparkplug_node: Option<String>,
+    pub payload: Option<String>,
+    pub topics: Option<TopicConfig>,
+    pub embedded_broker: Option<BrokerConfig>,
+    pub state_ts_topic: Option<bool>,
+    pub wildcard_subscribe: Option<bool>,
+    pub loop_protection: Option<bool>,
+}
+
+// runs an in-process broker that the bus connects to
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct BrokerConfig {
+    pub listen: Option<String>,
+    // in bytes, larger packets close the connection
+    pub max_packet_size: Option<usize>,
+}
+
+impl BrokerConfig {
+    pub fn listen(&self) -> &str {
+        self.listen.as_ref().map(|l| l.as_str()).unwrap_or(BROKER_LISTEN_DEFAULT)
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size.unwrap_or(BROKER_MAX_PACKET_SIZE_DEFAULT)
+    }
+}
+
+// topic templates for each message type. "{base}" is replaced with the
+// item_base and "{name}" with the item name.
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct TopicConfig {
+    pub state: Option<String>,
+    pub command: Option<String>,
+    pub meta: Option<String>,
+}
+
+// topic layout used for items
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Mode {
+    // <item_base>/<name>/state|command|meta
+    Catt,
+    // <homie_base>/<device>/<node>/value[/set]
+    Homie,
+    // spBv1.0/<group>/<message type>/<edge node>[/<device>]
+    Sparkplug,
+}
+
+impl Config {
+    // an explicitly configured broker wins over the embedded one
+    pub fn broker(&self) -> &str {
+        match (&self.broker, &self.embedded_broker) {
+            (&Some(ref b), _) => b.as_str(),
+            (&None, &Some(ref e)) => e.listen(),
+            (&None, &None) => MQTT_BROKER_DEFAULT,
+        }
+    }
+
+    pub fn item_base(&self) -> &str {
+        self.item_base.as_ref().map(|b| b.as_str()).unwrap_or(MQTT_BASE_DEFAULT)
+    }
+
+    pub fn keep_alive(&self) -> u16 {
+        self.keep_alive.unwrap_or(MQTT_KEEP_ALIVE_DEFAULT)
+    }
+
+    // seconds between attempts to reach the broker again
+    pub fn reconnect(&self) -> Result<u16> {
+        match self.reconnect.unwrap_or(MQTT_RECONNECT_DEFAULT) {
+            0 => Err(ErrorKind::InvalidConfig("reconnect must be at least 1 second".into()).into()),
+            r => Ok(r),
+        }
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(MQTT_QUEUE_SIZE_DEFAULT)
+    }
+
+    pub fn queue_policy(&self) -> Result<Policy> {
+        let policy = match self.queue_policy {
+            Some(ref p) => p,
+            None => return Ok(Policy::DropOldest),
+        };
+
+        match Policy::from_str(policy) {
+            Some(p) => Ok(p),
+            None => Err(ErrorKind::InvalidConfig(format!("unknown queue policy: {}", policy)).into()),
+        }
+    }
+
+    pub fn retain_state(&self) -> bool {
+        self.retain_state.unwrap_or(MQTT_RETAIN_STATE_DEFAULT)
+    }
+
+    pub fn retain_meta(&self) -> bool {
+        self.retain_meta.unwrap_or(MQTT_RETAIN_META_DEFAULT)
+    }
+
+    // also publish the observation time of each state to <state topic>/ts
+    pub fn state_ts_topic(&self) -> bool {
+        self.state_ts_topic.unwrap_or(false)
+    }
+
+    // subscribe once per message type instead of once per item
+    pub fn wildcard_subscribe(&self) -> bool {
+        self.wildcard_subscribe.unwrap_or(false)
+    }
+
+    pub fn discovery(&self) -> bool {
+        self.discovery.unwrap_or(false)
+    }
+
+    pub fn discovery_prefix(&self) -> &str {
+        self.discovery_prefix.as_ref().map(|p| p.as_str()).unwrap_or(MQTT_DISCOVERY_PREFIX_DEFAULT)
+    }
+
+    pub fn mode(&self) -> Result<Mode> {
+        Ok(match self.mode.as_ref().map(|m| m.to_lowercase()) {
+            None => Mode::Catt,
+            Some(ref m) if m == "catt" => Mode::Catt,
+            Some(ref m) if m == "homie" => Mode::Homie,
+            Some(ref m) if m == "sparkplug" => Mode::Sparkplug,
+            Some(m) => return Err(ErrorKind::InvalidConfig(format!("unknown mode: {}", m)).into()),
+        })
+    }
+
+    pub fn homie_base(&self) -> &str {
+        self.homie_base.as_ref().map(|b| b.as_str()).unwrap_or(HOMIE_BASE_DEFAULT)
+    }
+
+    pub fn homie_device(&self) -> &str {
+        self.homie_device
+            .as_ref()
+            .or(self.client_id.as_ref())
+            .map(|d| d.as_str())
+            .unwrap_or(HOMIE_DEVICE_DEFAULT)
+    }
+
+    pub fn sparkplug_group(&self) -> &str {
+        self.sparkplug_group.as_ref().map(|g| g.as_str()).unwrap_or(GROUP_DEFAULT)
+    }
+
+    pub fn sparkplug_node(&self) -> &str {
+        self.sparkplug_node
+            .as_ref()
+            .or(self.client_id.as_ref())
+            .map(|n| n.as_str())
+            .unwrap_or(EDGE_NODE_DEFAULT)
+    }
+
+    // echoed commands are recognized by the origin in the json envelope.
+    // on by default with json payloads, text payloads have no room for it.
+    pub fn loop_protection(&self) -> Result<bool> {
+        match (self.loop_protection, self.codec()?) {
+            (Some(true), Codec::Text) => {
+                Err(ErrorKind::InvalidConfig("loop_protection requires payload = \"json\"".into())
+                    .into())
+            }
+            (Some(p), _) => Ok(p),
+            (None, codec) => Ok(codec == Codec::Json),
+        }
+    }
+
+    pub fn codec(&self) -> Result<Codec> {
+        let payload = match self.payload {
+            Some(ref p) => p,
+            None => return Ok(Codec::Text),
+        };
+
+        match Codec::from_str(payload) {
+            Some(c) => Ok(c),
+            None => {
+                Err(ErrorKind::InvalidConfig(format!("unknown payload format: {}", payload)).into())
+            }
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use catt_core::item::Meta;
+
+// home assistant component types that catt items can be exposed as
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Component {
+    Switch,
+    Light,
+    BinarySensor,
+    Sensor,
+}
+
+impl Component {
+    pub fn from_meta(meta: &Meta) -> Component {
+        let class = meta.ext.as_ref().and_then(|ext| ext.get("command_class"));
+        match class.map(|c| c.as_str()) {
+            Some("SwitchBinary") => return Component::Switch,
+            Some("SwitchMultilevel") => return Component::Light,
+            Some("SensorBinary") => return Component::BinarySensor,
+            Some("SensorMultilevel") => return Component::Sensor,
+            _ => {}
+        }
+
+        match meta.value_type.as_ref().map(|t| t.as_str()) {
+            Some("bool") => Component::BinarySensor,
+            _ => Component::Sensor,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Component::Switch => "switch",
+            &Component::Light => "light",
+            &Component::BinarySensor => "binary_sensor",
+            &Component::Sensor => "sensor",
+        }
+    }
+}
+
+// home assistant only allows [a-zA-Z0-9_-] in the object id
+pub fn object_id(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+pub fn config_topic(prefix: &str, component: Component, name: &str) -> String {
+    format!("{}/{}/{}/config", prefix, component.as_str(), object_id(name))
+}
+
+pub fn config_payload(name: &str,
+                      meta: &Meta,
+                      component: Component,
+                      state_topic: &str,
+                      command_topic: &str)
+                      -> String {
+    let mut cfg = BTreeMap::new();
+    cfg.insert("name".to_string(), Json::String(name.into()));
+    cfg.insert("unique_id".to_string(), Json::String(object_id(name)));
+    cfg.insert("state_topic".to_string(), Json::String(state_topic.into()));
+
+    match component {
+        Component::Switch => {
+            cfg.insert("command_topic".to_string(), Json::String(command_topic.into()));
+            cfg.insert("payload_on".to_string(), Json::String("ON".into()));
+            cfg.insert("payload_off".to_string(), Json::String("OFF".into()));
+        }
+        Component::Light => {
+            // multilevel switches report their level (0-99) as state, so
+            // on/off has to be derived from it.
+            cfg.insert("command_topic".to_string(), Json::String(command_topic.into()));
+            cfg.insert("brightness_command_topic".to_string(),
+                       Json::String(command_topic.into()));
+            cfg.insert("brightness_state_topic".to_string(),
+                       Json::String(state_topic.into()));
+            cfg.insert("brightness_scale".to_string(), Json::U64(99));
+            cfg.insert("on_command_type".to_string(), Json::String("brightness".into()));
+            cfg.insert("payload_off".to_string(), Json::String("OFF".into()));
+            cfg.insert("state_value_template".to_string(),
+                       Json::String("{% if value | float > 0 %}ON{% else %}OFF{% endif %}"
+                           .into()));
+        }
+        Component::BinarySensor => {
+            cfg.insert("payload_on".to_string(), Json::String("ON".into()));
+            cfg.insert("payload_off".to_string(), Json::String("OFF".into()));
+        }
+        Component::Sensor => {
+            let units = meta.ext.as_ref().and_then(|ext| ext.get("units"));
+            if let Some(units) = units {
+                if !units.is_empty() {
+                    cfg.insert("unit_of_measurement".to_string(),
+                               Json::String(units.clone()));
+                }
+            }
+        }
+    }
+
+    if let Some(device) = device_info(meta) {
+        cfg.insert("device".to_string(), device);
+    }
+
+    Json::Object(cfg).to_string()
+}
+
+// groups all values of a zwave node under a single home assistant device
+fn device_info(meta: &Meta) -> Option<Json> {
+    let ext = match meta.ext {
+        Some(ref ext) => ext,
+        None => return None,
+    };
+
+    let (home_id, node_id) = match (ext.get("home_id"), ext.get("node_id")) {
+        (Some(h), Some(n)) => (h, n),
+        _ => return None,
+    };
+
+    let backend = meta.backend.as_ref().map(|b| b.as_str()).unwrap_or("catt");
+    let id = format!("{}_{}_{}", backend, home_id, node_id);
+
+    let mut device = BTreeMap::new();
+    device.insert("identifiers".to_string(),
+                  Json::Array(vec![Json::String(id.clone())]));
+    device.insert("name".to_string(), Json::String(id));
+    Some(Json::Object(device))
+}
@@ -9,6 +9,9 @@ error_chain!{
     foreign_links {
         ::std::net::AddrParseError, AddrParseError;
         ::std::io::Error, IoError;
+        ::std::string::FromUtf8Error, Utf8Error;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+        ::rustc_serialize::json::DecoderError, JsonDecodeError;
     }
 
     errors {
@@ -21,6 +24,16 @@ error_chain!{
             description("mqtt client not started")
             display("mqtt client not started")
         }
+
+        InvalidPayload(payload: String) {
+            description("invalid payload")
+            display("invalid payload: {}", payload)
+        }
+
+        InvalidConfig(reason: String) {
+            description("invalid mqtt configuration")
+            display("invalid mqtt configuration: {}", reason)
+        }
     }
 }
 
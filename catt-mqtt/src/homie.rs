@@ -0,0 +1,292 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::collections::BTreeMap;
+
+use catt_core::item::Meta;
+use catt_core::value::Value;
+use catt_core::util::always_lock;
+
+use errors::*;
+
+pub const HOMIE_BASE_DEFAULT: &'static str = "homie";
+pub const HOMIE_DEVICE_DEFAULT: &'static str = "catt";
+pub const HOMIE_VERSION: &'static str = "3.0.1";
+
+// every catt item is exposed as a homie node with a single "value" property
+const PROPERTY: &'static str = "value";
+
+// maps catt items onto the homie convention:
+// <base>/<device>/<node>/value[/set]
+#[derive(Clone)]
+pub struct Homie {
+    device_topic: String,
+    device_name: String,
+    // node id -> item name
+    nodes: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+impl Homie {
+    pub fn new(base: &str, device: &str) -> Self {
+        let mut device_id = node_id(device);
+        if device_id.is_empty() {
+            device_id = HOMIE_DEVICE_DEFAULT.into();
+        }
+        Homie {
+            device_topic: format!("{}/{}", base, device_id),
+            device_name: device.into(),
+            nodes: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    pub fn device_topics(&self) -> Vec<(String, String)> {
+        let nodes = always_lock(self.nodes.lock());
+        vec![(self.attr("$homie"), HOMIE_VERSION.into()),
+             (self.attr("$name"), self.device_name.clone()),
+             (self.attr("$nodes"), nodes.keys().cloned().collect::<Vec<_>>().join(",")),
+             (self.attr("$implementation"), "catt".into()),
+             (self.attr("$state"), "ready".into())]
+    }
+
+    // left with the broker so that controllers see the device as lost if
+    // the bridge goes away without disconnecting. the client library can
+    // only leave wills that aren't retained.
+    pub fn will(&self) -> (String, String) {
+        (self.attr("$state"), "lost".into())
+    }
+
+    pub fn node_topics(&self, name: &str, meta: &Meta) -> Vec<(String, String)> {
+        let node = self.register(name);
+        let node_topic = format!("{}/{}", self.device_topic, node);
+        let prop_topic = format!("{}/{}", node_topic, PROPERTY);
+
+        let mut topics = vec![(format!("{}/$name", node_topic), name.into()),
+                              (format!("{}/$type", node_topic),
+                               meta.backend.clone().unwrap_or("catt".into())),
+                              (format!("{}/$properties", node_topic), PROPERTY.into()),
+                              (format!("{}/$name", prop_topic), name.into()),
+                              (format!("{}/$datatype", prop_topic),
+                               datatype(meta.value_type.as_ref().map(|t| t.as_str())).into()),
+                              (format!("{}/$settable", prop_topic), settable(meta).into()),
+                              (format!("{}/$retained", prop_topic), "true".into())];
+
+        let units = meta.ext.as_ref().and_then(|ext| ext.get("units"));
+        if let Some(units) = units {
+            if !units.is_empty() {
+                topics.push((format!("{}/$unit", prop_topic), units.clone()));
+            }
+        }
+
+        topics.push(self.nodes_attr());
+        topics
+    }
+
+    // topics to clear (publish empty to) once an item goes away
+    pub fn remove_node(&self, name: &str) -> Vec<(String, String)> {
+        let node = match self.lookup(name) {
+            Some(n) => n,
+            None => return vec![],
+        };
+        always_lock(self.nodes.lock()).remove(&node);
+
+        let node_topic = format!("{}/{}", self.device_topic, node);
+        let prop_topic = format!("{}/{}", node_topic, PROPERTY);
+
+        let mut topics = ["$name", "$type", "$properties"]
+            .iter()
+            .map(|attr| (format!("{}/{}", node_topic, attr), String::new()))
+            .collect::<Vec<_>>();
+        topics.extend(["", "/$name", "/$datatype", "/$settable", "/$retained", "/$unit"]
+            .iter()
+            .map(|attr| (format!("{}{}", prop_topic, attr), String::new())));
+        topics.push(self.nodes_attr());
+        topics
+    }
+
+    pub fn state_topic(&self, name: &str) -> String {
+        format!("{}/{}/{}", self.device_topic, self.register(name), PROPERTY)
+    }
+
+    pub fn command_topic(&self, name: &str) -> String {
+        format!("{}/set", self.state_topic(name))
+    }
+
+    pub fn wildcard(&self) -> String {
+        format!("{}/#", self.device_topic)
+    }
+
+    pub fn all_topic(&self, name: &str) -> String {
+        format!("{}/{}/#", self.device_topic, self.register(name))
+    }
+
+    // returns the item name and whether the topic is the property's set topic
+    pub fn parse(&self, topic: &str) -> Option<(String, bool)> {
+        let prefix = format!("{}/", self.device_topic);
+        if !topic.starts_with(&prefix) {
+            return None;
+        }
+
+        let parts = topic[prefix.len()..].split('/').collect::<Vec<&str>>();
+        if parts.len() < 2 || parts[1] != PROPERTY {
+            return None;
+        }
+
+        let set = match parts.len() {
+            2 => false,
+            3 if parts[2] == "set" => true,
+            _ => return None,
+        };
+
+        always_lock(self.nodes.lock()).get(parts[0]).map(|name| (name.clone(), set))
+    }
+
+    pub fn encode(&self, value: &Value) -> Result<String> {
+        Ok(match value {
+            &Value::Bool(b) => format!("{}", b),
+            v => v.as_string()?,
+        })
+    }
+
+    fn attr(&self, attr: &str) -> String {
+        format!("{}/{}", self.device_topic, attr)
+    }
+
+    fn nodes_attr(&self) -> (String, String) {
+        let nodes = always_lock(self.nodes.lock());
+        (self.attr("$nodes"), nodes.keys().cloned().collect::<Vec<_>>().join(","))
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        let nodes = always_lock(self.nodes.lock());
+        nodes.iter().filter(|&(_, n)| n == name).map(|(id, _)| id.clone()).nth(0)
+    }
+
+    fn register(&self, name: &str) -> String {
+        if let Some(id) = self.lookup(name) {
+            return id;
+        }
+
+        let mut nodes = always_lock(self.nodes.lock());
+        let mut base = node_id(name);
+        if base.is_empty() {
+            base = "node".into();
+        }
+        let mut id = base.clone();
+        let mut i = 1;
+        while nodes.contains_key(&id) {
+            id = format!("{}-{}", base, i);
+            i += 1;
+        }
+        nodes.insert(id.clone(), name.into());
+        id
+    }
+}
+
+// homie ids may only contain lowercase letters, digits and hyphens. names
+// without any of those come out empty.
+pub fn node_id(name: &str) -> String {
+    let id = name.to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'...'z' | '0'...'9' => c,
+            _ => '-',
+        })
+        .collect::<String>();
+    id.trim_matches('-').into()
+}
+
+// bindings mark items that can't be set with a read_only ext entry
+fn settable(meta: &Meta) -> &'static str {
+    match meta.ext.as_ref().and_then(|ext| ext.get("read_only")) {
+        Some(r) if r == "true" => "false",
+        _ => "true",
+    }
+}
+
+fn datatype(value_type: Option<&str>) -> &'static str {
+    match value_type {
+        Some("bool") => "boolean",
+        Some("number") => "float",
+        _ => "string",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Homie;
+    use super::node_id;
+
+    use catt_core::item::Meta;
+
+    use std::collections::HashMap;
+
+    fn meta(read_only: Option<&str>) -> Meta {
+        let mut ext = HashMap::new();
+        if let Some(r) = read_only {
+            ext.insert("read_only".to_string(), r.to_string());
+        }
+        Meta {
+            value_type: Some("bool".into()),
+            ext: Some(ext),
+            ..Default::default()
+        }
+    }
+
+    fn attr(topics: &[(String, String)], topic: &str) -> Option<String> {
+        topics.iter().find(|&&(ref t, _)| t == topic).map(|&(_, ref p)| p.clone())
+    }
+
+    #[test]
+    fn ids() {
+        assert_eq!(node_id("Kitchen Light"), "kitchen-light");
+        assert_eq!(node_id("_temp_"), "temp");
+        assert_eq!(node_id("__"), "");
+
+        let homie = Homie::new("homie", "!!");
+        assert_eq!(homie.will(), ("homie/catt/$state".to_string(), "lost".to_string()));
+        assert_eq!(homie.state_topic("??"), "homie/catt/node/value");
+    }
+
+    #[test]
+    fn topics() {
+        let homie = Homie::new("homie", "catt");
+        assert_eq!(homie.state_topic("Kitchen Light"), "homie/catt/kitchen-light/value");
+        assert_eq!(homie.command_topic("Kitchen Light"),
+                   "homie/catt/kitchen-light/value/set");
+        // a different name mapping to the same id gets a suffix
+        assert_eq!(homie.state_topic("kitchen_light"), "homie/catt/kitchen-light-1/value");
+        assert_eq!(homie.state_topic("Kitchen Light"), "homie/catt/kitchen-light/value");
+    }
+
+    #[test]
+    fn parse() {
+        let homie = Homie::new("homie", "catt");
+        homie.state_topic("light");
+
+        assert_eq!(homie.parse("homie/catt/light/value"), Some(("light".to_string(), false)));
+        assert_eq!(homie.parse("homie/catt/light/value/set"),
+                   Some(("light".to_string(), true)));
+        assert_eq!(homie.parse("homie/catt/light/value/$name"), None);
+        assert_eq!(homie.parse("homie/catt/light/$name"), None);
+        assert_eq!(homie.parse("homie/catt/other/value"), None);
+        // another device whose id starts with ours
+        assert_eq!(homie.parse("homie/catt2/light/value"), None);
+    }
+
+    #[test]
+    fn settable() {
+        let homie = Homie::new("homie", "catt");
+        let topics = homie.node_topics("light", &meta(None));
+        assert_eq!(attr(&topics, "homie/catt/light/value/$settable"), Some("true".into()));
+        assert_eq!(attr(&topics, "homie/catt/light/value/$datatype"),
+                   Some("boolean".into()));
+        assert_eq!(attr(&topics, "homie/catt/$nodes"), Some("light".into()));
+
+        let topics = homie.node_topics("sensor", &meta(Some("true")));
+        assert_eq!(attr(&topics, "homie/catt/sensor/value/$settable"), Some("false".into()));
+        assert_eq!(attr(&topics, "homie/catt/$nodes"), Some("light,sensor".into()));
+
+        let removed = homie.remove_node("light");
+        assert_eq!(attr(&removed, "homie/catt/light/value/$settable"), Some("".into()));
+        assert_eq!(attr(&removed, "homie/catt/$nodes"), Some("sensor".into()));
+    }
+}
@@ -11,14 +11,25 @@ extern crate rumqtt;
 
 extern crate rustc_serialize;
 
+extern crate byteorder;
+
 extern crate catt_core;
 
 extern crate toml;
 
+extern crate chrono;
+
 extern crate tokio_core;
 extern crate futures;
 
 pub mod errors;
 pub mod config;
-
+pub mod codec;
+pub mod topic;
+pub mod queue;
+pub mod broker;
+
+pub mod discovery;
+pub mod homie;
+pub mod sparkplug;
 pub mod mqtt;
@@ -1,13 +1,22 @@
 use rumqtt;
 
-use toml;
-
 use config::Config;
-use config::MQTT_BASE_DEFAULT;
 
+use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use futures::Future;
+use futures::Stream;
+
+use chrono::UTC;
 
 use tokio_core::reactor::Handle;
+use tokio_core::reactor::Interval;
 
 use tokio_core::channel::channel;
 use tokio_core::channel::Receiver;
@@ -16,109 +25,275 @@ use tokio_core::channel::Sender;
 use catt_core::bus::Bus;
 use catt_core::bus::Message;
 use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
 
 use catt_core::value::Value;
+use catt_core::item::Meta;
+use catt_core::util;
+use catt_core::util::always_lock;
+
+use config::Mode;
+use codec;
+use codec::Codec;
+use codec::LoopDetector;
+use discovery;
+use discovery::Component;
+use homie::Homie;
+use sparkplug::Sparkplug;
+use broker::Broker;
+use queue::Pending;
+use queue::Queue;
+use topic::Kind;
+use topic::Topics;
 
 use errors::*;
 
+type Callback = Arc<Fn(rumqtt::Message) + Send + Sync>;
+
 pub struct MqttClient {
     cfg: Config,
-    client: Option<rumqtt::MqttClient>,
-    requester: Option<rumqtt::MqRequest>,
+    callback: Option<Callback>,
+    requester: Mutex<Option<rumqtt::MqRequest>>,
+    will: Option<(String, String)>,
+    // in sparkplug mode every connection is a new session with its own will
+    sparkplug: Option<Sparkplug>,
+    queue: Mutex<Queue>,
+    subscriptions: Mutex<Vec<String>>,
+    connected: AtomicBool,
 }
 
 impl MqttClient {
     pub fn with_config(cfg: &Config) -> Result<MqttClient> {
-        let mut client_options = rumqtt::MqttOptions::new()
-            .set_keep_alive(5)
-            .set_reconnect(3);
-
-        match &cfg.client_id {
-            &Some(ref id) => client_options = client_options.set_client_id(&id),
-            &None => {}
-        };
-
-        let addr: &str = cfg.broker.as_ref().map(|b| b.as_str()).unwrap_or("127.0.0.1:1883");
-        client_options = client_options.broker(addr);
-
-
-        let client = rumqtt::MqttClient::new(client_options);
-
         Ok(MqttClient {
             cfg: cfg.clone(),
-            client: Some(client),
-            requester: None,
+            callback: None,
+            requester: Mutex::new(None),
+            will: None,
+            sparkplug: None,
+            queue: Mutex::new(Queue::new(cfg.queue_policy()?, cfg.queue_size())),
+            subscriptions: Mutex::new(vec![]),
+            connected: AtomicBool::new(true),
         })
     }
 
     pub fn with_callback<F>(mut self, cb: F) -> Self
         where F: Fn(rumqtt::Message) + Send + Sync + 'static
     {
-        self.client = match self.client.take() {
-            Some(cl) => Some(cl.message_callback(cb)),
-            None => None,
-        };
+        let cb: Callback = Arc::new(cb);
+        self.callback = Some(cb);
+        self
+    }
 
+    pub fn with_will(mut self, topic: &str, payload: &str) -> Self {
+        self.will = Some((topic.into(), payload.into()));
         self
     }
 
-    pub fn start(mut self) -> Result<Self> {
-        let (client, requester) = match (self.client.take(), self.requester.take()) {
-            (Some(cl), _) => {
-                let requester = cl.start()?;
-                (None, Some(requester))
-            }
-            (None, Some(req)) => (None, Some(req)),
-            (cl, req) => (cl, req),
-        };
+    pub fn with_sparkplug(mut self, sparkplug: Sparkplug) -> Self {
+        self.sparkplug = Some(sparkplug);
+        self
+    }
 
-        self.client = client;
-        self.requester = requester;
+    pub fn start(self) -> Result<Self> {
+        self.connect()?;
         Ok(self)
     }
 
-    pub fn publish(&self, path: &str, state: &[u8]) -> Result<()> {
-        let pub_path = match self.cfg.item_base {
-            Some(ref b) => format!("{}/{}", b, path),
-            None => format!("{}/{}", MQTT_BASE_DEFAULT, path),
-        };
+    // starts a new connection, replacing the current one if there is any.
+    // sparkplug sessions begin with their births, before anything queued.
+    fn connect(&self) -> Result<()> {
+        let mut options = rumqtt::MqttOptions::new()
+            .set_keep_alive(self.cfg.keep_alive())
+            .set_reconnect(self.cfg.reconnect()?);
 
-        match self.requester {
-            Some(ref req) => Ok(req.publish(&pub_path, rumqtt::QoS::Level0, state.into())?),
-            None => Err(ErrorKind::NotStarted.into()),
+        if let Some(ref id) = self.cfg.client_id {
+            options = options.set_client_id(id);
+        }
+
+        options = options.broker(self.cfg.broker());
+
+        if let Some(ref old) = always_lock(self.requester.lock()).take() {
+            // end the previous session properly in case it is still up
+            if let Some(ref sparkplug) = self.sparkplug {
+                let (topic, payload) = sparkplug.death();
+                let _ = old.publish(&topic, rumqtt::QoS::Level0, payload);
+            }
+            let _ = old.disconnect();
+        }
+
+        if let Some((ref topic, ref payload)) = self.will {
+            options = options.set_will(topic, payload);
+        }
+
+        // the broker announces our death to sparkplug host applications
+        if let Some(ref sparkplug) = self.sparkplug {
+            let (topic, payload) = sparkplug.new_session();
+            // set_will only takes strings, see Sparkplug::new_session
+            options = options.set_will(&topic, &String::from_utf8(payload)?);
         }
+
+        let mut client = rumqtt::MqttClient::new(options);
+        if let Some(ref cb) = self.callback {
+            let cb = cb.clone();
+            client = client.message_callback(move |message| cb(message));
+        }
+        *always_lock(self.requester.lock()) = Some(client.start()?);
+
+        // straight to the new connection. going through publish would find
+        // the client still marked disconnected and reconnect all over again.
+        if let Some(ref sparkplug) = self.sparkplug {
+            for (topic, payload) in sparkplug.birth() {
+                self.send(&Pending {
+                    topic: topic,
+                    payload: payload,
+                    retain: false,
+                })?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn subscribe(&self, path: &str) -> Result<()> {
-        let sub_path = match self.cfg.item_base {
-            Some(ref b) => format!("{}/{}", b, path),
-            None => format!("{}/{}", MQTT_BASE_DEFAULT, path),
+    // messages that can't be sent are queued and replayed by `flush`
+    pub fn publish(&self, topic: &str, state: &[u8], retain: bool) -> Result<()> {
+        let msg = Pending {
+            topic: topic.into(),
+            payload: state.into(),
+            retain: retain,
         };
 
-        match self.requester {
-            Some(ref req) => Ok(req.subscribe(vec![(&sub_path, rumqtt::QoS::Level0)])?),
-            None => Err(ErrorKind::NotStarted.into()),
+        // anything already queued has to go out first to keep ordering
+        if !self.flush() {
+            debug!("mqtt disconnected, queueing message for {}", topic);
+            always_lock(self.queue.lock()).push(msg);
+            return Ok(());
+        }
+
+        if let Err(e) = self.send(&msg) {
+            warn!("mqtt publish to {} failed, queueing message: {}", topic, e);
+            self.connected.store(false, Ordering::SeqCst);
+            always_lock(self.queue.lock()).push(msg);
         }
+        Ok(())
     }
 
-    #[allow(unused_variables)]
-    pub fn unsubscribe(&self, path: &str) -> Result<()> {
-        // TODO once the library supports it
-        // let sub_path = match self.cfg.item_base {
-        //     Some(ref b) => format!("{}/{}", b, path),
-        //     None => format!("{}/{}", MQTT_BASE_DEFAULT, path),
-        // };
+    // subscriptions are remembered and re-issued after a reconnect
+    pub fn subscribe(&self, topic: &str) -> Result<()> {
+        {
+            let mut subs = always_lock(self.subscriptions.lock());
+            if !subs.iter().any(|s| s == topic) {
+                subs.push(topic.into());
+            }
+        }
+
+        if !self.connected.load(Ordering::SeqCst) {
+            debug!("mqtt disconnected, deferring subscription to {}", topic);
+            return Ok(());
+        }
 
+        if let Err(e) = self.send_subscribe(topic) {
+            warn!("mqtt subscribe to {} failed, retrying on reconnect: {}", topic, e);
+            self.connected.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, topic: &str) -> Result<()> {
+        always_lock(self.subscriptions.lock()).retain(|s| s != topic);
+
+        // TODO once the library supports it
         // match self.requester {
-        //     Some(ref req) => Ok(req.unsubscribe(vec![(&sub_path, rumqtt::QoS::Level0)])?),
+        //     Some(ref req) => Ok(req.unsubscribe(vec![(topic, rumqtt::QoS::Level0)])?),
         //     None => Err(ErrorKind::NotStarted.into()),
         // }
         Ok(())
     }
+
+    // tries to send everything in the queue, returns true if it is now empty.
+    // after a disconnect all subscriptions are re-issued first.
+    pub fn flush(&self) -> bool {
+        if !self.connected.load(Ordering::SeqCst) && !self.reconnect() {
+            return false;
+        }
+
+        let mut queue = always_lock(self.queue.lock());
+        if !queue.is_empty() {
+            debug!("replaying {} queued mqtt messages", queue.len());
+        }
+
+        while let Some(msg) = queue.pop() {
+            if let Err(e) = self.send(&msg) {
+                debug!("mqtt replay to {} failed: {}", msg.topic, e);
+                self.connected.store(false, Ordering::SeqCst);
+                queue.push_front(msg);
+                return false;
+            }
+        }
+        true
+    }
+
+    // the library reconnects on its own, but always with the will it was
+    // started with, so sparkplug mode starts over with a new session instead
+    fn reconnect(&self) -> bool {
+        if self.sparkplug.is_some() {
+            if let Err(e) = self.connect() {
+                debug!("mqtt reconnect failed: {}", e);
+                return false;
+            }
+        }
+        self.resubscribe()
+    }
+
+    // marks the client connected before anything else goes out, so that a
+    // publish or subscribe made meanwhile doesn't start another reconnect
+    fn resubscribe(&self) -> bool {
+        self.connected.store(true, Ordering::SeqCst);
+
+        let subs = always_lock(self.subscriptions.lock()).clone();
+        for topic in subs.iter() {
+            if let Err(e) = self.send_subscribe(topic) {
+                debug!("mqtt resubscribe to {} failed: {}", topic, e);
+                self.connected.store(false, Ordering::SeqCst);
+                return false;
+            }
+        }
+
+        info!("mqtt reconnected, re-issued {} subscriptions", subs.len());
+        true
+    }
+
+    fn send(&self, msg: &Pending) -> Result<()> {
+        let payload = msg.payload.clone();
+        match *always_lock(self.requester.lock()) {
+            Some(ref req) if msg.retain => {
+                Ok(req.retained_publish(&msg.topic, rumqtt::QoS::Level0, payload)?)
+            }
+            Some(ref req) => Ok(req.publish(&msg.topic, rumqtt::QoS::Level0, payload)?),
+            None => Err(ErrorKind::NotStarted.into()),
+        }
+    }
+
+    fn send_subscribe(&self, topic: &str) -> Result<()> {
+        match *always_lock(self.requester.lock()) {
+            Some(ref req) => Ok(req.subscribe(vec![(topic, rumqtt::QoS::Level0)])?),
+            None => Err(ErrorKind::NotStarted.into()),
+        }
+    }
 }
 
 pub struct Mqtt {
-    client: MqttClient,
+    #[allow(dead_code)]
+    broker: Option<Broker>,
+    client: Arc<MqttClient>,
+    codec: Codec,
+    topics: Topics,
+    origin: String,
+    loops: Option<LoopDetector>,
+    // pattern subscriptions are backed by broad mqtt subscriptions, so
+    // incoming messages are filtered against what was actually subscribed
+    filter: Subscriptions,
+    discovered: Mutex<HashMap<String, Component>>,
+    homie: Option<Homie>,
+    sparkplug: Option<Sparkplug>,
 }
 
 impl Mqtt {
@@ -126,52 +301,305 @@ impl Mqtt {
         let (tx, rx) = channel(handle)?;
         let tx = Mutex::new(tx);
 
-        let client = MqttClient::with_config(cfg)?
-            .with_callback(message_callback(tx))
+        let homie = match cfg.mode()? {
+            Mode::Homie => Some(Homie::new(cfg.homie_base(), cfg.homie_device())),
+            _ => None,
+        };
+        let sparkplug = match cfg.mode()? {
+            Mode::Sparkplug => Some(Sparkplug::new(cfg.sparkplug_group(), cfg.sparkplug_node())),
+            _ => None,
+        };
+
+        let topics = Topics::from_config(cfg)?;
+
+        // tag for our own publishes so that commands echoed back from them
+        // can be recognized
+        let origin = cfg.client_id
+            .clone()
+            .unwrap_or_else(|| format!("catt-{}", UTC::now().timestamp()));
+        let loops = match cfg.loop_protection()? {
+            true => Some(LoopDetector::new(&origin)),
+            false => None,
+        };
+        let filter = Subscriptions::default();
+
+        let broker = match cfg.embedded_broker {
+            Some(ref b) => Some(Broker::start(b.listen(), b.max_packet_size())?),
+            None => None,
+        };
+
+        let mut client = MqttClient::with_config(cfg)?;
+        if let Some(ref homie) = homie {
+            let (topic, payload) = homie.will();
+            client = client.with_will(&topic, &payload);
+        }
+        if let Some(ref sparkplug) = sparkplug {
+            client = client.with_sparkplug(sparkplug.clone());
+        }
+        let client = client.with_callback(message_callback(tx,
+                                                           cfg.codec()?,
+                                                           topics.clone(),
+                                                           homie.clone(),
+                                                           sparkplug.clone(),
+                                                           loops.clone(),
+                                                           filter.clone()))
             .start()?;
+        let client = Arc::new(client);
+
+        // periodically retry queued messages and subscriptions, and answer
+        // sparkplug rebirth requests
+        let retry_client = client.clone();
+        let retry_sparkplug = sparkplug.clone();
+        let retry = Interval::new(Duration::from_secs(cfg.reconnect()? as u64), handle)?
+            .for_each(move |_| {
+                retry_client.flush();
+                if let Some(ref sparkplug) = retry_sparkplug {
+                    if sparkplug.take_rebirth() {
+                        if let Err(e) = publish_births(&retry_client, sparkplug) {
+                            warn!("error publishing sparkplug rebirth: {:?}", e);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(|e| warn!("mqtt retry timer error: {}", e));
+        handle.spawn(retry);
 
-        Ok((Mqtt {
+        let mqtt = Mqtt {
+            broker: broker,
             client: client,
-        }, rx))
+            codec: cfg.codec()?,
+            topics: topics,
+            origin: origin,
+            loops: loops,
+            filter: filter,
+            discovered: Mutex::new(HashMap::new()),
+            homie: homie,
+            sparkplug: sparkplug,
+        };
+
+        if let Some(ref homie) = mqtt.homie {
+            mqtt.publish_topics(homie.device_topics())?;
+        }
+        // the births went out when the client connected
+        if let Some(ref sparkplug) = mqtt.sparkplug {
+            mqtt.get_client().subscribe(&sparkplug.node_command_topic())?;
+        }
+
+        Ok((mqtt, rx))
+    }
+
+    // number of commands dropped because they echoed one of our own publishes
+    pub fn loop_count(&self) -> usize {
+        self.loops.as_ref().map(|l| l.count()).unwrap_or(0)
     }
 
     fn get_client(&self) -> &MqttClient {
         &self.client
     }
+
+    fn get_config(&self) -> &Config {
+        &self.client.cfg
+    }
+
+    fn publish_topics(&self, topics: Vec<(String, String)>) -> Result<()> {
+        for (topic, payload) in topics {
+            self.get_client().publish(&topic, payload.as_bytes(), true)?;
+        }
+        Ok(())
+    }
+
+    fn publish_homie(&self, homie: &Homie, message: Message) -> Result<()> {
+        match message {
+            Message::Update(name, value, _) => {
+                self.get_client().publish(&homie.state_topic(&name),
+                                          homie.encode(&value)?.as_bytes(),
+                                          true)
+            }
+            Message::Command(name, value) => {
+                self.get_client().publish(&homie.command_topic(&name),
+                                          homie.encode(&value)?.as_bytes(),
+                                          false)
+            }
+            Message::Meta(name, meta) => self.publish_topics(homie.node_topics(&name, &meta)),
+        }
+    }
+
+    // sparkplug messages are never retained, the births carry the state
+    fn publish_sparkplug(&self, sparkplug: &Sparkplug, message: Message) -> Result<()> {
+        let (topic, payload) = match message {
+            Message::Update(name, value, ts) => sparkplug.data(&name, &value, ts),
+            Message::Command(name, value) => sparkplug.command(&name, &value),
+            Message::Meta(name, meta) => sparkplug.device_birth(&name, &meta),
+        };
+        self.get_client().publish(&topic, &payload, false)
+    }
+
+    // a '*' also matches the '/' in names spanning several topic levels, so
+    // those patterns need the multi-level wildcard. the filter drops whatever
+    // else it brings in.
+    fn pattern_topics(&self, pattern: &str, sub_type: SubType) -> Vec<String> {
+        let multi_level = pattern.contains('*') || pattern.contains('/');
+        match (&self.homie, &self.sparkplug) {
+            (&Some(ref homie), _) => vec![homie.wildcard()],
+            (_, &Some(ref sparkplug)) => vec![sparkplug.command_wildcard()],
+            (&None, &None) => self.topics.wildcards(sub_type, multi_level),
+        }
+    }
+
+    // an edge node only receives commands, its own data isn't subscribed to
+    fn sparkplug_topic(&self,
+                       sparkplug: &Sparkplug,
+                       item_name: &str,
+                       sub_type: SubType)
+                       -> Option<String> {
+        match sub_type {
+            SubType::Command |
+            SubType::All => Some(sparkplug.command_topic(item_name)),
+            SubType::Update |
+            SubType::Meta => None,
+        }
+    }
+
+    fn homie_topic(&self, homie: &Homie, item_name: &str, sub_type: SubType) -> String {
+        match sub_type {
+            SubType::Update => homie.state_topic(item_name),
+            SubType::Command => homie.command_topic(item_name),
+            // homie has no per-item meta topic, the node attributes cover it
+            SubType::Meta |
+            SubType::All => homie.all_topic(item_name),
+        }
+    }
+
+    fn publish_discovery(&self, name: &str, meta: &Meta) -> Result<()> {
+        let component = Component::from_meta(meta);
+        let topic = discovery::config_topic(self.get_config().discovery_prefix(), component, name);
+        let state_topic = self.topics.topic(name, Kind::State);
+        let command_topic = self.topics.topic(name, Kind::Command);
+        let payload = discovery::config_payload(name, meta, component, &state_topic, &command_topic);
+
+        debug!("publishing discovery config for {} to {}", name, topic);
+        self.get_client().publish(&topic, payload.as_bytes(), true)?;
+        always_lock(self.discovered.lock()).insert(name.into(), component);
+        Ok(())
+    }
+
+    fn clear_discovery(&self, name: &str) -> Result<()> {
+        let component = match always_lock(self.discovered.lock()).remove(name) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        let topic = discovery::config_topic(self.get_config().discovery_prefix(), component, name);
+        self.get_client().publish(&topic, &[], true)
+    }
+}
+
+fn publish_births(client: &MqttClient, sparkplug: &Sparkplug) -> Result<()> {
+    for (topic, payload) in sparkplug.birth() {
+        client.publish(&topic, &payload, false)?;
+    }
+    Ok(())
 }
 
-fn message_callback(tx: Mutex<Sender<Message>>) -> impl Fn(rumqtt::Message) {
+fn message_callback(tx: Mutex<Sender<Message>>,
+                    codec: Codec,
+                    topics: Topics,
+                    homie: Option<Homie>,
+                    sparkplug: Option<Sparkplug>,
+                    loops: Option<LoopDetector>,
+                    filter: Subscriptions)
+                    -> impl Fn(rumqtt::Message) {
     return move |message| {
         debug!("got message: {:?}", message);
-        let topic = message.topic.as_str().split("/").collect::<Vec<&str>>();
 
-        if topic.len() < 2 {
-            warn!("message with invalid path received: {}",
-                message.topic.as_str());
+        if let Some(ref sparkplug) = sparkplug {
+            let (item_name, value) = match sparkplug.parse(message.topic.as_str(),
+                                                           &*message.payload) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("error decoding sparkplug payload: {}", e);
+                    return;
+                }
+            };
+
+            if !filter.accepts(&item_name, SubType::Command) {
+                debug!("no subscription for {}, dropping", message.topic.as_str());
+                return;
+            }
+
+            match always_lock(tx.lock()).send(Message::Command(item_name, value)) {
+                Ok(_) => {}
+                Err(e) => warn!("channel send error: {}", e),
+            }
+            return;
+        }
+
+        if let Some(ref homie) = homie {
+            let (item_name, set) = match homie.parse(message.topic.as_str()) {
+                Some(parsed) => parsed,
+                None => {
+                    debug!("ignoring non-property homie topic: {}", message.topic.as_str());
+                    return;
+                }
+            };
+
+            let kind = if set { Kind::Command } else { Kind::State };
+            if !filter.accepts(&item_name, kind.sub_type()) {
+                debug!("no subscription for {}, dropping", message.topic.as_str());
+                return;
+            }
+
+            let value = Value::from_raw(&*message.payload);
+            let message = match kind {
+                Kind::Command => Message::Command(item_name, value),
+                _ => Message::Update(item_name, value, SystemTime::now()),
+            };
+
+            match always_lock(tx.lock()).send(message) {
+                Ok(_) => {}
+                Err(e) => warn!("channel send error: {}", e),
+            }
             return;
         }
 
-        let item_name = String::from(topic[topic.len() - 2]);
+        let (item_name, kind) = match topics.parse(message.topic.as_str()) {
+            Some(parsed) => parsed,
+            None => {
+                warn!("message with invalid path received: {}",
+                      message.topic.as_str());
+                return;
+            }
+        };
+
+        if !filter.accepts(&item_name, kind.sub_type()) {
+            debug!("no subscription for {}, dropping", message.topic.as_str());
+            return;
+        }
 
-        let message_type_str = topic[topic.len() - 1];
-        let message = match message_type_str {
-            "state" => Message::Update(item_name, Value::from_raw(&*message.payload)),
-            "command" => Message::Command(item_name, Value::from_raw(&*message.payload)),
-            "meta" => {
-                if let Ok(payload_str) = String::from_utf8((&*message.payload).clone()) {
-                    if let Some(meta) = toml::decode_str(payload_str.as_str()) {
-                        Message::Meta(item_name, meta)
-                    } else {
-                        warn!("error decoding toml: {}", payload_str);
-                        return;
+        let payload = &*message.payload;
+        let message = match kind {
+            Kind::State => {
+                codec.decode_envelope(payload).map(|env| {
+                    let ts = env.ts.unwrap_or_else(SystemTime::now);
+                    Message::Update(item_name, env.value, ts)
+                })
+            }
+            Kind::Command => {
+                match codec.decode_envelope(payload) {
+                    Ok(ref env) if loops.as_ref().map_or(false, |l| l.is_echo(&item_name, env)) => {
+                        return
                     }
-                } else {
-                    warn!("meta contained invalid utf8");
-                    return;
+                    res => res.map(|env| Message::Command(item_name, env.value)),
                 }
             }
-            _ => {
-                warn!("invalid message type: {}", message_type_str);
+            Kind::Meta => codec.decode_meta(payload).map(|m| Message::Meta(item_name, m)),
+        };
+
+        let message = match message {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("error decoding {} payload: {}", kind.as_str(), e);
                 return;
             }
         };
@@ -193,32 +621,278 @@ impl Bus for Mqtt {
 
     fn publish(&self, message: Message) -> Result<()> {
         debug!("publish {:?}", message);
-        let (name, message_type, payload) = match message {
-            Message::Update(name, value) => (name, "state", value.as_string()?),
-            Message::Command(name, value) => (name, "command", value.as_string()?),
-            Message::Meta(name, meta) => (name, "meta", toml::encode_str(&meta)),
+        if let Some(ref homie) = self.homie {
+            return self.publish_homie(homie, message);
+        }
+        if let Some(ref sparkplug) = self.sparkplug {
+            return self.publish_sparkplug(sparkplug, message);
+        }
+
+        let (name, kind, payload, retain) = match message {
+            Message::Update(name, value, ts) => {
+                let retain = self.get_config().retain_state();
+                if self.get_config().state_ts_topic() {
+                    let ts_topic = format!("{}/ts", self.topics.topic(&name, Kind::State));
+                    self.get_client()
+                        .publish(&ts_topic, util::format_ts(ts).as_bytes(), retain)?;
+                }
+                (name, Kind::State, self.codec.encode_value(&value, ts, &self.origin)?, retain)
+            }
+            // commands are never retained - a late subscriber would replay them
+            Message::Command(name, value) => {
+                let payload = self.codec.encode_value(&value, SystemTime::now(), &self.origin)?;
+                (name, Kind::Command, payload, false)
+            }
+            Message::Meta(name, meta) => {
+                if self.get_config().discovery() {
+                    if let Err(e) = self.publish_discovery(&name, &meta) {
+                        warn!("error publishing discovery config for {}: {:?}", name, e);
+                    }
+                }
+                let retain = self.get_config().retain_meta();
+                (name, Kind::Meta, self.codec.encode_meta(&meta)?, retain)
+            }
         };
-        let path = format!("{}/{}", name, message_type);
-        self.get_client().publish(&path, payload.as_bytes())
+        self.get_client().publish(&self.topics.topic(&name, kind), payload.as_bytes(), retain)
+    }
+
+    // never retained and without the separate timestamp topic
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        match message {
+            Message::Update(name, value, ts) => {
+                let payload = self.codec.encode_value(&value, ts, &self.origin)?;
+                self.get_client()
+                    .publish(&self.topics.topic(&name, Kind::State), payload.as_bytes(), false)
+            }
+            message => self.publish(message),
+        }
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        debug!("clear {}", item_name);
+        if let Some(ref homie) = self.homie {
+            return self.publish_topics(homie.remove_node(item_name));
+        }
+        if let Some(ref sparkplug) = self.sparkplug {
+            return match sparkplug.device_death(item_name) {
+                Some((topic, payload)) => self.get_client().publish(&topic, &payload, false),
+                None => Ok(()),
+            };
+        }
+
+        // an empty retained message removes the retained value from the broker
+        if self.get_config().retain_state() {
+            let state_topic = self.topics.topic(item_name, Kind::State);
+            self.get_client().publish(&state_topic, &[], true)?;
+            if self.get_config().state_ts_topic() {
+                self.get_client().publish(&format!("{}/ts", state_topic), &[], true)?;
+            }
+        }
+        if self.get_config().retain_meta() {
+            self.get_client().publish(&self.topics.topic(item_name, Kind::Meta), &[], true)?;
+        }
+        if self.get_config().discovery() {
+            self.clear_discovery(item_name)?;
+        }
+        Ok(())
     }
 
     fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
         debug!("subscribe {}, {:?}", item_name, sub_type);
-        match sub_type {
-            SubType::Update => self.get_client().subscribe(&format!("{}/state", item_name)),
-            SubType::Command => self.get_client().subscribe(&format!("{}/command", item_name)),
-            SubType::Meta => self.get_client().subscribe(&format!("{}/meta", item_name)),
-            SubType::All => self.get_client().subscribe(&format!("{}/#", item_name)),
+        self.filter.add_item(item_name, sub_type);
+        if let Some(ref homie) = self.homie {
+            return self.get_client().subscribe(&self.homie_topic(homie, item_name, sub_type));
         }
+        if let Some(ref sparkplug) = self.sparkplug {
+            return match self.sparkplug_topic(sparkplug, item_name, sub_type) {
+                Some(topic) => self.get_client().subscribe(&topic),
+                None => Ok(()),
+            };
+        }
+
+        for topic in self.topics.sub_topics(item_name, sub_type) {
+            self.get_client().subscribe(&topic)?;
+        }
+        Ok(())
     }
 
     fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
         debug!("unsubscribe {}, {:?}", item_name, sub_type);
-        match sub_type {
-            SubType::Update => self.get_client().unsubscribe(&format!("{}/state", item_name)),
-            SubType::Command => self.get_client().unsubscribe(&format!("{}/command", item_name)),
-            SubType::Meta => self.get_client().unsubscribe(&format!("{}/meta", item_name)),
-            SubType::All => self.get_client().unsubscribe(&format!("{}/#", item_name)),
+        self.filter.remove_item(item_name, sub_type);
+        if let Some(ref homie) = self.homie {
+            return self.get_client().unsubscribe(&self.homie_topic(homie, item_name, sub_type));
+        }
+        if let Some(ref sparkplug) = self.sparkplug {
+            return match self.sparkplug_topic(sparkplug, item_name, sub_type) {
+                Some(topic) => self.get_client().unsubscribe(&topic),
+                None => Ok(()),
+            };
+        }
+
+        for topic in self.topics.sub_topics(item_name, sub_type) {
+            self.get_client().unsubscribe(&topic)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        if !self.get_config().wildcard_subscribe() {
+            return Ok(false);
+        }
+
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        for topic in self.pattern_topics(pattern, sub_type) {
+            self.get_client().subscribe(&topic)?;
+        }
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        if !self.get_config().wildcard_subscribe() {
+            return Ok(false);
+        }
+
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        // the broad subscription stays in place while other patterns may
+        // still rely on it, the filter takes care of the rest
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mqtt;
+    use super::MqttClient;
+
+    use broker::Broker;
+    use broker::BROKER_MAX_PACKET_SIZE_DEFAULT;
+    use config::Config;
+    use sparkplug::Sparkplug;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::util::always_lock;
+    use catt_core::value::Value;
+
+    use rumqtt;
+
+    use futures::Future;
+    use futures::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    // a sparkplug host application watching the edge node
+    fn host(broker: &Broker) -> (rumqtt::MqRequest, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let options = rumqtt::MqttOptions::new()
+            .set_client_id("host")
+            .broker(&format!("{}", broker.local_addr()));
+        let request = rumqtt::MqttClient::new(options)
+            .message_callback(move |message: rumqtt::Message| {
+                let _ = always_lock(tx.lock()).send(message.topic.as_str().to_string());
+            })
+            .start()
+            .unwrap();
+
+        request.subscribe(vec![("spBv1.0/#", rumqtt::QoS::Level0)]).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        (request, rx)
+    }
+
+    fn topics_until(received: &mpsc::Receiver<String>, last: &str) -> Vec<String> {
+        let mut topics = vec![];
+        while let Ok(topic) = received.recv_timeout(Duration::from_secs(5)) {
+            topics.push(topic.clone());
+            if topic == last {
+                break;
+            }
+        }
+        topics
+    }
+
+    #[test]
+    fn sparkplug_reconnect() {
+        let broker = Broker::start("127.0.0.1:0", BROKER_MAX_PACKET_SIZE_DEFAULT).unwrap();
+        let (_host, received) = host(&broker);
+
+        let mut cfg = Config::default();
+        cfg.broker = Some(format!("{}", broker.local_addr()));
+        cfg.client_id = Some("edge".into());
+        let sparkplug = Sparkplug::new(cfg.sparkplug_group(), cfg.sparkplug_node());
+        let client = MqttClient::with_config(&cfg)
+            .unwrap()
+            .with_sparkplug(sparkplug)
+            .start()
+            .unwrap();
+
+        let birth = "spBv1.0/catt/NBIRTH/edge".to_string();
+        assert_eq!(topics_until(&received, &birth), vec![birth.clone()]);
+
+        // the connection goes away and the next send fails
+        broker.disconnect("edge");
+        client.connected.store(false, Ordering::SeqCst);
+
+        // a new session announces itself before the message goes out
+        let data = "spBv1.0/catt/DDATA/edge/light";
+        client.publish(data, b"", false).unwrap();
+        let topics = topics_until(&received, data);
+        let birth_at = topics.iter().position(|t| *t == birth).expect("no rebirth");
+        assert_eq!(topics.last().map(|t| t.as_str()), Some(data));
+        assert!(birth_at < topics.len() - 1);
+        assert!(client.connected.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wildcard_subscription_covers_hierarchical_names() {
+        let broker = Broker::start("127.0.0.1:0", BROKER_MAX_PACKET_SIZE_DEFAULT).unwrap();
+
+        let mut core = Core::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.broker = Some(format!("{}", broker.local_addr()));
+        cfg.client_id = Some("bridge".into());
+        cfg.wildcard_subscribe = Some(true);
+        let (mqtt, rx) = Mqtt::with_config(&core.handle(), &cfg).unwrap();
+
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        assert!(mqtt.subscribe_pattern("*", SubType::Command).unwrap());
+        thread::sleep(Duration::from_millis(200));
+
+        let options = rumqtt::MqttOptions::new()
+            .set_client_id("controller")
+            .broker(&format!("{}", broker.local_addr()));
+        let controller = rumqtt::MqttClient::new(options).start().unwrap();
+        controller.publish("catt/items/kitchen/light/command", rumqtt::QoS::Level0, b"42".to_vec())
+            .unwrap();
+
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            match messages.try_recv() {
+                Ok(Message::Command(name, value)) => {
+                    assert_eq!(name, "kitchen/light");
+                    assert_eq!(value, Value::Number(42.0));
+                    return;
+                }
+                Ok(m) => panic!("expected the command, got {:?}", m),
+                Err(_) => {}
+            }
         }
+        panic!("command for a hierarchical name never arrived");
     }
 }
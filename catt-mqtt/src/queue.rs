@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+
+// what to do with queued messages while the broker is unreachable
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Policy {
+    // keep every message, dropping the oldest once the queue is full
+    DropOldest,
+    // only keep the latest message for each topic
+    Coalesce,
+}
+
+impl Policy {
+    pub fn from_str(s: &str) -> Option<Policy> {
+        match s.to_lowercase().as_str() {
+            "drop_oldest" => Some(Policy::DropOldest),
+            "coalesce" => Some(Policy::Coalesce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug,Clone)]
+pub struct Pending {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+// bounded buffer for messages that could not be published
+pub struct Queue {
+    policy: Policy,
+    capacity: usize,
+    pending: VecDeque<Pending>,
+}
+
+impl Queue {
+    pub fn new(policy: Policy, capacity: usize) -> Self {
+        Queue {
+            policy: policy,
+            capacity: capacity,
+            pending: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, msg: Pending) {
+        if self.policy == Policy::Coalesce {
+            let existing = self.pending.iter().position(|p| p.topic == msg.topic);
+            if let Some(i) = existing {
+                self.pending.remove(i);
+            }
+        }
+
+        if self.capacity == 0 {
+            warn!("publish queue disabled, dropping message for {}", msg.topic);
+            return;
+        }
+
+        while self.pending.len() >= self.capacity {
+            if let Some(dropped) = self.pending.pop_front() {
+                warn!("publish queue full, dropping message for {}", dropped.topic);
+            }
+        }
+
+        self.pending.push_back(msg);
+    }
+
+    // puts a message that failed to publish again back at the head of the queue
+    pub fn push_front(&mut self, msg: Pending) {
+        if self.pending.len() < self.capacity {
+            self.pending.push_front(msg);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Pending> {
+        self.pending.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pending;
+    use super::Policy;
+    use super::Queue;
+
+    fn msg(topic: &str, payload: &str) -> Pending {
+        Pending {
+            topic: topic.into(),
+            payload: payload.as_bytes().to_vec(),
+            retain: false,
+        }
+    }
+
+    fn drain(queue: &mut Queue) -> Vec<(String, String)> {
+        let mut out = vec![];
+        while let Some(p) = queue.pop() {
+            out.push((p.topic, String::from_utf8(p.payload).unwrap()));
+        }
+        out
+    }
+
+    fn pairs(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter().map(|&(t, p)| (t.to_string(), p.to_string())).collect()
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(Policy::from_str("Drop_Oldest"), Some(Policy::DropOldest));
+        assert_eq!(Policy::from_str("coalesce"), Some(Policy::Coalesce));
+        assert_eq!(Policy::from_str("drop_newest"), None);
+    }
+
+    #[test]
+    fn drop_oldest_overflow() {
+        let mut queue = Queue::new(Policy::DropOldest, 3);
+        for i in 0..5 {
+            queue.push(msg("a", &i.to_string()));
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(drain(&mut queue), pairs(&[("a", "2"), ("a", "3"), ("a", "4")]));
+    }
+
+    #[test]
+    fn coalesce_keeps_latest_per_topic() {
+        let mut queue = Queue::new(Policy::Coalesce, 10);
+        queue.push(msg("a", "1"));
+        queue.push(msg("b", "1"));
+        queue.push(msg("a", "2"));
+        queue.push(msg("c", "1"));
+        queue.push(msg("b", "2"));
+
+        // a topic moves to the back with its latest message
+        assert_eq!(drain(&mut queue), pairs(&[("a", "2"), ("c", "1"), ("b", "2")]));
+    }
+
+    #[test]
+    fn coalesce_overflow() {
+        let mut queue = Queue::new(Policy::Coalesce, 2);
+        queue.push(msg("a", "1"));
+        queue.push(msg("b", "1"));
+        queue.push(msg("a", "2"));
+        queue.push(msg("c", "1"));
+        assert_eq!(drain(&mut queue), pairs(&[("a", "2"), ("c", "1")]));
+    }
+
+    #[test]
+    fn failed_message_goes_back_first() {
+        let mut queue = Queue::new(Policy::DropOldest, 2);
+        queue.push(msg("a", "1"));
+        queue.push(msg("b", "1"));
+        let first = queue.pop().unwrap();
+        queue.push_front(first);
+        assert_eq!(drain(&mut queue), pairs(&[("a", "1"), ("b", "1")]));
+
+        // a full queue doesn't take it back
+        let mut queue = Queue::new(Policy::DropOldest, 1);
+        queue.push(msg("a", "1"));
+        queue.push_front(msg("b", "1"));
+        assert_eq!(drain(&mut queue), pairs(&[("a", "1")]));
+    }
+
+    #[test]
+    fn disabled_queue() {
+        let mut queue = Queue::new(Policy::DropOldest, 0);
+        queue.push(msg("a", "1"));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,527 @@
+// sparkplug b mapping: the bridge is an edge node and every catt item is a
+// device with a single "value" metric.
+//
+// spBv1.0/<group>/NBIRTH|NDEATH|NCMD/<edge node>
+// spBv1.0/<group>/DBIRTH|DDEATH|DDATA|DCMD/<edge node>/<device>
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use catt_core::item::Meta;
+use catt_core::value::Value;
+use catt_core::util::always_lock;
+use catt_core::util::millis;
+
+use errors::*;
+
+pub const NAMESPACE: &'static str = "spBv1.0";
+pub const GROUP_DEFAULT: &'static str = "catt";
+pub const EDGE_NODE_DEFAULT: &'static str = "catt";
+
+const METRIC: &'static str = "value";
+const BD_SEQ: &'static str = "bdSeq";
+const REBIRTH: &'static str = "Node Control/Rebirth";
+
+// sparkplug b datatypes
+const UINT64: u64 = 8;
+const DOUBLE: u64 = 10;
+const BOOLEAN: u64 = 11;
+const STRING: u64 = 12;
+const BYTES: u64 = 17;
+
+#[derive(Clone)]
+pub struct Sparkplug {
+    group: String,
+    edge_node: String,
+    state: Arc<Mutex<State>>,
+    rebirth: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct State {
+    seq: u8,
+    // birth/death sequence of the current session, None before the first
+    bd_seq: Option<u8>,
+    // device id -> item name
+    devices: BTreeMap<String, String>,
+    // device id -> value type from the item's meta, used for rebirths
+    births: BTreeMap<String, Option<String>>,
+}
+
+impl State {
+    fn next_seq(&mut self) -> u64 {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        seq as u64
+    }
+}
+
+impl Sparkplug {
+    pub fn new(group: &str, edge_node: &str) -> Self {
+        Sparkplug {
+            group: sanitize(group),
+            edge_node: sanitize(edge_node),
+            state: Arc::new(Mutex::new(Default::default())),
+            rebirth: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // starts a new session with the next bdSeq and returns its NDEATH, to be
+    // registered as the will message. bdSeq wraps at 128 rather than 256 so
+    // that the payload stays valid utf-8, which the client library requires
+    // for wills.
+    pub fn new_session(&self) -> (String, Vec<u8>) {
+        {
+            let mut state = always_lock(self.state.lock());
+            state.bd_seq = Some(match state.bd_seq {
+                Some(n) => (n + 1) % 128,
+                None => 0,
+            });
+        }
+        self.death()
+    }
+
+    // NDEATH of the current session
+    pub fn death(&self) -> (String, Vec<u8>) {
+        let bd_seq = self.bd_seq();
+        let mut payload = vec![];
+        put_message(&mut payload, 2, &metric(BD_SEQ, UINT64, Some(&bd_seq), None));
+        (self.node_topic("NDEATH"), payload)
+    }
+
+    // NBIRTH and a DBIRTH for every known device
+    pub fn birth(&self) -> Vec<(String, Vec<u8>)> {
+        let bd_seq = self.bd_seq();
+        let mut state = always_lock(self.state.lock());
+        // a new birth sequence always starts at 0
+        state.seq = 0;
+
+        let metrics = vec![metric(BD_SEQ, UINT64, Some(&bd_seq), None),
+                           metric(REBIRTH, BOOLEAN, Some(&Value::Bool(false)), None)];
+        let mut births = vec![(self.node_topic("NBIRTH"), payload(&mut state, metrics))];
+
+        let devices = state.births.clone();
+        for (device, value_type) in devices {
+            let m = metric(METRIC, datatype(value_type.as_ref().map(|t| t.as_str())), None, None);
+            births.push((self.device_topic("DBIRTH", &device), payload(&mut state, vec![m])));
+        }
+        births
+    }
+
+    pub fn device_birth(&self, name: &str, meta: &Meta) -> (String, Vec<u8>) {
+        let device = self.register(name);
+        let mut state = always_lock(self.state.lock());
+        state.births.insert(device.clone(), meta.value_type.clone());
+
+        let m = metric(METRIC, datatype(meta.value_type.as_ref().map(|t| t.as_str())), None, None);
+        (self.device_topic("DBIRTH", &device), payload(&mut state, vec![m]))
+    }
+
+    pub fn device_death(&self, name: &str) -> Option<(String, Vec<u8>)> {
+        let device = match self.lookup(name) {
+            Some(d) => d,
+            None => return None,
+        };
+
+        let mut state = always_lock(self.state.lock());
+        state.devices.remove(&device);
+        state.births.remove(&device);
+        Some((self.device_topic("DDEATH", &device), payload(&mut state, vec![])))
+    }
+
+    pub fn data(&self, name: &str, value: &Value, ts: SystemTime) -> (String, Vec<u8>) {
+        self.value_message("DDATA", name, value, ts)
+    }
+
+    pub fn command(&self, name: &str, value: &Value) -> (String, Vec<u8>) {
+        self.value_message("DCMD", name, value, SystemTime::now())
+    }
+
+    pub fn command_topic(&self, name: &str) -> String {
+        self.device_topic("DCMD", &self.register(name))
+    }
+
+    pub fn command_wildcard(&self) -> String {
+        format!("{}/{}/DCMD/{}/+", NAMESPACE, self.group, self.edge_node)
+    }
+
+    pub fn node_command_topic(&self) -> String {
+        self.node_topic("NCMD")
+    }
+
+    // set when a host application asked for a rebirth through NCMD
+    pub fn take_rebirth(&self) -> bool {
+        self.rebirth.swap(false, Ordering::SeqCst)
+    }
+
+    // decodes DCMD messages into item name and value. NCMD rebirth requests
+    // are recorded and yield nothing.
+    pub fn parse(&self, topic: &str, payload: &[u8]) -> Result<Option<(String, Value)>> {
+        if topic == self.node_topic("NCMD") {
+            for m in decode_metrics(payload)? {
+                if m.name.as_ref().map(|n| n.as_str()) == Some(REBIRTH) &&
+                   m.value.as_ref().and_then(|v| v.as_bool().ok()) == Some(true) {
+                    info!("sparkplug rebirth requested");
+                    self.rebirth.store(true, Ordering::SeqCst);
+                }
+            }
+            return Ok(None);
+        }
+
+        let prefix = format!("{}/{}/DCMD/{}/", NAMESPACE, self.group, self.edge_node);
+        if !topic.starts_with(&prefix) {
+            return Ok(None);
+        }
+
+        let name = match always_lock(self.state.lock()).devices.get(&topic[prefix.len()..]) {
+            Some(n) => n.clone(),
+            None => return Ok(None),
+        };
+
+        let value = decode_metrics(payload)?
+            .into_iter()
+            .filter(|m| m.name.as_ref().map(|n| n.as_str()) == Some(METRIC))
+            .filter_map(|m| m.value)
+            .nth(0);
+
+        Ok(value.map(|v| (name, v)))
+    }
+
+    fn value_message(&self,
+                     kind: &str,
+                     name: &str,
+                     value: &Value,
+                     ts: SystemTime)
+                     -> (String, Vec<u8>) {
+        let device = self.register(name);
+        let mut state = always_lock(self.state.lock());
+        let m = metric(METRIC, value_datatype(value), Some(value), Some(ts));
+        (self.device_topic(kind, &device), payload(&mut state, vec![m]))
+    }
+
+    fn bd_seq(&self) -> Value {
+        Value::Number(always_lock(self.state.lock()).bd_seq.unwrap_or(0) as f64)
+    }
+
+    fn node_topic(&self, kind: &str) -> String {
+        format!("{}/{}/{}/{}", NAMESPACE, self.group, kind, self.edge_node)
+    }
+
+    fn device_topic(&self, kind: &str, device: &str) -> String {
+        format!("{}/{}/{}/{}/{}", NAMESPACE, self.group, kind, self.edge_node, device)
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        let state = always_lock(self.state.lock());
+        state.devices.iter().filter(|&(_, n)| n == name).map(|(id, _)| id.clone()).nth(0)
+    }
+
+    fn register(&self, name: &str) -> String {
+        if let Some(id) = self.lookup(name) {
+            return id;
+        }
+
+        let mut state = always_lock(self.state.lock());
+        let base = sanitize(name);
+        let mut id = base.clone();
+        let mut i = 1;
+        while state.devices.contains_key(&id) {
+            id = format!("{}_{}", base, i);
+            i += 1;
+        }
+        state.devices.insert(id.clone(), name.into());
+        id
+    }
+}
+
+// sparkplug ids may not contain topic separators or wildcards
+fn sanitize(id: &str) -> String {
+    id.chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn datatype(value_type: Option<&str>) -> u64 {
+    match value_type {
+        Some("bool") => BOOLEAN,
+        Some("number") => DOUBLE,
+        Some("raw") => BYTES,
+        _ => STRING,
+    }
+}
+
+fn value_datatype(value: &Value) -> u64 {
+    datatype(Some(value.type_string()))
+}
+
+fn payload(state: &mut State, metrics: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut buf = vec![];
+    put_varint_field(&mut buf, 1, millis(SystemTime::now()));
+    for m in metrics {
+        put_message(&mut buf, 2, &m);
+    }
+    put_varint_field(&mut buf, 3, state.next_seq());
+    buf
+}
+
+fn metric(name: &str, datatype: u64, value: Option<&Value>, ts: Option<SystemTime>) -> Vec<u8> {
+    let mut buf = vec![];
+    put_message(&mut buf, 1, name.as_bytes());
+    if let Some(ts) = ts {
+        put_varint_field(&mut buf, 3, millis(ts));
+    }
+    put_varint_field(&mut buf, 4, datatype);
+
+    let value = match value {
+        Some(v) => v,
+        None => {
+            // is_null
+            put_varint_field(&mut buf, 7, 1);
+            return buf;
+        }
+    };
+
+    match value {
+        &Value::Number(n) if datatype == UINT64 => put_varint_field(&mut buf, 11, n as u64),
+        &Value::Number(n) => {
+            let mut bytes = [0u8; 8];
+            LittleEndian::write_f64(&mut bytes, n);
+            put_key(&mut buf, 13, 1);
+            buf.extend(&bytes);
+        }
+        &Value::Bool(b) => put_varint_field(&mut buf, 14, b as u64),
+        &Value::String(ref s) => put_message(&mut buf, 15, s.as_bytes()),
+        &Value::Raw(ref r) => put_message(&mut buf, 16, r),
+    }
+    buf
+}
+
+struct DecodedMetric {
+    name: Option<String>,
+    value: Option<Value>,
+}
+
+fn decode_metrics(payload: &[u8]) -> Result<Vec<DecodedMetric>> {
+    let mut metrics = vec![];
+    for (field, data) in decode_fields(payload)? {
+        if let (2, Field::Bytes(m)) = (field, data) {
+            metrics.push(decode_metric(&m)?);
+        }
+    }
+    Ok(metrics)
+}
+
+fn decode_metric(buf: &[u8]) -> Result<DecodedMetric> {
+    let mut metric = DecodedMetric {
+        name: None,
+        value: None,
+    };
+
+    for (field, data) in decode_fields(buf)? {
+        metric.value = match (field, data) {
+            (1, Field::Bytes(b)) => {
+                metric.name = Some(String::from_utf8(b)?);
+                continue;
+            }
+            (10, Field::Varint(n)) |
+            (11, Field::Varint(n)) => Some(Value::Number(n as f64)),
+            (12, Field::Fixed32(b)) => Some(Value::Number(LittleEndian::read_f32(&b) as f64)),
+            (13, Field::Fixed64(b)) => Some(Value::Number(LittleEndian::read_f64(&b))),
+            (14, Field::Varint(b)) => Some(Value::Bool(b != 0)),
+            (15, Field::Bytes(s)) => Some(Value::String(String::from_utf8(s)?)),
+            (16, Field::Bytes(r)) => Some(Value::Raw(r)),
+            _ => continue,
+        };
+    }
+    Ok(metric)
+}
+
+// minimal protobuf wire format support
+
+// fixed width fields are kept as their little endian bytes
+#[derive(Debug,PartialEq)]
+enum Field {
+    Varint(u64),
+    Fixed64(Vec<u8>),
+    Bytes(Vec<u8>),
+    Fixed32(Vec<u8>),
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, n: u64) {
+    put_key(buf, field, 0);
+    put_varint(buf, n);
+}
+
+fn put_message(buf: &mut Vec<u8>, field: u64, data: &[u8]) {
+    put_key(buf, field, 2);
+    put_varint(buf, data.len() as u64);
+    buf.extend(data);
+}
+
+fn get_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        if *pos >= buf.len() || shift > 63 {
+            return Err(ErrorKind::InvalidPayload("truncated protobuf varint".into()).into());
+        }
+        let byte = buf[*pos];
+        *pos += 1;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+    }
+}
+
+// the next len bytes. len may come from the payload, so it is checked
+// against what is left rather than added to pos.
+fn get_bytes(buf: &[u8], pos: &mut usize, len: u64) -> Result<Vec<u8>> {
+    if len > (buf.len() - *pos) as u64 {
+        return Err(ErrorKind::InvalidPayload("truncated protobuf field".into()).into());
+    }
+    let len = len as usize;
+    *pos += len;
+    Ok(buf[*pos - len..*pos].to_vec())
+}
+
+fn decode_fields(buf: &[u8]) -> Result<Vec<(u64, Field)>> {
+    let mut fields = vec![];
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = get_varint(buf, &mut pos)?;
+        let field = match key & 0x07 {
+            0 => Field::Varint(get_varint(buf, &mut pos)?),
+            1 => Field::Fixed64(get_bytes(buf, &mut pos, 8)?),
+            2 => {
+                let len = get_varint(buf, &mut pos)?;
+                Field::Bytes(get_bytes(buf, &mut pos, len)?)
+            }
+            5 => Field::Fixed32(get_bytes(buf, &mut pos, 4)?),
+            t => {
+                return Err(ErrorKind::InvalidPayload(format!("unsupported protobuf wire type {}",
+                                                             t))
+                    .into())
+            }
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sparkplug;
+    use super::decode_fields;
+    use super::decode_metrics;
+    use super::Field;
+
+    use catt_core::value::Value;
+
+    #[test]
+    fn command_round_trip() {
+        let sparkplug = Sparkplug::new("group", "node");
+        for value in vec![Value::Number(1.5),
+                          Value::Bool(true),
+                          Value::String("on".into()),
+                          Value::Raw(vec![0, 0xff])] {
+            let (topic, payload) = sparkplug.command("living room/lamp", &value);
+            assert_eq!(topic, "spBv1.0/group/DCMD/node/living room_lamp");
+            assert_eq!(sparkplug.parse(&topic, &payload).unwrap(),
+                       Some(("living room/lamp".into(), value)));
+        }
+    }
+
+    #[test]
+    fn unknown_device() {
+        let sparkplug = Sparkplug::new("group", "node");
+        let (_, payload) = sparkplug.command("lamp", &Value::Bool(true));
+        assert_eq!(sparkplug.parse("spBv1.0/group/DCMD/node/other", &payload).unwrap(),
+                   None);
+    }
+
+    #[test]
+    fn bd_seq_per_session() {
+        let sparkplug = Sparkplug::new("group", "node");
+        let bd_seq = |payload: &[u8]| {
+            decode_metrics(payload).unwrap().into_iter().filter_map(|m| m.value).nth(0)
+        };
+
+        let (topic, payload) = sparkplug.new_session();
+        assert_eq!(topic, "spBv1.0/group/NDEATH/node");
+        assert_eq!(bd_seq(&payload), Some(Value::Number(0.0)));
+
+        let (_, payload) = sparkplug.new_session();
+        assert_eq!(bd_seq(&payload), Some(Value::Number(1.0)));
+        let births = sparkplug.birth();
+        assert_eq!(births[0].0, "spBv1.0/group/NBIRTH/node");
+        assert_eq!(bd_seq(&births[0].1), Some(Value::Number(1.0)));
+
+        for _ in 0..127 {
+            sparkplug.new_session();
+        }
+        let (_, payload) = sparkplug.death();
+        assert_eq!(bd_seq(&payload), Some(Value::Number(0.0)));
+        assert!(String::from_utf8(payload).is_ok());
+    }
+
+    #[test]
+    fn births_include_devices() {
+        let sparkplug = Sparkplug::new("group", "node");
+        sparkplug.device_birth("lamp", &Default::default());
+        let topics: Vec<String> = sparkplug.birth().into_iter().map(|(t, _)| t).collect();
+        assert_eq!(topics,
+                   vec!["spBv1.0/group/NBIRTH/node".to_string(),
+                        "spBv1.0/group/DBIRTH/node/lamp".to_string()]);
+    }
+
+    #[test]
+    fn fixed_fields() {
+        let buf = [0x09, 1, 2, 3, 4, 5, 6, 7, 8, 0x15, 9, 10, 11, 12];
+        assert_eq!(decode_fields(&buf).unwrap(),
+                   vec![(1, Field::Fixed64(vec![1, 2, 3, 4, 5, 6, 7, 8])),
+                        (2, Field::Fixed32(vec![9, 10, 11, 12]))]);
+    }
+
+    #[test]
+    fn bogus_length() {
+        // field 2, length delimited, with a length close to u64::MAX
+        let buf = [0x12, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00];
+        assert!(decode_fields(&buf).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        // length runs past the end
+        assert!(decode_fields(&[0x12, 0x05, 0x00]).is_err());
+        // varint without its last byte
+        assert!(decode_fields(&[0x08, 0x80]).is_err());
+        // fixed64 cut short
+        assert!(decode_fields(&[0x09, 0x00, 0x00]).is_err());
+        // unsupported wire type
+        assert!(decode_fields(&[0x0b]).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use catt_core::bus::SubType;
+use catt_core::util;
+
+use config::Config;
+
+use errors::*;
+
+pub const STATE_TEMPLATE_DEFAULT: &'static str = "{base}/{name}/state";
+pub const COMMAND_TEMPLATE_DEFAULT: &'static str = "{base}/{name}/command";
+pub const META_TEMPLATE_DEFAULT: &'static str = "{base}/{name}/meta";
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Kind {
+    State,
+    Command,
+    Meta,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Kind::State => "state",
+            &Kind::Command => "command",
+            &Kind::Meta => "meta",
+        }
+    }
+
+    pub fn sub_type(&self) -> SubType {
+        match self {
+            &Kind::State => SubType::Update,
+            &Kind::Command => SubType::Command,
+            &Kind::Meta => SubType::Meta,
+        }
+    }
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum Segment {
+    Literal(String),
+    Name,
+}
+
+// a topic with a single {name} segment, e.g. "home/{name}/set".
+// the same template is used to build topics and to parse incoming ones.
+// item names may contain '/' in which case {name} spans several levels.
+#[derive(Debug,Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str, base: &str) -> Result<Template> {
+        let expanded = template.replace("{base}", base);
+        let segments = expanded.split('/')
+            .map(|s| match s {
+                "{name}" => Segment::Name,
+                s => Segment::Literal(s.into()),
+            })
+            .collect::<Vec<_>>();
+
+        let names = segments.iter().filter(|s| **s == Segment::Name).count();
+        let invalid = segments.iter().any(|s| match s {
+            &Segment::Literal(ref l) => l.contains('{') || l.contains('+') || l.contains('#'),
+            &Segment::Name => false,
+        });
+
+        if names != 1 || invalid {
+            return Err(ErrorKind::InvalidConfig(format!("invalid topic template: {}", template))
+                .into());
+        }
+
+        Ok(Template { segments: segments })
+    }
+
+    pub fn render(&self, name: &str) -> String {
+        let name = escape_name(name);
+        self.segments
+            .iter()
+            .map(|s| match s {
+                &Segment::Literal(ref l) => l.as_str(),
+                &Segment::Name => name.as_str(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    // returns the item name if the topic matches this template
+    pub fn matches(&self, topic: &str) -> Option<String> {
+        let parts = topic.split('/').collect::<Vec<&str>>();
+        if parts.len() < self.segments.len() {
+            return None;
+        }
+
+        let name_pos = match self.segments.iter().position(|s| *s == Segment::Name) {
+            Some(p) => p,
+            None => return None,
+        };
+        let suffix_len = self.segments.len() - name_pos - 1;
+        let name_end = parts.len() - suffix_len;
+
+        let prefix = self.segments[..name_pos].iter().zip(&parts[..name_pos]);
+        let suffix = self.segments[name_pos + 1..].iter().zip(&parts[name_end..]);
+        for (segment, part) in prefix.chain(suffix) {
+            match segment {
+                &Segment::Literal(ref l) if l == part => {}
+                _ => return None,
+            }
+        }
+
+        unescape_name(&parts[name_pos..name_end].join("/"))
+    }
+
+    // a subscription covering the topics of every single level item name,
+    // e.g. "home/+/set". names spanning several levels need a multi-level
+    // wildcard, which has to be the last level, so everything below the
+    // prefix is subscribed to and the suffix is left to local filtering.
+    pub fn wildcard(&self, multi_level: bool) -> String {
+        let mut levels = vec![];
+        for segment in &self.segments {
+            match segment {
+                &Segment::Literal(ref l) => levels.push(l.as_str()),
+                &Segment::Name if multi_level => {
+                    levels.push("#");
+                    break;
+                }
+                &Segment::Name => levels.push("+"),
+            }
+        }
+        levels.join("/")
+    }
+
+    fn literal_count(&self) -> usize {
+        self.segments.len() - 1
+    }
+}
+
+// '+' and '#' are wildcards and may not appear in published topics, so they
+// are percent-encoded along with '%' itself. '/' is kept as a level separator.
+const RESERVED: &'static [char] = &['+', '#', '\0'];
+
+pub fn escape_name(name: &str) -> String {
+    util::escape_name(name, RESERVED)
+}
+
+pub fn unescape_name(name: &str) -> Option<String> {
+    util::unescape_name(name)
+}
+
+#[derive(Debug,Clone)]
+pub struct Topics {
+    state: Template,
+    command: Template,
+    meta: Template,
+}
+
+impl Topics {
+    pub fn from_config(cfg: &Config) -> Result<Topics> {
+        let base = cfg.item_base();
+        let templates = cfg.topics.clone().unwrap_or_default();
+        Ok(Topics {
+            state: Template::parse(templates.state
+                                       .as_ref()
+                                       .map(|t| t.as_str())
+                                       .unwrap_or(STATE_TEMPLATE_DEFAULT),
+                                   base)?,
+            command: Template::parse(templates.command
+                                         .as_ref()
+                                         .map(|t| t.as_str())
+                                         .unwrap_or(COMMAND_TEMPLATE_DEFAULT),
+                                     base)?,
+            meta: Template::parse(templates.meta
+                                      .as_ref()
+                                      .map(|t| t.as_str())
+                                      .unwrap_or(META_TEMPLATE_DEFAULT),
+                                  base)?,
+        })
+    }
+
+    pub fn template(&self, kind: Kind) -> &Template {
+        match kind {
+            Kind::State => &self.state,
+            Kind::Command => &self.command,
+            Kind::Meta => &self.meta,
+        }
+    }
+
+    pub fn topic(&self, name: &str, kind: Kind) -> String {
+        self.template(kind).render(name)
+    }
+
+    pub fn sub_topics(&self, name: &str, sub_type: SubType) -> Vec<String> {
+        kinds(sub_type).into_iter().map(|k| self.topic(name, k)).collect()
+    }
+
+    pub fn wildcards(&self, sub_type: SubType, multi_level: bool) -> Vec<String> {
+        let mut wildcards = kinds(sub_type)
+            .into_iter()
+            .map(|k| self.template(k).wildcard(multi_level))
+            .collect::<Vec<_>>();
+        wildcards.sort();
+        wildcards.dedup();
+        wildcards
+    }
+
+    // since names can span several levels a topic may match more than one
+    // template, e.g. "{base}/{name}" and "{base}/{name}/command". the most
+    // specific template wins.
+    pub fn parse(&self, topic: &str) -> Option<(String, Kind)> {
+        let mut best: Option<(String, Kind)> = None;
+        for kind in &[Kind::State, Kind::Command, Kind::Meta] {
+            let name = match self.template(*kind).matches(topic) {
+                Some(n) => n,
+                None => continue,
+            };
+            let better = match best {
+                Some((_, k)) => {
+                    self.template(*kind).literal_count() > self.template(k).literal_count()
+                }
+                None => true,
+            };
+            if better {
+                best = Some((name, *kind));
+            }
+        }
+        best
+    }
+}
+
+pub fn kinds(sub_type: SubType) -> Vec<Kind> {
+    match sub_type {
+        SubType::Update => vec![Kind::State],
+        SubType::Command => vec![Kind::Command],
+        SubType::Meta => vec![Kind::Meta],
+        SubType::All => vec![Kind::State, Kind::Command, Kind::Meta],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Kind;
+    use super::Template;
+    use super::Topics;
+
+    use catt_core::bus::SubType;
+
+    use config::Config;
+    use config::TopicConfig;
+
+    #[test]
+    fn render_and_match() {
+        let template = Template::parse("{base}/{name}/set", "home").unwrap();
+        assert_eq!(template.render("light"), "home/light/set");
+        assert_eq!(template.render("a+b"), "home/a%2Bb/set");
+        assert_eq!(template.matches("home/light/set"), Some("light".to_string()));
+        assert_eq!(template.matches("home/a/b/set"), Some("a/b".to_string()));
+        assert_eq!(template.matches("home/a%2Bb/set"), Some("a+b".to_string()));
+        assert_eq!(template.matches("home/light/state"), None);
+        assert_eq!(template.matches("other/light/set"), None);
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(Template::parse("{base}/items", "home").is_err());
+        assert!(Template::parse("{base}/{name}/{name}", "home").is_err());
+        assert!(Template::parse("{base}/+/{name}", "home").is_err());
+    }
+
+    #[test]
+    fn wildcards() {
+        let template = Template::parse("{base}/{name}/set", "home").unwrap();
+        assert_eq!(template.wildcard(false), "home/+/set");
+        assert_eq!(template.wildcard(true), "home/#");
+
+        let template = Template::parse("{base}/{name}", "home").unwrap();
+        assert_eq!(template.wildcard(false), "home/+");
+        assert_eq!(template.wildcard(true), "home/#");
+    }
+
+    #[test]
+    fn most_specific_template_wins() {
+        let mut cfg = Config::default();
+        cfg.item_base = Some("home".into());
+        cfg.topics = Some(TopicConfig {
+            state: Some("{base}/{name}".into()),
+            command: Some("{base}/{name}/command".into()),
+            meta: None,
+        });
+        let topics = Topics::from_config(&cfg).unwrap();
+
+        assert_eq!(topics.parse("home/light"), Some(("light".to_string(), Kind::State)));
+        assert_eq!(topics.parse("home/light/command"),
+                   Some(("light".to_string(), Kind::Command)));
+        assert_eq!(topics.parse("home/light/meta"),
+                   Some(("light".to_string(), Kind::Meta)));
+        assert_eq!(topics.wildcards(SubType::Command, false),
+                   vec!["home/+/command".to_string()]);
+    }
+}
@@ -0,0 +1,311 @@
+use nats;
+
+use config::Config;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::value::Value;
+use catt_core::item::Meta;
+use catt_core::util;
+use catt_core::util::always_lock;
+
+use errors::*;
+
+// reply subjects of commands that are waiting for their set_value result, in
+// the order the commands were handed to the bridge. commands that were
+// published without a reply subject are kept as None to preserve the order.
+#[derive(Clone,Default)]
+struct Replies {
+    pending: Arc<Mutex<HashMap<String, VecDeque<Option<String>>>>>,
+}
+
+impl Replies {
+    fn push(&self, name: &str, inbox: Option<String>) {
+        always_lock(self.pending.lock())
+            .entry(name.into())
+            .or_insert_with(VecDeque::new)
+            .push_back(inbox);
+    }
+
+    fn pop(&self, name: &str) -> Option<String> {
+        let mut pending = always_lock(self.pending.lock());
+        let (inbox, empty) = match pending.get_mut(name) {
+            Some(queue) => (queue.pop_front().and_then(|i| i), queue.is_empty()),
+            None => return None,
+        };
+        if empty {
+            pending.remove(name);
+        }
+        inbox
+    }
+}
+
+// subjects can't contain whitespace, and '*' and '>' are wildcards. '.'
+// separates tokens and is left alone, the same way mqtt keeps '/'.
+const RESERVED: &'static [char] = &[' ', '\t', '\r', '\n', '\0', '*', '>'];
+
+fn subject_suffix(sub_type: SubType) -> &'static str {
+    match sub_type {
+        SubType::Update => "state",
+        SubType::Command => "command",
+        SubType::Meta => "meta",
+        SubType::All => "*",
+    }
+}
+
+// splits <prefix>.<name>.<kind> into the unescaped item name and message
+// type. item names may themselves contain '.'.
+fn parse_subject(prefix: &str, subject: &str) -> Option<(String, SubType)> {
+    if !subject.starts_with(prefix) || !subject[prefix.len()..].starts_with('.') {
+        return None;
+    }
+
+    let rest = &subject[prefix.len() + 1..];
+    let split = match rest.rfind('.') {
+        Some(i) => i,
+        None => return None,
+    };
+
+    let sub_type = match &rest[split + 1..] {
+        "state" => SubType::Update,
+        "command" => SubType::Command,
+        "meta" => SubType::Meta,
+        _ => return None,
+    };
+    util::unescape_name(&rest[..split]).map(|name| (name, sub_type))
+}
+
+// messages go to <prefix>.<name>.state|command|meta subjects. the subscriber
+// connection listens on <prefix>.> and filters locally, it can't take new
+// subscriptions while it's blocked waiting for messages.
+//
+// commands sent as requests get a {"ok": bool, "error": ...} reply once the
+// binding has tried to set the value.
+pub struct Nats {
+    cfg: Config,
+    client: Mutex<nats::Client>,
+    filter: Subscriptions,
+    replies: Replies,
+}
+
+impl Nats {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let client = nats::Client::new(cfg.url())?;
+        let filter = Subscriptions::default();
+        let replies = Replies::default();
+
+        let listener_filter = filter.clone();
+        let listener_replies = replies.clone();
+        let listener_cfg = cfg.clone();
+        thread::spawn(move || listen(listener_cfg, listener_filter, listener_replies, tx));
+
+        let nats = Nats {
+            cfg: cfg.clone(),
+            client: Mutex::new(client),
+            filter: filter,
+            replies: replies,
+        };
+
+        Ok((nats, rx))
+    }
+
+    fn subject(&self, name: &str, sub_type: SubType) -> String {
+        format!("{}.{}.{}",
+                self.cfg.prefix(),
+                util::escape_name(name, RESERVED),
+                subject_suffix(sub_type))
+    }
+
+    fn send(&self, subject: &str, payload: &[u8]) -> Result<()> {
+        Ok(always_lock(self.client.lock()).publish(subject, payload)?)
+    }
+}
+
+// runs on its own thread until the bus is dropped, reconnecting whenever the
+// subscriber connection breaks
+fn listen(cfg: Config, filter: Subscriptions, replies: Replies, tx: Sender<Message>) {
+    loop {
+        match receive(&cfg, &filter, &replies, &tx) {
+            Ok(_) => {
+                debug!("message receiver closed, stopping nats listener");
+                return;
+            }
+            Err(e) => warn!("nats subscriber error, reconnecting: {}", e),
+        }
+        thread::sleep(Duration::from_secs(cfg.reconnect() as u64));
+    }
+}
+
+fn receive(cfg: &Config,
+           filter: &Subscriptions,
+           replies: &Replies,
+           tx: &Sender<Message>)
+           -> Result<()> {
+    let mut client = nats::Client::new(cfg.url())?;
+    client.subscribe(&format!("{}.>", cfg.prefix()), None)?;
+    info!("listening for nats messages on {}.>", cfg.prefix());
+
+    loop {
+        let event = client.wait()?;
+        debug!("got message on {}", event.subject);
+
+        let (item_name, sub_type) = match parse_subject(cfg.prefix(), &event.subject) {
+            Some(parsed) => parsed,
+            None => {
+                debug!("ignoring message on {}", event.subject);
+                continue;
+            }
+        };
+
+        if !filter.accepts(&item_name, sub_type) {
+            continue;
+        }
+
+        let message = match sub_type {
+            SubType::Update => {
+                Message::Update(item_name, Value::from_raw(&event.msg), SystemTime::now())
+            }
+            SubType::Command => {
+                replies.push(&item_name, event.inbox);
+                Message::Command(item_name, Value::from_raw(&event.msg))
+            }
+            _ => {
+                let meta = String::from_utf8(event.msg)
+                    .map_err(Error::from)
+                    .and_then(|s| Ok(json::decode::<Meta>(&s)?));
+                match meta {
+                    Ok(m) => Message::Meta(item_name, m),
+                    Err(e) => {
+                        warn!("error decoding meta payload: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = tx.send(message) {
+            warn!("channel send error: {}", e);
+            return Ok(());
+        }
+    }
+}
+
+impl Bus for Nats {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Nats::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        let (name, sub_type, payload) = match message {
+            Message::Update(name, value, _) => (name, SubType::Update, value.as_string()?),
+            Message::Command(name, value) => (name, SubType::Command, value.as_string()?),
+            Message::Meta(name, meta) => (name, SubType::Meta, json::encode(&meta)?),
+        };
+        self.send(&self.subject(&name, sub_type), payload.as_bytes())
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn command_result(&self,
+                      item_name: &str,
+                      result: ::std::result::Result<(), String>)
+                      -> Result<()> {
+        let inbox = match self.replies.pop(item_name) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        let mut reply = BTreeMap::new();
+        reply.insert("ok".to_string(), Json::Boolean(result.is_ok()));
+        if let Err(e) = result {
+            reply.insert("error".to_string(), Json::String(e));
+        }
+
+        debug!("replying to command for {} on {}", item_name, inbox);
+        self.send(&inbox, Json::Object(reply).to_string().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_subject;
+    use super::RESERVED;
+
+    use catt_core::bus::SubType;
+    use catt_core::util;
+
+    #[test]
+    fn escaped_names() {
+        let escaped = util::escape_name("living room.lamp>1*", RESERVED);
+        assert_eq!(escaped, "living%20room.lamp%3E1%2A");
+
+        let subject = format!("catt.{}.command", escaped);
+        assert_eq!(parse_subject("catt", &subject),
+                   Some(("living room.lamp>1*".to_string(), SubType::Command)));
+    }
+
+    #[test]
+    fn percent_round_trip() {
+        let escaped = util::escape_name("100% on", RESERVED);
+        assert_eq!(escaped, "100%25%20on");
+        assert_eq!(parse_subject("catt", &format!("catt.{}.state", escaped)),
+                   Some(("100% on".to_string(), SubType::Update)));
+    }
+
+    #[test]
+    fn invalid_subjects() {
+        assert_eq!(parse_subject("catt", "catt.lamp.other"), None);
+        assert_eq!(parse_subject("catt", "cattle.lamp.state"), None);
+        assert_eq!(parse_subject("catt", "catt.state"), None);
+        assert_eq!(parse_subject("catt", "catt.lamp%2.state"), None);
+        assert_eq!(parse_subject("catt", "catt.lamp%zz.state"), None);
+    }
+}
@@ -0,0 +1,25 @@
+pub const NATS_URL_DEFAULT: &'static str = "nats://127.0.0.1:4222";
+pub const NATS_PREFIX_DEFAULT: &'static str = "catt.items";
+pub const NATS_RECONNECT_DEFAULT: u16 = 3;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub url: Option<String>,
+    pub prefix: Option<String>,
+    pub reconnect: Option<u16>,
+}
+
+impl Config {
+    pub fn url(&self) -> &str {
+        self.url.as_ref().map(|u| u.as_str()).unwrap_or(NATS_URL_DEFAULT)
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_ref().map(|p| p.as_str()).unwrap_or(NATS_PREFIX_DEFAULT)
+    }
+
+    // seconds to wait before re-opening a broken subscriber connection
+    pub fn reconnect(&self) -> u16 {
+        self.reconnect.unwrap_or(NATS_RECONNECT_DEFAULT)
+    }
+}
@@ -0,0 +1,28 @@
+use nats;
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::std::string::FromUtf8Error, Utf8Error;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+        ::rustc_serialize::json::DecoderError, JsonDecodeError;
+    }
+
+    errors {
+        Nats(e: nats::NatsError) {
+            description("nats error")
+            display("nats error: {}", e)
+        }
+    }
+}
+
+impl From<nats::NatsError> for Error {
+    fn from(other: nats::NatsError) -> Self {
+        ErrorKind::Nats(other).into()
+    }
+}
@@ -0,0 +1,449 @@
+use redis;
+
+use config::Config;
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use rustc_serialize::json;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::value::Value;
+use catt_core::item::Meta;
+use catt_core::util;
+use catt_core::util::always_lock;
+
+use errors::*;
+
+fn channel_suffix(sub_type: SubType) -> &'static str {
+    match sub_type {
+        SubType::Update => "state",
+        SubType::Command => "command",
+        SubType::Meta => "meta",
+        SubType::All => "*",
+    }
+}
+
+// splits <prefix>:<name>:<kind> into the item name and message type. item
+// names may themselves contain ':'.
+fn parse_channel(prefix: &str, channel: &str) -> Option<(String, SubType)> {
+    if !channel.starts_with(prefix) || !channel[prefix.len()..].starts_with(':') {
+        return None;
+    }
+
+    let rest = &channel[prefix.len() + 1..];
+    let split = match rest.rfind(':') {
+        Some(i) => i,
+        None => return None,
+    };
+
+    let sub_type = match &rest[split + 1..] {
+        "state" => SubType::Update,
+        "command" => SubType::Command,
+        "meta" => SubType::Meta,
+        _ => return None,
+    };
+    Some((rest[..split].into(), sub_type))
+}
+
+// messages go to <prefix>:<name>:state|command|meta channels. the subscriber
+// connection listens on <prefix>:* and incoming messages are filtered locally,
+// since a redis connection can't change its subscriptions while it's blocked
+// waiting for messages.
+pub struct Redis {
+    cfg: Config,
+    client: redis::Client,
+    // opened lazily and dropped after an error so that the next publish
+    // reconnects
+    conn: Mutex<Option<redis::Connection>>,
+    filter: Subscriptions,
+}
+
+impl Redis {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let client = redis::Client::open(cfg.url())?;
+        let filter = Subscriptions::default();
+
+        let listener_client = redis::Client::open(cfg.url())?;
+        let listener_filter = filter.clone();
+        let listener_cfg = cfg.clone();
+        thread::spawn(move || listen(listener_client, listener_cfg, listener_filter, tx));
+
+        let redis = Redis {
+            cfg: cfg.clone(),
+            client: client,
+            conn: Mutex::new(None),
+            filter: filter,
+        };
+
+        Ok((redis, rx))
+    }
+
+    fn channel_name(&self, name: &str, sub_type: SubType) -> String {
+        format!("{}:{}:{}", self.cfg.prefix(), name, channel_suffix(sub_type))
+    }
+
+    fn state_key(&self, name: &str) -> String {
+        format!("{}:{}", self.cfg.prefix(), name)
+    }
+
+    fn query(&self, pipe: &redis::Pipeline) -> Result<()> {
+        let mut conn = always_lock(self.conn.lock());
+        if conn.is_none() {
+            *conn = Some(self.client.get_connection()?);
+        }
+
+        let res = match *conn {
+            Some(ref c) => pipe.query::<()>(c),
+            None => unreachable!(),
+        };
+
+        if let Err(e) = res {
+            *conn = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+// runs on its own thread until the bus is dropped, reconnecting whenever the
+// subscriber connection breaks
+fn listen(client: redis::Client, cfg: Config, filter: Subscriptions, tx: Sender<Message>) {
+    loop {
+        match receive(&client, &cfg, &filter, &tx) {
+            Ok(_) => {
+                debug!("message receiver closed, stopping redis listener");
+                return;
+            }
+            Err(e) => warn!("redis subscriber error, reconnecting: {}", e),
+        }
+        thread::sleep(Duration::from_secs(cfg.reconnect() as u64));
+    }
+}
+
+fn receive(client: &redis::Client,
+           cfg: &Config,
+           filter: &Subscriptions,
+           tx: &Sender<Message>)
+           -> Result<()> {
+    let mut pubsub = client.get_pubsub()?;
+    pubsub.psubscribe(format!("{}:*", cfg.prefix()))?;
+    info!("listening for redis messages on {}:*", cfg.prefix());
+
+    loop {
+        let msg = pubsub.get_message()?;
+        debug!("got message on {}", msg.get_channel_name());
+
+        let (item_name, sub_type) = match parse_channel(cfg.prefix(), msg.get_channel_name()) {
+            Some(parsed) => parsed,
+            None => {
+                debug!("ignoring message on {}", msg.get_channel_name());
+                continue;
+            }
+        };
+
+        if !filter.accepts(&item_name, sub_type) {
+            continue;
+        }
+
+        let payload = msg.get_payload_bytes();
+        let message = match sub_type {
+            SubType::Update => {
+                Message::Update(item_name, Value::from_raw(payload), SystemTime::now())
+            }
+            SubType::Command => Message::Command(item_name, Value::from_raw(payload)),
+            _ => {
+                let meta = String::from_utf8(payload.into())
+                    .map_err(Error::from)
+                    .and_then(|s| Ok(json::decode::<Meta>(&s)?));
+                match meta {
+                    Ok(m) => Message::Meta(item_name, m),
+                    Err(e) => {
+                        warn!("error decoding meta payload: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = tx.send(message) {
+            warn!("channel send error: {}", e);
+            return Ok(());
+        }
+    }
+}
+
+impl Bus for Redis {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        Redis::with_config(handle, cfg)
+    }
+
+    // state updates are also written to the item's hash in the same
+    // transaction so readers never see a value that wasn't published
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        match message {
+            Message::Update(name, value, ts) => {
+                let payload = value.as_string()?;
+                if self.cfg.write_state() {
+                    pipe.cmd("HMSET")
+                        .arg(self.state_key(&name))
+                        .arg("value")
+                        .arg(payload.as_str())
+                        .arg("type")
+                        .arg(value.type_string())
+                        .arg("ts")
+                        .arg(util::format_ts(ts))
+                        .ignore();
+                }
+                pipe.cmd("PUBLISH")
+                    .arg(self.channel_name(&name, SubType::Update))
+                    .arg(payload)
+                    .ignore();
+            }
+            Message::Command(name, value) => {
+                pipe.cmd("PUBLISH")
+                    .arg(self.channel_name(&name, SubType::Command))
+                    .arg(value.as_string()?)
+                    .ignore();
+            }
+            Message::Meta(name, meta) => {
+                let payload = json::encode(&meta)?;
+                if self.cfg.write_state() {
+                    pipe.cmd("HSET")
+                        .arg(self.state_key(&name))
+                        .arg("meta")
+                        .arg(payload.as_str())
+                        .ignore();
+                }
+                pipe.cmd("PUBLISH")
+                    .arg(self.channel_name(&name, SubType::Meta))
+                    .arg(payload)
+                    .ignore();
+            }
+        }
+
+        self.query(&pipe)
+    }
+
+    // published without touching the state hash
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        match message {
+            Message::Update(name, value, _) => {
+                let mut pipe = redis::pipe();
+                pipe.cmd("PUBLISH")
+                    .arg(self.channel_name(&name, SubType::Update))
+                    .arg(value.as_string()?)
+                    .ignore();
+                self.query(&pipe)
+            }
+            message => self.publish(message),
+        }
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        debug!("clear {}", item_name);
+        if !self.cfg.write_state() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("DEL").arg(self.state_key(item_name)).ignore();
+        self.query(&pipe)
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redis;
+    use super::parse_channel;
+
+    use config::Config;
+
+    use redis;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::bus::Subscriptions;
+    use catt_core::value::Value;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    // the integration tests run against a redis-server on localhost, e.g.
+    //   redis-server & cargo test -- --ignored
+    // each uses a prefix of its own
+    fn test_config(name: &str) -> Config {
+        let mut cfg = Config::default();
+        cfg.prefix = Some(format!("catt:test:{}", name));
+        cfg.reconnect = Some(1);
+        cfg
+    }
+
+    #[test]
+    fn channel_names() {
+        let cfg = Config::default();
+        let redis = Redis {
+            cfg: cfg.clone(),
+            client: redis::Client::open(cfg.url()).unwrap(),
+            conn: Mutex::new(None),
+            filter: Subscriptions::default(),
+        };
+        assert_eq!(redis.channel_name("lamp", SubType::Update), "catt:items:lamp:state");
+        assert_eq!(redis.channel_name("lamp", SubType::Command), "catt:items:lamp:command");
+        assert_eq!(redis.channel_name("lamp", SubType::Meta), "catt:items:lamp:meta");
+        assert_eq!(redis.state_key("lamp"), "catt:items:lamp");
+
+        for &sub_type in &[SubType::Update, SubType::Command, SubType::Meta] {
+            let channel = redis.channel_name("hall:lamp", sub_type);
+            assert_eq!(parse_channel("catt:items", &channel),
+                       Some(("hall:lamp".to_string(), sub_type)));
+        }
+    }
+
+    #[test]
+    fn invalid_channels() {
+        assert_eq!(parse_channel("catt:items", "catt:items2:lamp:state"), None);
+        assert_eq!(parse_channel("catt:items", "other:lamp:state"), None);
+        assert_eq!(parse_channel("catt:items", "catt:items:lamp:other"), None);
+        assert_eq!(parse_channel("catt:items", "catt:items:lamp"), None);
+        assert_eq!(parse_channel("catt:items", "catt:items"), None);
+    }
+
+    #[test]
+    #[ignore]
+    fn publish_writes_state() {
+        let cfg = test_config("publish");
+        let client = redis::Client::open(cfg.url()).unwrap();
+        let conn = client.get_connection().unwrap();
+        redis::cmd("DEL").arg("catt:test:publish:lamp").execute(&conn);
+
+        // listens for the published update on a connection of its own
+        let (tx, published) = mpsc::channel();
+        let mut pubsub = client.get_pubsub().unwrap();
+        pubsub.subscribe("catt:test:publish:lamp:state").unwrap();
+        thread::spawn(move || {
+            let msg = pubsub.get_message().unwrap();
+            let _ = tx.send(msg.get_payload::<String>().unwrap());
+        });
+
+        let core = Core::new().unwrap();
+        let (bus, _rx) = Redis::with_config(&core.handle(), &cfg).unwrap();
+        bus.publish(Message::Update("lamp".into(), Value::Bool(true), SystemTime::now()))
+            .unwrap();
+        assert_eq!(published.recv_timeout(Duration::from_secs(5)).unwrap(), "ON");
+
+        let state: HashMap<String, String> =
+            redis::cmd("HGETALL").arg("catt:test:publish:lamp").query(&conn).unwrap();
+        assert_eq!(state.get("value").map(|v| v.as_str()), Some("ON"));
+        assert_eq!(state.get("type").map(|v| v.as_str()), Some("bool"));
+        assert!(state.contains_key("ts"));
+
+        // transient updates leave the state alone
+        bus.publish_transient(Message::Update("lamp".into(),
+                                              Value::Bool(false),
+                                              SystemTime::now()))
+            .unwrap();
+        let value: String =
+            redis::cmd("HGET").arg("catt:test:publish:lamp").arg("value").query(&conn).unwrap();
+        assert_eq!(value, "ON");
+
+        bus.clear("lamp").unwrap();
+        let exists: bool = redis::cmd("EXISTS").arg("catt:test:publish:lamp").query(&conn).unwrap();
+        assert!(!exists);
+    }
+
+    #[test]
+    #[ignore]
+    fn command_round_trip() {
+        let cfg = test_config("command");
+        let mut core = Core::new().unwrap();
+        let (bus, rx) = Redis::with_config(&core.handle(), &cfg).unwrap();
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        bus.subscribe("hall:lamp", SubType::Command).unwrap();
+        // give the listener time to psubscribe
+        thread::sleep(Duration::from_millis(500));
+
+        let client = redis::Client::open(cfg.url()).unwrap();
+        let conn = client.get_connection().unwrap();
+        // not subscribed, filtered out by the bus
+        redis::cmd("PUBLISH").arg("catt:test:command:other:command").arg("1").execute(&conn);
+        redis::cmd("PUBLISH").arg("catt:test:command:hall:lamp:command").arg("42").execute(&conn);
+
+        let mut received = None;
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                received = Some(m);
+                break;
+            }
+        }
+        match received {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "hall:lamp");
+                assert_eq!(value, Value::Number(42.0));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+pub const REDIS_URL_DEFAULT: &'static str = "redis://127.0.0.1/";
+pub const REDIS_PREFIX_DEFAULT: &'static str = "catt:items";
+pub const REDIS_RECONNECT_DEFAULT: u16 = 3;
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub url: Option<String>,
+    pub prefix: Option<String>,
+    pub reconnect: Option<u16>,
+    pub write_state: Option<bool>,
+}
+
+impl Config {
+    pub fn url(&self) -> &str {
+        self.url.as_ref().map(|u| u.as_str()).unwrap_or(REDIS_URL_DEFAULT)
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_ref().map(|p| p.as_str()).unwrap_or(REDIS_PREFIX_DEFAULT)
+    }
+
+    // seconds to wait before re-opening a broken subscriber connection
+    pub fn reconnect(&self) -> u16 {
+        self.reconnect.unwrap_or(REDIS_RECONNECT_DEFAULT)
+    }
+
+    // also keep the current state of each item in the <prefix>:<name> hash
+    pub fn write_state(&self) -> bool {
+        self.write_state.unwrap_or(true)
+    }
+}
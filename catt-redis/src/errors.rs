@@ -0,0 +1,28 @@
+use redis;
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::std::string::FromUtf8Error, Utf8Error;
+        ::rustc_serialize::json::EncoderError, JsonEncodeError;
+        ::rustc_serialize::json::DecoderError, JsonDecodeError;
+    }
+
+    errors {
+        Redis(e: redis::RedisError) {
+            description("redis error")
+            display("redis error: {}", e)
+        }
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(other: redis::RedisError) -> Self {
+        ErrorKind::Redis(other).into()
+    }
+}
@@ -0,0 +1,12 @@
+pub const WS_LISTEN_DEFAULT: &'static str = "127.0.0.1:8081";
+
+#[derive(RustcDecodable,Debug,Clone,Default)]
+pub struct Config {
+    pub listen: Option<String>,
+}
+
+impl Config {
+    pub fn listen(&self) -> &str {
+        self.listen.as_ref().map(|l| l.as_str()).unwrap_or(WS_LISTEN_DEFAULT)
+    }
+}
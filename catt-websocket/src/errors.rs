@@ -0,0 +1,28 @@
+use websocket;
+use catt_core::clients;
+use catt_core::value;
+
+error_chain!{
+    links {
+        value::Error, value::ErrorKind, ValueError;
+        clients::Error, clients::ErrorKind, ClientError;
+    }
+
+    foreign_links {
+        ::std::io::Error, IoError;
+        ::std::string::FromUtf8Error, Utf8Error;
+    }
+
+    errors {
+        WebSocket(e: websocket::result::WebSocketError) {
+            description("websocket error")
+            display("websocket error: {}", e)
+        }
+    }
+}
+
+impl From<websocket::result::WebSocketError> for Error {
+    fn from(other: websocket::result::WebSocketError) -> Self {
+        ErrorKind::WebSocket(other).into()
+    }
+}
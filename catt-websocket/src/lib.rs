@@ -0,0 +1,22 @@
+#![feature(question_mark)]
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate log;
+
+extern crate websocket;
+
+extern crate rustc_serialize;
+
+extern crate catt_core;
+
+extern crate tokio_core;
+
+#[cfg(test)]
+extern crate futures;
+
+pub mod errors;
+pub mod config;
+pub mod ws;
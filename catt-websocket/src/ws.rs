@@ -0,0 +1,336 @@
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+use websocket::Server;
+use websocket::Message as WsMessage;
+use websocket::message::Type;
+
+use tokio_core::reactor::Handle;
+
+use tokio_core::channel::channel;
+use tokio_core::channel::Receiver;
+use tokio_core::channel::Sender;
+
+use catt_core::bus::Bus;
+use catt_core::bus::Message;
+use catt_core::bus::SubType;
+use catt_core::bus::Subscriptions;
+
+use catt_core::clients::Registry;
+use catt_core::clients::protocol;
+
+use config::Config;
+
+use errors::*;
+
+// what the writer of a connection sends: frames from the registry and
+// control frames from the reader
+enum Frame {
+    Text(String),
+    Control(WsMessage<'static>),
+}
+
+impl From<String> for Frame {
+    fn from(text: String) -> Frame {
+        Frame::Text(text)
+    }
+}
+
+// pushes updates and meta to websocket clients according to the patterns
+// each connection subscribed to, and accepts commands from them
+pub struct WebSocket {
+    clients: Registry<Frame>,
+    filter: Subscriptions,
+    local_addr: SocketAddr,
+}
+
+impl WebSocket {
+    pub fn with_config(handle: &Handle, cfg: &Config) -> Result<(Self, Receiver<Message>)> {
+        let (tx, rx) = channel(handle)?;
+        let clients = Registry::default();
+        let filter = Subscriptions::default();
+
+        let server = Server::bind(cfg.listen())?;
+        let local_addr = server.local_addr()?;
+        info!("websocket server listening on {}", local_addr);
+
+        let server_clients = clients.clone();
+        let server_filter = filter.clone();
+        let tx = Arc::new(Mutex::new(tx));
+        thread::spawn(move || {
+            for connection in server {
+                let connection = match connection {
+                    Ok(c) => c,
+                    Err(e) => {
+                        warn!("websocket accept error: {}", e);
+                        continue;
+                    }
+                };
+
+                let clients = server_clients.clone();
+                let filter = server_filter.clone();
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let client = connection.read_request()
+                        .map_err(Error::from)
+                        .and_then(|request| {
+                            request.validate()?;
+                            Ok(request.accept().send()?)
+                        });
+                    let client = match client {
+                        Ok(c) => c,
+                        Err(e) => {
+                            warn!("websocket handshake error: {}", e);
+                            return;
+                        }
+                    };
+
+                    let (mut sender, mut receiver) = client.split();
+                    let (id, out_rx) = clients.register();
+                    debug!("websocket client {} connected", id);
+
+                    // the receiver runs dry once the client is unregistered,
+                    // or dropped for lagging behind
+                    thread::spawn(move || {
+                        for frame in out_rx {
+                            let sent = match frame {
+                                Frame::Text(text) => sender.send_message(&WsMessage::text(text)),
+                                Frame::Control(msg) => sender.send_message(&msg),
+                            };
+                            if let Err(e) = sent {
+                                debug!("websocket send error: {}", e);
+                                break;
+                            }
+                        }
+                        let _ = sender.send_message(&WsMessage::close());
+                        let _ = sender.get_mut().shutdown(Shutdown::Both);
+                    });
+
+                    for message in receiver.incoming_messages() {
+                        let message: WsMessage = match message {
+                            Ok(m) => m,
+                            Err(e) => {
+                                debug!("websocket receive error: {}", e);
+                                break;
+                            }
+                        };
+
+                        match message.opcode {
+                            Type::Text => {
+                                let reply = String::from_utf8(message.payload.into_owned())
+                                    .map_err(Error::from)
+                                    .and_then(|text| {
+                                        Ok(clients.handle_request(id, &text, &filter, &tx)?)
+                                    });
+                                if let Err(e) = reply {
+                                    clients.send(id, protocol::error(&format!("{}", e)).into());
+                                }
+                            }
+                            Type::Ping => {
+                                let payload = message.payload.into_owned();
+                                clients.send(id, Frame::Control(WsMessage::pong(payload)));
+                            }
+                            Type::Close => break,
+                            _ => {}
+                        }
+                    }
+
+                    debug!("websocket client {} disconnected", id);
+                    clients.unregister(id);
+                });
+            }
+        });
+
+        let ws = WebSocket {
+            clients: clients,
+            filter: filter,
+            local_addr: local_addr,
+        };
+
+        Ok((ws, rx))
+    }
+
+    // where the server ended up listening, e.g. when configured with port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Bus for WebSocket {
+    type Config = Config;
+    type Error = Error;
+
+    fn new(handle: &Handle, cfg: &Self::Config) -> Result<(Self, Receiver<Message>)> {
+        WebSocket::with_config(handle, cfg)
+    }
+
+    fn publish(&self, message: Message) -> Result<()> {
+        debug!("publish {:?}", message);
+        self.clients.publish(message);
+        Ok(())
+    }
+
+    fn publish_transient(&self, message: Message) -> Result<()> {
+        debug!("publish transient {:?}", message);
+        self.clients.publish_transient(message);
+        Ok(())
+    }
+
+    fn clear(&self, item_name: &str) -> Result<()> {
+        self.clients.clear(item_name);
+        Ok(())
+    }
+
+    fn subscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("subscribe {}, {:?}", item_name, sub_type);
+        self.filter.add_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn unsubscribe(&self, item_name: &str, sub_type: SubType) -> Result<()> {
+        debug!("unsubscribe {}, {:?}", item_name, sub_type);
+        self.filter.remove_item(item_name, sub_type);
+        Ok(())
+    }
+
+    fn subscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("subscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.add_pattern(pattern, sub_type);
+        Ok(true)
+    }
+
+    fn unsubscribe_pattern(&self, pattern: &str, sub_type: SubType) -> Result<bool> {
+        debug!("unsubscribe pattern {}, {:?}", pattern, sub_type);
+        self.filter.remove_pattern(pattern, sub_type);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WebSocket;
+
+    use config::Config;
+
+    use websocket::Client;
+    use websocket::Message as WsMessage;
+    use websocket::client::request::Url;
+    use websocket::stream::WebSocketStream;
+
+    use rustc_serialize::json::Json;
+
+    use futures::Future;
+    use futures::stream::Stream;
+
+    use tokio_core::reactor::Core;
+
+    use catt_core::bus::Bus;
+    use catt_core::bus::Message;
+    use catt_core::bus::SubType;
+    use catt_core::value::Value;
+
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    type WsClient = Client<WebSocketStream, WebSocketStream>;
+
+    fn connect(addr: SocketAddr) -> WsClient {
+        let url = Url::parse(&format!("ws://{}", addr)).unwrap();
+        let response = Client::connect(url).unwrap().send().unwrap();
+        response.validate().unwrap();
+        response.begin()
+    }
+
+    fn send(client: &mut WsClient, text: &str) {
+        client.send_message(&WsMessage::text(text)).unwrap();
+    }
+
+    // the next text frame as json
+    fn recv(client: &mut WsClient) -> Json {
+        let message: WsMessage = client.recv_message().unwrap();
+        Json::from_str(&String::from_utf8(message.payload.into_owned()).unwrap()).unwrap()
+    }
+
+    fn field(frame: &Json, name: &str) -> String {
+        match frame.find(name) {
+            Some(&Json::String(ref s)) => s.clone(),
+            Some(j) => j.to_string(),
+            None => panic!("no {} in {}", name, frame),
+        }
+    }
+
+    #[test]
+    fn per_connection_filters() {
+        let core = Core::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.listen = Some("127.0.0.1:0".into());
+        let (ws, _rx) = WebSocket::with_config(&core.handle(), &cfg).unwrap();
+        ws.publish(Message::Update("kitchen/light".into(), Value::Number(1.5), SystemTime::now()))
+            .unwrap();
+
+        let mut kitchen = connect(ws.local_addr());
+        let mut hall = connect(ws.local_addr());
+        send(&mut kitchen, r#"{"type":"subscribe","items":["kitchen/*"]}"#);
+        send(&mut hall, r#"{"type":"subscribe","items":["hall"]}"#);
+
+        // the current state comes first
+        let frame = recv(&mut kitchen);
+        assert_eq!(field(&frame, "name"), "kitchen/light");
+        assert_eq!(field(&frame, "value"), "1.5");
+
+        ws.publish(Message::Update("kitchen/light".into(), Value::Number(2.5), SystemTime::now()))
+            .unwrap();
+        ws.publish(Message::Update("hall".into(), Value::Bool(true), SystemTime::now()))
+            .unwrap();
+        let frame = recv(&mut kitchen);
+        assert_eq!(field(&frame, "name"), "kitchen/light");
+        assert_eq!(field(&frame, "value"), "2.5");
+        let frame = recv(&mut hall);
+        assert_eq!(field(&frame, "name"), "hall");
+        assert_eq!(field(&frame, "value"), "true");
+    }
+
+    #[test]
+    fn commands() {
+        let mut core = Core::new().unwrap();
+        let mut cfg = Config::default();
+        cfg.listen = Some("127.0.0.1:0".into());
+        let (ws, rx) = WebSocket::with_config(&core.handle(), &cfg).unwrap();
+        let (tx, messages) = mpsc::channel();
+        core.handle().spawn(rx.for_each(move |m| {
+                let _ = tx.send(m);
+                Ok(())
+            })
+            .map_err(|_| ()));
+
+        let mut client = connect(ws.local_addr());
+        let command = r#"{"type":"command","name":"lamp","value":"ON"}"#;
+        send(&mut client, command);
+        let frame = recv(&mut client);
+        assert_eq!(field(&frame, "type"), "error");
+        assert!(field(&frame, "error").contains("unknown item lamp"));
+
+        ws.subscribe("lamp", SubType::Command).unwrap();
+        send(&mut client, command);
+        let mut received = None;
+        for _ in 0..50 {
+            core.turn(Some(Duration::from_millis(100)));
+            if let Ok(m) = messages.try_recv() {
+                received = Some(m);
+                break;
+            }
+        }
+        match received {
+            Some(Message::Command(name, value)) => {
+                assert_eq!(name, "lamp");
+                assert_eq!(value, Value::String("ON".into()));
+            }
+            m => panic!("expected the command, got {:?}", m),
+        }
+    }
+}
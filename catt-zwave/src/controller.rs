@@ -1,6 +1,7 @@
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::MutexGuard;
+use std::time::SystemTime;
 
 use catt_core::item::Item;
 use catt_core::item::Meta;
@@ -16,6 +17,7 @@ pub struct ControllerItem {
     driver: ZWave,
     home_id: u32,
     state: Arc<Mutex<String>>,
+    changed: Arc<Mutex<SystemTime>>,
 }
 
 impl ControllerItem {
@@ -25,6 +27,7 @@ impl ControllerItem {
             driver: driver,
             home_id: home_id,
             state: Arc::new(Mutex::new("idle".into())),
+            changed: Arc::new(Mutex::new(SystemTime::now())),
         }
     }
 
@@ -48,6 +51,10 @@ impl Item for ControllerItem {
         })
     }
 
+    fn get_timestamp(&self) -> Option<SystemTime> {
+        Some(*::catt_core::util::always_lock(self.changed.lock()))
+    }
+
     fn get_value(&self) -> Result<Value> {
         let s = self.get_state();
         Ok(Value::String(s.clone()))
@@ -69,6 +76,16 @@ impl Item for ControllerItem {
 
         let mut s = self.get_state();
         *s = cmd;
+        *::catt_core::util::always_lock(self.changed.lock()) = SystemTime::now();
         Ok(())
     }
+
+    fn describe_set(&self, val: Value) -> Result<String> {
+        let cmd = val.as_string()?.trim().to_lowercase();
+        Ok(match cmd.as_str() {
+            "include" => format!("add_node({}, false)", self.home_id),
+            "exclude" => format!("remove_node({})", self.home_id),
+            _ => format!("nothing, state set to {:?}", cmd),
+        })
+    }
 }
@@ -1,5 +1,7 @@
 use openzwave::value_classes::value_id::ValueID;
 
+use std::time::SystemTime;
+
 use catt_core::item;
 use catt_core::value::Value as CValue;
 
@@ -52,6 +54,18 @@ impl item::Item for Item {
         unreachable!()
     }
 
+    fn get_timestamp(&self) -> Option<SystemTime> {
+        if let Some(ref z_item) = self.zwave_item {
+            return z_item.get_timestamp();
+        }
+
+        if let Some(ref controller) = self.controller {
+            return controller.get_timestamp();
+        }
+
+        unreachable!()
+    }
+
     fn get_meta(&self) -> Option<item::Meta> {
         if let Some(ref z_item) = self.zwave_item {
             return z_item.get_meta();
@@ -75,4 +89,16 @@ impl item::Item for Item {
 
         unreachable!()
     }
+
+    fn describe_set(&self, value: CValue) -> Result<String> {
+        if let Some(ref z_item) = self.zwave_item {
+            return z_item.describe_set(value);
+        }
+
+        if let Some(ref controller) = self.controller {
+            return controller.describe_set(value);
+        }
+
+        unreachable!()
+    }
 }
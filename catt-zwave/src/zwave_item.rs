@@ -3,14 +3,40 @@ use openzwave::value_classes::value_id::{ValueType, ValueID};
 use catt_core::item;
 use catt_core::value::Value as CValue;
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::time::SystemTime;
 
 use super::errors::*;
 
-#[derive(PartialOrd, Ord, Eq, PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct ZWaveItem {
     name: String,
     ozw_value: ValueID,
+    // items are created from zwave notifications, so this is when the
+    // value was reported
+    observed: SystemTime,
+}
+
+// the same value reported again is the same item, whenever it was observed
+impl PartialEq for ZWaveItem {
+    fn eq(&self, other: &ZWaveItem) -> bool {
+        self.name == other.name && self.ozw_value == other.ozw_value
+    }
+}
+
+impl Eq for ZWaveItem {}
+
+impl PartialOrd for ZWaveItem {
+    fn partial_cmp(&self, other: &ZWaveItem) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ZWaveItem {
+    fn cmp(&self, other: &ZWaveItem) -> Ordering {
+        (&self.name, &self.ozw_value).cmp(&(&other.name, &other.ozw_value))
+    }
 }
 
 impl ZWaveItem {
@@ -18,6 +44,7 @@ impl ZWaveItem {
         ZWaveItem {
             name: name.into(),
             ozw_value: value,
+            observed: SystemTime::now(),
         }
     }
 
@@ -48,6 +75,65 @@ impl ZWaveItem {
     pub fn set_raw(&self, val: &Vec<u8>) -> Result<()> {
         Ok(self.ozw_value.set_raw(val)?)
     }
+
+    fn setter(&self, value: CValue) -> Result<Setter> {
+        let val_type = self.ozw_value.get_type();
+        Ok(match val_type {
+            ValueType::ValueType_Raw => Setter::Raw(value.as_raw()?),
+
+            ValueType::ValueType_Button |
+            ValueType::ValueType_List |
+            ValueType::ValueType_Schedule => {
+                return Err(ErrorKind::Unimplemented(self.name.clone(), val_type).into())
+            }
+
+            ValueType::ValueType_Bool => Setter::Bool(value.as_bool()?),
+            ValueType::ValueType_Byte => Setter::Byte(value.as_number()? as u8),
+            ValueType::ValueType_Short => Setter::Short(value.as_number()? as i16),
+            ValueType::ValueType_Int => Setter::Int(value.as_number()? as i32),
+            ValueType::ValueType_Decimal => Setter::Float(value.as_number()? as f32),
+            ValueType::ValueType_String => Setter::String(value.as_string()?),
+        })
+    }
+}
+
+// the openzwave setter a value goes to, so that set_value and describe_set
+// can't disagree about it
+enum Setter {
+    Raw(Vec<u8>),
+    Bool(bool),
+    Byte(u8),
+    Short(i16),
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+impl Setter {
+    fn apply(&self, value: &ValueID) -> Result<()> {
+        let res = match self {
+            &Setter::Raw(ref v) => value.set_raw(v),
+            &Setter::Bool(v) => value.set_bool(v),
+            &Setter::Byte(v) => value.set_byte(v),
+            &Setter::Short(v) => value.set_short(v),
+            &Setter::Int(v) => value.set_int(v),
+            &Setter::Float(v) => value.set_float(v),
+            &Setter::String(ref v) => value.set_string(v),
+        };
+        Ok(res?)
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            &Setter::Raw(ref v) => format!("set_raw({:?})", v),
+            &Setter::Bool(v) => format!("set_bool({})", v),
+            &Setter::Byte(v) => format!("set_byte({})", v),
+            &Setter::Short(v) => format!("set_short({})", v),
+            &Setter::Int(v) => format!("set_int({})", v),
+            &Setter::Float(v) => format!("set_float({})", v),
+            &Setter::String(ref v) => format!("set_string({:?})", v),
+        }
+    }
 }
 
 impl item::Item for ZWaveItem {
@@ -57,6 +143,10 @@ impl item::Item for ZWaveItem {
         self.name.clone()
     }
 
+    fn get_timestamp(&self) -> Option<SystemTime> {
+        Some(self.observed)
+    }
+
     fn get_value(&self) -> Result<CValue> {
         let val_type = self.ozw_value.get_type();
         let val = match val_type {
@@ -95,6 +185,8 @@ impl item::Item for ZWaveItem {
         ext.insert("help".into(), self.ozw_value.get_help());
         ext.insert("units".into(), self.ozw_value.get_units());
         ext.insert("genre".into(), format!("{:?}", self.ozw_value.get_genre()));
+        ext.insert("read_only".into(),
+                   format!("{}", self.ozw_value.is_read_only()));
 
         Some(item::Meta {
             backend: String::from("zwave").into(),
@@ -109,24 +201,13 @@ impl item::Item for ZWaveItem {
     }
 
     fn set_value(&self, value: CValue) -> Result<()> {
-        let val_type = self.ozw_value.get_type();
-        let res = match val_type {
-            ValueType::ValueType_Raw => self.set_raw(&value.as_raw()?),
-
-            ValueType::ValueType_Button |
-            ValueType::ValueType_List |
-            ValueType::ValueType_Schedule => {
-                return Err(ErrorKind::Unimplemented(self.get_name(), val_type).into())
-            }
-
-            ValueType::ValueType_Bool => self.set_bool(value.as_bool()?),
-            ValueType::ValueType_Byte |
-            ValueType::ValueType_Short |
-            ValueType::ValueType_Int |
-            ValueType::ValueType_Decimal => self.set_number(value.as_number()?),
-            ValueType::ValueType_String => self.set_string(&value.as_string()?),
-        };
+        self.setter(value)?.apply(&self.ozw_value)
+    }
 
-        Ok(res?)
+    fn describe_set(&self, value: CValue) -> Result<String> {
+        Ok(format!("{} on node {}, {}",
+                   self.setter(value)?.describe(),
+                   self.ozw_value.get_node_id(),
+                   self.ozw_value.get_label()))
     }
 }
@@ -0,0 +1,12 @@
+extern crate catt;
+
+extern crate env_logger;
+
+use catt::replay;
+
+#[allow(unused_variables)]
+fn main() {
+    env_logger::init().unwrap();
+
+    let _ = replay("replay.toml");
+}
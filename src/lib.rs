@@ -13,6 +13,10 @@ extern crate catt_mqtt;
 extern crate log;
 
 use catt_core::bridge;
+use catt_core::composite::Composite;
+use catt_core::record::Recorder;
+use catt_core::record::Replay;
+use catt_core::record::ReplayBus;
 
 use catt_zwave::driver::ZWave;
 use catt_mqtt::mqtt::Mqtt;
@@ -40,3 +44,27 @@ pub fn zwave(cfg: &str) -> Result<()> {
 
     Ok(())
 }
+
+// plays back a recording: updates and meta go out over mqtt, and the recorded
+// commands come in alongside whatever mqtt sends. the recording to play is
+// configured for both the binding and the secondary bus.
+pub fn replay(cfg: &str) -> Result<()> {
+    let mut reactor = Core::new().unwrap();
+    let handle = reactor.handle();
+    let (f1, f2) = bridge::from_file::<Composite<Mqtt, ReplayBus>, Replay>(&handle, &cfg)?;
+
+    let _ = reactor.run(f1.select(f2));
+
+    Ok(())
+}
+
+// runs the zwave bridge while recording its mqtt traffic for replay
+pub fn record(cfg: &str) -> Result<()> {
+    let mut reactor = Core::new().unwrap();
+    let handle = reactor.handle();
+    let (f1, f2) = bridge::from_file::<Recorder<Mqtt>, ZWave>(&handle, &cfg)?;
+
+    let _ = reactor.run(f1.select(f2));
+
+    Ok(())
+}